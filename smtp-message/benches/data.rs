@@ -0,0 +1,52 @@
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use futures::{executor, io::Cursor, AsyncReadExt};
+
+use smtp_message::{DataUnescaper, EscapedDataReader};
+
+/// A 4MiB message with 78-character lines, some of them dot-escaped
+fn escaped_message() -> Vec<u8> {
+    let mut message = Vec::with_capacity(4 * 1024 * 1024 + 128);
+    let mut line = 0;
+    while message.len() < 4 * 1024 * 1024 {
+        if line % 50 == 0 {
+            message.extend_from_slice(b"..");
+        }
+        message.extend_from_slice(&[b'a' + (line % 26) as u8; 76]);
+        message.extend_from_slice(b"\r\n");
+        line += 1;
+    }
+    message.extend_from_slice(b".\r\n");
+    message
+}
+
+fn unescape(c: &mut Criterion) {
+    let message = escaped_message();
+    let mut group = c.benchmark_group("data");
+    group.throughput(Throughput::Bytes(message.len() as u64));
+    group.bench_function("read_and_unescape_4MiB", |b| {
+        let mut rdbuf = vec![0; 16 * 1024];
+        let mut buf = vec![0; 16 * 1024];
+        b.iter(|| {
+            let mut reader = EscapedDataReader::new(&mut rdbuf, 0..0, Cursor::new(&message));
+            let mut unescaper = DataUnescaper::new(true);
+            let mut start = 0;
+            let mut total = 0;
+            loop {
+                let read = executor::block_on(reader.read(&mut buf[start..])).unwrap();
+                if read == 0 {
+                    break;
+                }
+                let res = unescaper.unescape(&mut buf[..start + read]);
+                total += res.written;
+                buf.copy_within(res.unhandled_idx..start + read, 0);
+                start = start + read - res.unhandled_idx;
+            }
+            reader.complete();
+            total
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, unescape);
+criterion_main!(benches);
@@ -0,0 +1,56 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+
+use smtp_message::{Command, Reply};
+
+const COMMANDS: &[(&str, &[u8])] = &[
+    ("ehlo", b"EHLO mail.example.org\r\n"),
+    ("ehlo_ipv6", b"EHLO [IPv6:2001:db8::1]\r\n"),
+    (
+        "mail_from",
+        b"MAIL FROM:<first.last+tag@example.org> BODY=8BITMIME SIZE=12345\r\n",
+    ),
+    ("rcpt_to_quoted", b"RCPT TO:<\"john doe\"@example.org>\r\n"),
+    (
+        "rcpt_to_utf8",
+        "RCPT TO:<jérôme@exämple.org> SMTPUTF8\r\n".as_bytes(),
+    ),
+    ("data", b"DATA\r\n"),
+];
+
+const REPLIES: &[(&str, &[u8])] = &[
+    ("okay", b"250 2.1.5 Okay\r\n"),
+    (
+        "ehlo",
+        b"250-mail.example.org Hello\r\n250-8BITMIME\r\n250-ENHANCEDSTATUSCODES\r\n\
+          250-PIPELINING\r\n250-SMTPUTF8\r\n250 STARTTLS\r\n",
+    ),
+    (
+        "utf8",
+        "550 5.1.1 Boîte aux lettres inexistante\r\n".as_bytes(),
+    ),
+];
+
+fn command_parse(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Command::parse");
+    for (name, input) in COMMANDS {
+        group.throughput(Throughput::Bytes(input.len() as u64));
+        group.bench_function(*name, |b| {
+            b.iter(|| Command::<&str>::parse(black_box(input)).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn reply_parse(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Reply::parse");
+    for (name, input) in REPLIES {
+        group.throughput(Throughput::Bytes(input.len() as u64));
+        group.bench_function(*name, |b| {
+            b.iter(|| Reply::<&str>::parse(black_box(input)).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, command_parse, reply_parse);
+criterion_main!(benches);
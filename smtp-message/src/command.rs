@@ -1,7 +1,6 @@
 use std::{io::IoSlice, iter, str};
 
 use auto_enums::auto_enum;
-use lazy_static::lazy_static;
 use nom::{
     branch::alt,
     bytes::streaming::{is_a, tag, tag_no_case, take_until},
@@ -11,27 +10,31 @@ use nom::{
     sequence::{pair, preceded, terminated, tuple},
     IResult,
 };
-use regex_automata::{Regex, RegexBuilder};
 
 use crate::*;
 
-lazy_static! {
-    static ref PARAMETER_NAME: Regex = RegexBuilder::new()
-        .anchored(true)
-        .build(
-            r#"(?x)
-            [[:alnum:]] ( [[:alnum:]-] )*
-        "#
-        )
-        .unwrap();
-    static ref PARAMETER_VALUE_ASCII: Regex = RegexBuilder::new()
-        .anchored(true)
-        .build(r#"[[:ascii:]&&[^= [:cntrl:]]]+"#)
-        .unwrap();
-    static ref PARAMETER_VALUE_UTF8: Regex = RegexBuilder::new()
-        .anchored(true)
-        .build(r#"[^= [:cntrl:]]+"#)
-        .unwrap();
+fn parameter_name(buf: &[u8]) -> Scan {
+    match buf.first() {
+        None => Scan::Incomplete,
+        Some(b) if b.is_ascii_alphanumeric() => {
+            let (len, _) = take_run(buf, false, |b| b == b'-' || b.is_ascii_alphanumeric());
+            Scan::Match(len)
+        }
+        Some(_) => Scan::Invalid,
+    }
+}
+
+#[inline]
+fn is_parameter_value_char(b: u8) -> bool {
+    b > b' ' && b != b'=' && b != 0x7F
+}
+
+fn parameter_value_ascii(buf: &[u8]) -> Scan {
+    Scan::from_run(take_run(buf, false, is_parameter_value_char))
+}
+
+fn parameter_value_utf8(buf: &[u8]) -> Scan {
+    Scan::from_run(take_run(buf, true, is_parameter_value_char))
 }
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -45,8 +48,8 @@ impl<S> ParameterName<S> {
     where
         S: From<&'a str>,
     {
-        map(apply_regex(&PARAMETER_NAME), |b: &[u8]| {
-            // The below unsafe is OK, thanks to PARAMETER_NAME
+        map(apply_scanner(parameter_name), |b: &[u8]| {
+            // The below unsafe is OK, thanks to parameter_name
             // validating that `b` is proper ascii
             let s = unsafe { str::from_utf8_unchecked(b) };
             ParameterName::Other(s.into())
@@ -90,12 +93,12 @@ impl<S> Parameters<S> {
                         alt((
                             map(
                                 terminated(
-                                    apply_regex(&PARAMETER_VALUE_ASCII),
+                                    apply_scanner(parameter_value_ascii),
                                     terminate(term_with_sp_tab),
                                 ),
                                 |b| {
                                     // The below unsafe is OK, thanks
-                                    // to the regex having validated
+                                    // to the scanner having validated
                                     // that it is pure ASCII
                                     let s = unsafe { str::from_utf8_unchecked(b) };
                                     MaybeUtf8::Ascii(s.into())
@@ -103,12 +106,12 @@ impl<S> Parameters<S> {
                             ),
                             map(
                                 terminated(
-                                    apply_regex(&PARAMETER_VALUE_UTF8),
+                                    apply_scanner(parameter_value_utf8),
                                     terminate(term_with_sp_tab),
                                 ),
                                 |b| {
                                     // The below unsafe is OK, thanks
-                                    // to the regex having validated
+                                    // to the scanner having validated
                                     // that it is valid UTF-8
                                     let s = unsafe { str::from_utf8_unchecked(b) };
                                     MaybeUtf8::Utf8(s.into())
@@ -345,6 +348,25 @@ impl<S> Command<S> {
             ),
         ))(buf)
     }
+
+    /// Name of the command, in upper case
+    pub fn verb(&self) -> &'static str {
+        match self {
+            Command::Data => "DATA",
+            Command::Ehlo { .. } => "EHLO",
+            Command::Expn { .. } => "EXPN",
+            Command::Helo { .. } => "HELO",
+            Command::Help { .. } => "HELP",
+            Command::Lhlo { .. } => "LHLO",
+            Command::Mail { .. } => "MAIL",
+            Command::Noop { .. } => "NOOP",
+            Command::Quit => "QUIT",
+            Command::Rcpt { .. } => "RCPT",
+            Command::Rset => "RSET",
+            Command::Starttls => "STARTTLS",
+            Command::Vrfy { .. } => "VRFY",
+        }
+    }
 }
 
 impl<S> Command<S>
@@ -435,8 +457,39 @@ where
 
 #[cfg(test)]
 mod tests {
+    use std::sync::LazyLock;
+
+    use quickcheck_macros::quickcheck;
+    use regex_automata::Regex;
+
     use super::*;
 
+    static PARAMETER_NAME: LazyLock<Regex> =
+        LazyLock::new(|| differential::regex(r#"[[:alnum:]]([[:alnum:]-])*"#));
+    static PARAMETER_VALUE_ASCII: LazyLock<Regex> =
+        LazyLock::new(|| differential::regex(r#"[[:ascii:]&&[^= [:cntrl:]]]+"#));
+    static PARAMETER_VALUE_UTF8: LazyLock<Regex> =
+        LazyLock::new(|| differential::regex(r#"[^= [:cntrl:]]+"#));
+
+    #[test]
+    fn scanners_match_regexes() {
+        differential::check_exhaustive(&PARAMETER_NAME, parameter_name);
+        differential::check_exhaustive(&PARAMETER_VALUE_ASCII, parameter_value_ascii);
+        differential::check_exhaustive(&PARAMETER_VALUE_UTF8, parameter_value_utf8);
+    }
+
+    #[quickcheck]
+    fn scanners_match_regexes_random(indices: Vec<u8>, raw: Vec<u8>) {
+        differential::check_fragments(&PARAMETER_NAME, parameter_name, &indices, &raw);
+        differential::check_fragments(
+            &PARAMETER_VALUE_ASCII,
+            parameter_value_ascii,
+            &indices,
+            &raw,
+        );
+        differential::check_fragments(&PARAMETER_VALUE_UTF8, parameter_value_utf8, &indices, &raw);
+    }
+
     // TODO: test parameter (without an s) valid, incomplete, invalid and build
 
     #[test]
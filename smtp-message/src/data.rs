@@ -7,6 +7,7 @@ use std::{
 };
 
 use futures::{pin_mut, AsyncRead, AsyncWrite, AsyncWriteExt};
+use memchr::{memchr, memmem};
 use pin_project::pin_project;
 
 // use crate::*;
@@ -150,8 +151,17 @@ where
         // Then, look for the end in the bufs
         let mut size = 0;
         for b in 0..bufs.len() {
-            for i in 0..cmp::min(bufs[b].len(), raw_size - size) {
+            let len = cmp::min(bufs[b].len(), raw_size - size);
+            let mut i = 0;
+            while i < len {
                 use EscapedDataReaderState::*;
+                if *this.state == Start {
+                    // Only a \r can get us out of Start, so jump straight to it
+                    match memchr(b'\r', &bufs[b][i..len]) {
+                        Some(p) => i += p,
+                        None => break,
+                    }
+                }
                 match (*this.state, bufs[b][i]) {
                     (Cr, b'\n') => *this.state = CrLf,
                     (CrLf, b'.') => *this.state = CrLfDot,
@@ -185,8 +195,9 @@ where
                     (_, b'\r') => *this.state = Cr,
                     _ => *this.state = Start,
                 }
+                i += 1;
             }
-            size += cmp::min(bufs[b].len(), raw_size - size);
+            size += len;
         }
 
         // Didn't reach the end, let's return everything found
@@ -280,7 +291,7 @@ impl DataUnescaper {
         }
 
         // First, look for "\r\n."
-        while let Some(i) = data[unhandled_idx..].windows(3).position(|s| s == b"\r\n.") {
+        while let Some(i) = memmem::find(&data[unhandled_idx..], b"\r\n.") {
             if data.len() <= unhandled_idx + i + 4 {
                 // Don't have enough information to know whether it's the end or just an escape
                 if unhandled_idx != written {
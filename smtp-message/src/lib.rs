@@ -17,11 +17,11 @@ pub use data::{DataUnescapeRes, DataUnescaper, EscapedDataReader, EscapingDataWr
 pub use misc::{next_crlf, Email, Hostname, Localpart, MaybeUtf8, NextCrLfState, Path};
 pub use reply::{
     EnhancedReplyCode, EnhancedReplyCodeClass, EnhancedReplyCodeSubject, Reply, ReplyCode,
-    ReplyCodeCategory, ReplyCodeKind, ReplyLine,
+    ReplyCodeCategory, ReplyCodeKind, ReplyLine, ReplyLines,
 };
 
 #[cfg(test)]
-use std::str;
+use std::{iter, str};
 
 /// Used as `println!("{:?}", show_bytes(b))`
 #[cfg(test)]
@@ -35,6 +35,148 @@ pub(crate) fn show_bytes(b: &[u8]) -> String {
     }
 }
 
+/// Differential testing of the scanners against the regexes they replaced
+#[cfg(test)]
+pub(crate) mod differential {
+    use nom::IResult;
+    use regex_automata::{DenseDFA, Regex, RegexBuilder, DFA};
+
+    use super::*;
+
+    /// Pieces of inputs that exercise the syntax rules, including partial and
+    /// invalid UTF-8
+    const FRAGMENTS: &[&[u8]] = &[
+        b"a",
+        b"Z",
+        b"0",
+        b"2",
+        b"4",
+        b"5",
+        b"7",
+        b"f",
+        b"-",
+        b".",
+        b",",
+        b"/",
+        b"!",
+        b"~",
+        b"=",
+        b"[",
+        b"]",
+        b"[IPv6:",
+        b"IPv6",
+        b":",
+        b"\"",
+        b"\\",
+        b" ",
+        b"\t",
+        b"\r\n",
+        b"\x00",
+        b"\x7f",
+        b"\x80",
+        b"\xc3\xa9",
+        b"\xc3",
+        b"\xe2\x82\xac",
+        b"\xe2\x82",
+        b"\xf0\x9f\x98\x80",
+        b"\xf0\x9f",
+        b"\xed\xa0\x80",
+        b"\xc0\xaf",
+        b"\xff",
+        b"\xc2\x85",
+    ];
+
+    pub fn regex(pattern: &str) -> Regex {
+        RegexBuilder::new().anchored(true).build(pattern).unwrap()
+    }
+
+    // Implementation is similar to regex_automata's, but also returns the state
+    // when a match wasn't found
+    fn find_dfa<D: DFA>(dfa: &D, buf: &[u8]) -> Result<usize, D::ID> {
+        let mut state = dfa.start_state();
+        let mut last_match = if dfa.is_dead_state(state) {
+            return Err(state);
+        } else if dfa.is_match_state(state) {
+            Some(0)
+        } else {
+            None
+        };
+
+        for (i, &b) in buf.iter().enumerate() {
+            state = dfa.next_state(state, b);
+            if dfa.is_match_or_dead_state(state) {
+                if dfa.is_dead_state(state) {
+                    return last_match.ok_or(state);
+                }
+                last_match = Some(i + 1);
+            }
+        }
+
+        last_match.ok_or(state)
+    }
+
+    /// The regex-based parser the scanners replaced
+    fn apply_regex(regex: &Regex) -> impl '_ + FnMut(&[u8]) -> IResult<&[u8], &[u8]> {
+        move |buf: &[u8]| {
+            let dfa = regex.forward();
+
+            let dfa_result = match dfa {
+                DenseDFA::Standard(r) => find_dfa(r, buf),
+                DenseDFA::ByteClass(r) => find_dfa(r, buf),
+                DenseDFA::Premultiplied(r) => find_dfa(r, buf),
+                DenseDFA::PremultipliedByteClass(r) => find_dfa(r, buf),
+                other => find_dfa(other, buf),
+            };
+
+            match dfa_result {
+                Ok(end) => Ok((&buf[end..], &buf[..end])),
+                Err(s) if dfa.is_dead_state(s) => Err(nom::Err::Error(nom::error::Error::new(
+                    buf,
+                    nom::error::ErrorKind::Verify,
+                ))),
+                Err(_) => Err(nom::Err::Incomplete(nom::Needed::Unknown)),
+            }
+        }
+    }
+
+    pub fn check(regex: &Regex, scanner: fn(&[u8]) -> Scan, input: &[u8]) {
+        assert_eq!(
+            apply_regex(regex)(input),
+            apply_scanner(scanner)(input),
+            "input: {:?}",
+            show_bytes(input)
+        );
+    }
+
+    /// Checks all the concatenations of up to three fragments
+    pub fn check_exhaustive(regex: &Regex, scanner: fn(&[u8]) -> Scan) {
+        let mut input = Vec::new();
+        check(regex, scanner, &input);
+        for a in FRAGMENTS {
+            for b in iter::once(&&b""[..]).chain(FRAGMENTS) {
+                for c in iter::once(&&b""[..]).chain(FRAGMENTS) {
+                    input.clear();
+                    input.extend_from_slice(a);
+                    input.extend_from_slice(b);
+                    input.extend_from_slice(c);
+                    check(regex, scanner, &input);
+                }
+            }
+        }
+    }
+
+    /// Checks the concatenation of the fragments chosen by `indices`, then
+    /// `raw` itself
+    pub fn check_fragments(regex: &Regex, scanner: fn(&[u8]) -> Scan, indices: &[u8], raw: &[u8]) {
+        let input = indices
+            .iter()
+            .flat_map(|&i| FRAGMENTS[i as usize % FRAGMENTS.len()].iter().copied())
+            .collect::<Vec<u8>>();
+        check(regex, scanner, &input);
+        check(regex, scanner, raw);
+    }
+}
+
 #[cfg(any(test, feature = "fuzz-targets"))]
 pub mod fuzz {
     use super::*;
@@ -45,7 +187,6 @@ pub mod fuzz {
         executor,
         io::{AsyncReadExt, AsyncWriteExt, Cursor},
     };
-    use regex_automata::RegexBuilder;
 
     pub fn escaping_then_unescaping(
         data: Vec<Vec<Vec<u8>>>,
@@ -109,14 +250,12 @@ pub mod fuzz {
 
             assert!(wire == b".\r\n" || wire.ends_with(b"\r\n.\r\n"));
 
-            // Either there's no such sequence, or it's at the end
-            let reg = RegexBuilder::new()
-                .allow_invalid_utf8(true)
-                .build(r#"\r\n\.[^.]"#)
-                .unwrap();
-            assert!(reg
-                .find(&wire)
-                .map(|(start, _)| start == wire.len() - 5)
+            // Either there's no unescaped dot at the start of a line, or it's
+            // the one at the end
+            assert!(wire
+                .windows(4)
+                .position(|w| w.starts_with(b"\r\n.") && w[3] != b'.')
+                .map(|start| start == wire.len() - 5)
                 .unwrap_or(true));
         }
 
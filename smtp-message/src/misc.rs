@@ -1,5 +1,5 @@
 use std::{
-    fmt,
+    cmp, fmt,
     io::IoSlice,
     iter,
     net::{Ipv4Addr, Ipv6Addr},
@@ -8,7 +8,7 @@ use std::{
 
 use auto_enums::auto_enum;
 use idna::AsciiDenyList;
-use lazy_static::lazy_static;
+use memchr::memmem;
 use nom::{
     branch::alt,
     bytes::streaming::tag,
@@ -18,93 +18,202 @@ use nom::{
     sequence::{pair, preceded, terminated},
     IResult,
 };
-use regex_automata::{Regex, RegexBuilder, DFA};
 
 use crate::*;
 
-lazy_static! {
-    static ref HOSTNAME_ASCII: Regex = RegexBuilder::new().anchored(true).build(
-        r#"(?x)
-            \[IPv6: [:.[:xdigit:]]+ \] |             # Ipv6
-            \[ [.0-9]+ \] |                          # Ipv4
-            [[:alnum:]] ([-[:alnum:]]* [[:alnum:]])? # Ascii-only domain
-                ( \. [[:alnum:]] ([-[:alnum:]]* [[:alnum:]])? )*
-        "#
-    ).unwrap();
+/// Outcome of a scanner, that looks for the longest prefix of a buffer that
+/// follows some syntax
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Scan {
+    /// The first `n` bytes are the longest prefix following the syntax
+    Match(usize),
 
-    static ref HOSTNAME_UTF8: Regex = RegexBuilder::new().anchored(true).build(
-        r#"([-.[:alnum:]]|[[:^ascii:]])+"#
-    ).unwrap();
+    /// No prefix follows the syntax yet, but more data could change that
+    Incomplete,
 
-    // Note: we have to disable the x flag here so that the # in the
-    // middle of the character class does not get construed as a
-    // comment
-    static ref LOCALPART_ASCII: Regex = RegexBuilder::new().anchored(true).build(
-        r#"(?x)
-            " ( [[:ascii:]&&[^\\"[:cntrl:]]] |       # Quoted-string localpart
-                \\ [[:ascii:]&&[:^cntrl:]] )+ " |
-            (?-x)[a-zA-Z0-9!#$%&'*+-/=?^_`{|}~]+(?x) # Dot-string localpart
-                ( \. (?-x)[a-zA-Z0-9!#$%&'*+/=?^_`{|}~-]+(?x) )*
-        "#
-    ).unwrap();
+    /// No prefix follows the syntax, whatever data comes next
+    Invalid,
+}
 
-    // Note: we have to disable the x flag here so that the # in the
-    // middle of the character class does not get construed as a
-    // comment
-    static ref LOCALPART_UTF8: Regex = RegexBuilder::new().anchored(true).build(
-        r#"(?x)
-            " ( [^\\"[:cntrl:]] | \\ [[:^cntrl:]] )+ " |                # Quoted-string localpart
-            ( (?-x)[a-zA-Z0-9!#$%&'*+-/=?^_`{|}~](?x) | [[:^ascii:]] )+ # Dot-string localpart
-                ( \. ( (?-x)[a-zA-Z0-9!#$%&'*+-/=?^_`{|}~](?x) | [[:^ascii:]] )+ )*
-        "#
-    ).unwrap();
+impl Scan {
+    /// Outcome of a syntax that is a non-empty run of characters, given the
+    /// result of `take_run`
+    #[inline]
+    pub fn from_run((len, at_end): (usize, bool)) -> Scan {
+        match (len, at_end) {
+            (0, true) => Scan::Incomplete,
+            (0, false) => Scan::Invalid,
+            (len, _) => Scan::Match(len),
+        }
+    }
 }
 
-// Implementation is similar to regex_automata's, but also returns the state
-// when a match wasn't found
-fn find_dfa<D: DFA>(dfa: &D, buf: &[u8]) -> Result<usize, D::ID> {
-    let mut state = dfa.start_state();
-    let mut last_match = if dfa.is_dead_state(state) {
-        return Err(state);
-    } else if dfa.is_match_state(state) {
-        Some(0)
-    } else {
-        None
+pub fn apply_scanner(scanner: fn(&[u8]) -> Scan) -> impl FnMut(&[u8]) -> IResult<&[u8], &[u8]> {
+    move |buf: &[u8]| match scanner(buf) {
+        Scan::Match(end) => Ok((&buf[end..], &buf[..end])),
+        Scan::Incomplete => Err(nom::Err::Incomplete(nom::Needed::Unknown)),
+        Scan::Invalid => Err(nom::Err::Error(nom::error::Error::new(
+            buf,
+            nom::error::ErrorKind::Verify,
+        ))),
+    }
+}
+
+/// Length of the valid non-ASCII UTF-8 character at the start of `buf`, or
+/// whether it could still become one with more data
+#[inline]
+fn utf8_char_len(buf: &[u8]) -> Result<usize, bool> {
+    let len = match buf[0] {
+        0xC2..=0xDF => 2,
+        0xE0..=0xEF => 3,
+        0xF0..=0xF4 => 4,
+        _ => return Err(false),
     };
+    match str::from_utf8(&buf[..cmp::min(len, buf.len())]) {
+        Ok(_) => Ok(len),
+        Err(e) => Err(e.error_len().is_none()),
+    }
+}
 
-    for (i, &b) in buf.iter().enumerate() {
-        state = unsafe { dfa.next_state_unchecked(state, b) };
-        if dfa.is_match_or_dead_state(state) {
-            if dfa.is_dead_state(state) {
-                return last_match.ok_or(state);
+/// Length of the run of characters accepted by `ascii` at the start of `buf`,
+/// and whether the run stopped only because `buf` ended. With `utf8`, all the
+/// valid non-ASCII characters are accepted too.
+#[inline]
+pub fn take_run(buf: &[u8], utf8: bool, ascii: impl Fn(u8) -> bool) -> (usize, bool) {
+    let mut i = 0;
+    while i < buf.len() {
+        if buf[i].is_ascii() {
+            if !ascii(buf[i]) {
+                return (i, false);
+            }
+            i += 1;
+        } else if !utf8 {
+            return (i, false);
+        } else {
+            match utf8_char_len(&buf[i..]) {
+                Ok(len) => i += len,
+                Err(at_end) => return (i, at_end),
             }
-            last_match = Some(i + 1);
         }
     }
+    (i, true)
+}
 
-    last_match.ok_or(state)
+#[inline]
+pub fn is_cntrl(b: u8) -> bool {
+    b < 0x20 || b == 0x7F
 }
 
-pub fn apply_regex(regex: &Regex) -> impl '_ + FnMut(&[u8]) -> IResult<&[u8], &[u8]> {
-    move |buf: &[u8]| {
-        let dfa = regex.forward();
+/// `[IPv6:...]` or `[...]` address literals, or ASCII domains
+fn hostname_ascii(buf: &[u8]) -> Scan {
+    match buf.first() {
+        None => Scan::Incomplete,
+        Some(b'[') => address_literal(buf),
+        Some(_) => ascii_domain(buf),
+    }
+}
 
-        let dfa_result = match dfa {
-            regex_automata::DenseDFA::Standard(r) => find_dfa(r, buf),
-            regex_automata::DenseDFA::ByteClass(r) => find_dfa(r, buf),
-            regex_automata::DenseDFA::Premultiplied(r) => find_dfa(r, buf),
-            regex_automata::DenseDFA::PremultipliedByteClass(r) => find_dfa(r, buf),
-            other => find_dfa(other, buf),
-        };
+fn address_literal(buf: &[u8]) -> Scan {
+    let (start, class): (usize, fn(u8) -> bool) = match buf.get(1) {
+        None => return Scan::Incomplete,
+        Some(b'I') => {
+            for (i, &c) in b"[IPv6:".iter().enumerate().skip(2) {
+                match buf.get(i) {
+                    None => return Scan::Incomplete,
+                    Some(&b) if b == c => (),
+                    Some(_) => return Scan::Invalid,
+                }
+            }
+            (6, |b| b == b':' || b == b'.' || b.is_ascii_hexdigit())
+        }
+        Some(_) => (1, |b| b == b'.' || b.is_ascii_digit()),
+    };
+    let (len, _) = take_run(&buf[start..], false, class);
+    match buf.get(start + len) {
+        None => Scan::Incomplete,
+        Some(b']') if len > 0 => Scan::Match(start + len + 1),
+        Some(_) => Scan::Invalid,
+    }
+}
 
-        match dfa_result {
-            Ok(end) => Ok((&buf[end..], &buf[..end])),
-            Err(s) if dfa.is_dead_state(s) => Err(nom::Err::Error(nom::error::Error::new(
-                buf,
-                nom::error::ErrorKind::Verify,
-            ))),
-            Err(_) => Err(nom::Err::Incomplete(nom::Needed::Unknown)),
+/// Dot-separated labels made of alphanumeric characters, with hyphens only
+/// inside labels
+fn ascii_domain(buf: &[u8]) -> Scan {
+    let mut last_match = None;
+    let mut prev = None;
+    for (i, &b) in buf.iter().enumerate() {
+        let accepted = match b {
+            _ if b.is_ascii_alphanumeric() => true,
+            b'-' => prev.is_some() && prev != Some(b'.'),
+            b'.' => prev.is_some_and(|p: u8| p.is_ascii_alphanumeric()),
+            _ => false,
+        };
+        if !accepted {
+            return last_match.map_or(Scan::Invalid, Scan::Match);
         }
+        if b.is_ascii_alphanumeric() {
+            last_match = Some(i + 1);
+        }
+        prev = Some(b);
+    }
+    last_match.map_or(Scan::Incomplete, Scan::Match)
+}
+
+fn hostname_utf8(buf: &[u8]) -> Scan {
+    Scan::from_run(take_run(buf, true, |b| {
+        b == b'-' || b == b'.' || b.is_ascii_alphanumeric()
+    }))
+}
+
+/// Characters allowed in a dot-string local part. This includes the dot, so
+/// that the dot-string is just a run of these characters.
+#[inline]
+fn is_localpart_char(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+,-./=?^_`{|}~".contains(&b)
+}
+
+fn localpart_ascii(buf: &[u8]) -> Scan {
+    match buf.first() {
+        None => Scan::Incomplete,
+        Some(b'"') => quoted_localpart(buf, false),
+        Some(_) => Scan::from_run(take_run(buf, false, is_localpart_char)),
+    }
+}
+
+fn localpart_utf8(buf: &[u8]) -> Scan {
+    match buf.first() {
+        None => Scan::Incomplete,
+        Some(b'"') => quoted_localpart(buf, true),
+        Some(_) => Scan::from_run(take_run(buf, true, is_localpart_char)),
+    }
+}
+
+/// Non-empty quoted string, with backslash escapes
+fn quoted_localpart(buf: &[u8], utf8: bool) -> Scan {
+    let mut i = 1;
+    loop {
+        let escaped = buf.get(i) == Some(&b'\\');
+        let c = i + escaped as usize;
+        let len = match buf.get(c) {
+            None => return Scan::Incomplete,
+            Some(b'"') if !escaped => {
+                return match i {
+                    1 => Scan::Invalid,
+                    _ => Scan::Match(i + 1),
+                };
+            }
+            Some(&b) if b.is_ascii() => match is_cntrl(b) {
+                true => return Scan::Invalid,
+                false => 1,
+            },
+            Some(_) if !utf8 => return Scan::Invalid,
+            Some(_) => match utf8_char_len(&buf[c..]) {
+                Ok(len) => len,
+                Err(true) => return Scan::Incomplete,
+                Err(false) => return Scan::Invalid,
+            },
+        };
+        i = c + len;
     }
 }
 
@@ -133,7 +242,7 @@ pub fn next_crlf(buf: &[u8], state: &mut NextCrLfState) -> Option<usize> {
     if *state == NextCrLfState::CrPassed && buf[0] == b'\n' {
         return Some(0);
     }
-    if let Some(p) = buf.windows(2).position(|s| s == b"\r\n") {
+    if let Some(p) = memmem::find(buf, b"\r\n") {
         Some(p + 1)
     } else {
         *state = match buf[buf.len() - 1] {
@@ -182,6 +291,14 @@ where
             MaybeUtf8::Utf8(s) => s.as_ref(),
         }
     }
+
+    #[inline]
+    pub fn as_borrowed(&self) -> MaybeUtf8<&str> {
+        match self {
+            MaybeUtf8::Ascii(s) => MaybeUtf8::Ascii(s.as_ref()),
+            MaybeUtf8::Utf8(s) => MaybeUtf8::Utf8(s.as_ref()),
+        }
+    }
 }
 
 impl<'a, S> From<&'a str> for MaybeUtf8<S>
@@ -210,7 +327,7 @@ impl<T> MaybeUtf8<T> {
     }
 }
 
-// TODO: Ideally the ipv6 and ipv4 variants would be parsed in the single regex
+// TODO: Ideally the ipv6 and ipv4 variants would be parsed in the single scanner
 // pass. However, that's hard to do, so let's just not do it for now and keep it
 // as an optimization. So for now, it's just as well to return the parsed IPs,
 // but some day they will probably be removed
@@ -231,8 +348,8 @@ macro_rules! parse_hostname {
     (expected_length: $len:expr) => {
         parse_hostname!(
             @unsafe_impl,
-            apply_regex(&HOSTNAME_ASCII),
-            apply_regex(&HOSTNAME_UTF8),
+            apply_scanner(hostname_ascii),
+            apply_scanner(hostname_utf8),
             |b: &[u8]| $len == b.len(),
         )
     };
@@ -240,23 +357,23 @@ macro_rules! parse_hostname {
     (terminator: $until_term:ident) => {
         parse_hostname!(
             @unsafe_impl,
-            terminated(apply_regex(&HOSTNAME_ASCII), terminate($until_term)),
-            terminated(apply_regex(&HOSTNAME_UTF8), terminate($until_term)),
+            terminated(apply_scanner(hostname_ascii), terminate($until_term)),
+            terminated(apply_scanner(hostname_utf8), terminate($until_term)),
             |_| true,
         )
     };
 
-    (@unsafe_impl, $ascii_regex:expr, $utf8_regex:expr, $check:expr,) => {
+    (@unsafe_impl, $ascii_scanner:expr, $utf8_scanner:expr, $check:expr,) => {
         alt((
             map_opt(
-                $ascii_regex,
+                $ascii_scanner,
                 |b: &[u8]| {
                     if !$check(b) {
                         return None;
                     }
 
                     // The three below unsafe are OK, thanks to our
-                    // regex validating that `b` is proper ascii
+                    // scanner validating that `b` is proper ascii
                     // (and thus utf-8)
                     let s = unsafe { str::from_utf8_unchecked(b) };
 
@@ -276,14 +393,13 @@ macro_rules! parse_hostname {
                 },
             ),
             map_opt(
-                $utf8_regex,
+                $utf8_scanner,
                 |b: &[u8]| {
                     if !$check(b) {
                         return None;
                     }
 
-                    // The below unsafe is OK, thanks to our regex
-                    // never disabling the `u` flag and thus
+                    // The below unsafe is OK, thanks to our scanner
                     // validating that the match is proper utf-8
                     let raw = unsafe { str::from_utf8_unchecked(b) };
 
@@ -458,9 +574,9 @@ impl<S> Localpart<S> {
     {
         alt((
             map(
-                terminated(apply_regex(&LOCALPART_ASCII), terminate(term)),
+                terminated(apply_scanner(localpart_ascii), terminate(term)),
                 |b: &[u8]| {
-                    // The below unsafe is OK, thanks to our regex
+                    // The below unsafe is OK, thanks to our scanner
                     // validating that `b` is proper ascii (and thus
                     // utf-8)
                     let s = unsafe { str::from_utf8_unchecked(b) };
@@ -473,10 +589,10 @@ impl<S> Localpart<S> {
                 },
             ),
             map(
-                terminated(apply_regex(&LOCALPART_UTF8), terminate(term)),
+                terminated(apply_scanner(localpart_utf8), terminate(term)),
                 |b: &[u8]| {
-                    // The below unsafe is OK, thanks to our regex
-                    // validating that `b` is proper utf-8 by never disabling the `u` flag
+                    // The below unsafe is OK, thanks to our scanner
+                    // validating that `b` is proper utf-8
                     let s = unsafe { str::from_utf8_unchecked(b) };
 
                     if b[0] != b'"' {
@@ -795,8 +911,67 @@ where
 
 #[cfg(test)]
 mod tests {
+    use std::sync::LazyLock;
+
+    use quickcheck_macros::quickcheck;
+    use regex_automata::Regex;
+
     use super::*;
 
+    static HOSTNAME_ASCII: LazyLock<Regex> = LazyLock::new(|| {
+        differential::regex(
+            r#"(?x)
+                \[IPv6: [:.[:xdigit:]]+ \] |             # Ipv6
+                \[ [.0-9]+ \] |                          # Ipv4
+                [[:alnum:]] ([-[:alnum:]]* [[:alnum:]])? # Ascii-only domain
+                    ( \. [[:alnum:]] ([-[:alnum:]]* [[:alnum:]])? )*
+            "#,
+        )
+    });
+
+    static HOSTNAME_UTF8: LazyLock<Regex> =
+        LazyLock::new(|| differential::regex(r#"([-.[:alnum:]]|[[:^ascii:]])+"#));
+
+    // Note: we have to disable the x flag here so that the # in the
+    // middle of the character class does not get construed as a
+    // comment
+    static LOCALPART_ASCII: LazyLock<Regex> = LazyLock::new(|| {
+        differential::regex(
+            r#"(?x)
+                " ( [[:ascii:]&&[^\\"[:cntrl:]]] |       # Quoted-string localpart
+                    \\ [[:ascii:]&&[:^cntrl:]] )+ " |
+                (?-x)[a-zA-Z0-9!#$%&'*+-/=?^_`{|}~]+(?x) # Dot-string localpart
+                    ( \. (?-x)[a-zA-Z0-9!#$%&'*+/=?^_`{|}~-]+(?x) )*
+            "#,
+        )
+    });
+
+    static LOCALPART_UTF8: LazyLock<Regex> = LazyLock::new(|| {
+        differential::regex(
+            r#"(?x)
+                " ( [^\\"[:cntrl:]] | \\ [[:^cntrl:]] )+ " |                # Quoted-string localpart
+                ( (?-x)[a-zA-Z0-9!#$%&'*+-/=?^_`{|}~](?x) | [[:^ascii:]] )+ # Dot-string localpart
+                    ( \. ( (?-x)[a-zA-Z0-9!#$%&'*+-/=?^_`{|}~](?x) | [[:^ascii:]] )+ )*
+            "#,
+        )
+    });
+
+    #[test]
+    fn scanners_match_regexes() {
+        differential::check_exhaustive(&HOSTNAME_ASCII, hostname_ascii);
+        differential::check_exhaustive(&HOSTNAME_UTF8, hostname_utf8);
+        differential::check_exhaustive(&LOCALPART_ASCII, localpart_ascii);
+        differential::check_exhaustive(&LOCALPART_UTF8, localpart_utf8);
+    }
+
+    #[quickcheck]
+    fn scanners_match_regexes_random(indices: Vec<u8>, raw: Vec<u8>) {
+        differential::check_fragments(&HOSTNAME_ASCII, hostname_ascii, &indices, &raw);
+        differential::check_fragments(&HOSTNAME_UTF8, hostname_utf8, &indices, &raw);
+        differential::check_fragments(&LOCALPART_ASCII, localpart_ascii, &indices, &raw);
+        differential::check_fragments(&LOCALPART_UTF8, localpart_utf8, &indices, &raw);
+    }
+
     #[test]
     fn next_crlf_works() {
         let tests: &[(&[u8], NextCrLfState, Option<usize>, NextCrLfState)] = &[
@@ -843,6 +1018,20 @@ mod tests {
         }
     }
 
+    #[quickcheck]
+    fn next_crlf_finds_first_crlf(data: Vec<u8>, split: usize) {
+        let data = data
+            .iter()
+            .map(|b| b"\r\na."[*b as usize % 4])
+            .collect::<Vec<u8>>();
+        let expected = data.windows(2).position(|s| s == b"\r\n").map(|p| p + 1);
+        let split = split % (data.len() + 1);
+        let mut st = NextCrLfState::Start;
+        let res = next_crlf(&data[..split], &mut st)
+            .or_else(|| next_crlf(&data[split..], &mut st).map(|p| p + split));
+        assert_eq!(res, expected, "data: {:?}", show_bytes(&data));
+    }
+
     #[test]
     fn hostname_valid() {
         let tests: &[(&[u8], &[u8], Hostname<&str>)] = &[
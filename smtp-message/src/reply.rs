@@ -1,6 +1,6 @@
-use std::{convert::TryInto, fmt, io::IoSlice, iter, str};
+use std::{cmp, convert::TryInto, fmt, io::IoSlice, iter, str};
 
-use lazy_static::lazy_static;
+use auto_enums::auto_enum;
 use nom::{
     branch::alt,
     bytes::streaming::{tag, take},
@@ -9,27 +9,53 @@ use nom::{
     sequence::{pair, preceded, terminated, tuple},
     IResult,
 };
-use regex_automata::{Regex, RegexBuilder};
 
 use crate::*;
 
-lazy_static! {
-    static ref REPLY_CODE: Regex = RegexBuilder::new()
-        .anchored(true)
-        .build(r#"[2-5][0-9][0-9]"#)
-        .unwrap();
-    static ref EXTENDED_REPLY_CODE: Regex = RegexBuilder::new()
-        .anchored(true)
-        .build(r#"[245]\.[0-9]{1,3}\.[0-9]{1,3}"#)
-        .unwrap();
-    static ref REPLY_TEXT_ASCII: Regex = RegexBuilder::new()
-        .anchored(true)
-        .build(r#"[\t -~]*"#)
-        .unwrap();
-    static ref REPLY_TEXT_UTF8: Regex = RegexBuilder::new()
-        .anchored(true)
-        .build(r#"[\t -~[:^ascii:]]*"#)
-        .unwrap();
+fn reply_code(buf: &[u8]) -> Scan {
+    for (i, range) in [b'2'..=b'5', b'0'..=b'9', b'0'..=b'9'].iter().enumerate() {
+        match buf.get(i) {
+            None => return Scan::Incomplete,
+            Some(b) if range.contains(b) => (),
+            Some(_) => return Scan::Invalid,
+        }
+    }
+    Scan::Match(3)
+}
+
+/// `[245]`, then twice a dot followed by one to three digits
+fn enhanced_reply_code(buf: &[u8]) -> Scan {
+    match buf.first() {
+        None => return Scan::Incomplete,
+        Some(b'2' | b'4' | b'5') => (),
+        Some(_) => return Scan::Invalid,
+    }
+    let mut i = 1;
+    for last in [false, true] {
+        match buf.get(i) {
+            None => return Scan::Incomplete,
+            Some(b'.') => i += 1,
+            Some(_) => return Scan::Invalid,
+        }
+        let (len, at_end) = take_run(&buf[i..cmp::min(i + 3, buf.len())], false, |b| {
+            b.is_ascii_digit()
+        });
+        match len {
+            0 if at_end => return Scan::Incomplete,
+            0 => return Scan::Invalid,
+            _ if last => return Scan::Match(i + len),
+            _ => i += len,
+        }
+    }
+    unreachable!()
+}
+
+fn reply_text_ascii(buf: &[u8]) -> Scan {
+    Scan::Match(take_run(buf, false, |b| b == b'\t' || !is_cntrl(b)).0)
+}
+
+fn reply_text_utf8(buf: &[u8]) -> Scan {
+    Scan::Match(take_run(buf, true, |b| b == b'\t' || !is_cntrl(b)).0)
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -87,8 +113,8 @@ impl ReplyCode {
 impl ReplyCode {
     #[inline]
     pub fn parse(buf: &[u8]) -> IResult<&[u8], ReplyCode> {
-        map(apply_regex(&REPLY_CODE), |b| {
-            // The below unwrap is OK, as the regex already validated
+        map(apply_scanner(reply_code), |b| {
+            // The below unwrap is OK, as the scanner already validated
             // that there are exactly 3 characters
             ReplyCode(b.try_into().unwrap())
         })(buf)
@@ -151,7 +177,7 @@ pub enum EnhancedReplyCodeSubject {
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 #[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
-// Note: S is here always ascii-only, as we know the regex it matches
+// Note: S is here always ascii-only, as we know the scanner it matches
 pub struct EnhancedReplyCode<S> {
     pub raw: S,
     pub class: EnhancedReplyCodeClass,
@@ -297,16 +323,16 @@ impl<S> EnhancedReplyCode<S> {
     where
         S: From<&'a str>,
     {
-        map(apply_regex(&EXTENDED_REPLY_CODE), |raw| {
+        map(apply_scanner(enhanced_reply_code), |raw| {
             let class = raw[0] - b'0';
             let class = match class {
                 2 => EnhancedReplyCodeClass::Success,
                 4 => EnhancedReplyCodeClass::PersistentTransient,
                 5 => EnhancedReplyCodeClass::PermanentFailure,
-                _ => panic!("Regex allowed unexpected elements"),
+                _ => panic!("Scanner allowed unexpected elements"),
             };
             let after_class = &raw[2..];
-            // These unwrap and unsafe are OK thanks to the regex
+            // These unwrap and unsafe are OK thanks to the scanner
             // already matching
             let second_dot = after_class.iter().position(|c| *c == b'.').unwrap();
             let raw_subject = unsafe { str::from_utf8_unchecked(&after_class[..second_dot]) }
@@ -412,20 +438,20 @@ impl<S> ReplyLine<S> {
                 )),
                 alt((
                     map(
-                        terminated(apply_regex(&REPLY_TEXT_ASCII), tag(b"\r\n")),
+                        terminated(apply_scanner(reply_text_ascii), tag(b"\r\n")),
                         |b: &[u8]| {
                             // The below unsafe is OK, thanks to our
-                            // regex validating that `b` is proper
+                            // scanner validating that `b` is proper
                             // ascii (and thus utf-8)
                             let s = unsafe { str::from_utf8_unchecked(b) };
                             MaybeUtf8::Ascii(s.into())
                         },
                     ),
                     map(
-                        terminated(apply_regex(&REPLY_TEXT_UTF8), tag(b"\r\n")),
+                        terminated(apply_scanner(reply_text_utf8), tag(b"\r\n")),
                         |b: &[u8]| {
                             // The below unsafe is OK, thanks to our
-                            // regex validating that `b` is proper
+                            // scanner validating that `b` is proper
                             // utf8
                             let s = unsafe { str::from_utf8_unchecked(b) };
                             MaybeUtf8::Utf8(s.into())
@@ -448,7 +474,7 @@ fn line_as_io_slices<'a, S>(
     code: &'a ReplyCode,
     last: bool,
     ecode: &'a Option<EnhancedReplyCode<S>>,
-    text: &'a MaybeUtf8<S>,
+    text: &'a str,
 ) -> impl 'a + Iterator<Item = IoSlice<'a>>
 where
     S: AsRef<str>,
@@ -464,7 +490,7 @@ where
                 .iter()
                 .flat_map(|c| c.as_io_slices().chain(iter::once(IoSlice::new(b" ")))),
         )
-        .chain(text.as_io_slices())
+        .chain(iter::once(IoSlice::new(text.as_bytes())))
         .chain(iter::once(IoSlice::new(b"\r\n")))
 }
 
@@ -474,23 +500,153 @@ where
 {
     #[inline]
     pub fn as_io_slices(&self) -> impl Iterator<Item = IoSlice> {
-        line_as_io_slices(&self.code, self.last, &self.ecode, &self.text)
+        line_as_io_slices(&self.code, self.last, &self.ecode, self.text.as_str())
     }
 }
 
 // TODO: use ascii crate for From<&'a AsciiStr> instead of From<&'a
 // str> for the ascii variants
 
-#[derive(Clone, Debug, Eq, PartialEq)]
+/// Text lines of a [`Reply`]
+///
+/// Constant replies borrow their lines from a `'static` slice, so that building
+/// them does not allocate.
+#[derive(Clone, Debug)]
+pub enum ReplyLines<S = String> {
+    Static(&'static [MaybeUtf8<&'static str>]),
+    Owned(Vec<MaybeUtf8<S>>),
+}
+
+impl<S> ReplyLines<S> {
+    #[inline]
+    pub fn len(&self) -> usize {
+        match self {
+            ReplyLines::Static(lines) => lines.len(),
+            ReplyLines::Owned(lines) => lines.len(),
+        }
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Mutable access to the lines, copying them out of the `'static` slice
+    /// first if needed
+    pub fn to_mut(&mut self) -> &mut Vec<MaybeUtf8<S>>
+    where
+        S: From<&'static str>,
+    {
+        match self {
+            ReplyLines::Owned(lines) => lines,
+            ReplyLines::Static(lines) => {
+                *self = ReplyLines::Owned(lines.iter().map(|l| l.convert()).collect());
+                self.to_mut()
+            }
+        }
+    }
+
+    pub fn convert<T>(self) -> ReplyLines<T>
+    where
+        T: From<S>,
+    {
+        match self {
+            ReplyLines::Static(lines) => ReplyLines::Static(lines),
+            ReplyLines::Owned(lines) => {
+                ReplyLines::Owned(lines.into_iter().map(|l| l.convert()).collect())
+            }
+        }
+    }
+}
+
+impl<S> ReplyLines<S>
+where
+    S: AsRef<str>,
+{
+    #[auto_enum(Iterator)]
+    #[inline]
+    pub fn iter(&self) -> impl Iterator<Item = MaybeUtf8<&str>> {
+        match self {
+            ReplyLines::Static(lines) => lines.iter().map(|l| l.as_borrowed()),
+            ReplyLines::Owned(lines) => lines.iter().map(|l| l.as_borrowed()),
+        }
+    }
+}
+
+impl<S> From<Vec<MaybeUtf8<S>>> for ReplyLines<S> {
+    #[inline]
+    fn from(lines: Vec<MaybeUtf8<S>>) -> ReplyLines<S> {
+        ReplyLines::Owned(lines)
+    }
+}
+
+impl<S, T> PartialEq<ReplyLines<T>> for ReplyLines<S>
+where
+    S: AsRef<str>,
+    T: AsRef<str>,
+{
+    #[inline]
+    fn eq(&self, other: &ReplyLines<T>) -> bool {
+        self.iter().eq(other.iter())
+    }
+}
+
+impl<S> Eq for ReplyLines<S> where S: AsRef<str> {}
+
+#[cfg(feature = "serde")]
+impl<S> serde::Serialize for ReplyLines<S>
+where
+    S: serde::Serialize,
+{
+    fn serialize<Ser>(&self, serializer: Ser) -> Result<Ser::Ok, Ser::Error>
+    where
+        Ser: serde::Serializer,
+    {
+        match self {
+            ReplyLines::Static(lines) => lines.serialize(serializer),
+            ReplyLines::Owned(lines) => lines.serialize(serializer),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, S> serde::Deserialize<'de> for ReplyLines<S>
+where
+    S: serde::Deserialize<'de>,
+{
+    fn deserialize<D>(deserializer: D) -> Result<ReplyLines<S>, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        Vec::deserialize(deserializer).map(ReplyLines::Owned)
+    }
+}
+
+#[derive(Clone, Debug)]
 #[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
 pub struct Reply<S = String> {
     pub code: ReplyCode,
     pub ecode: Option<EnhancedReplyCode<S>>,
-    // TODO: should we try to make constructing a constant reply noalloc?
-    pub text: Vec<MaybeUtf8<S>>,
+    /// Note: this used to be a `Vec`, so struct literals now need a `.into()`
+    /// on it, or can be replaced with [`Reply::new`]
+    pub text: ReplyLines<S>,
 }
 
 impl<S> Reply<S> {
+    /// Build a reply from its `text` lines, given as a `Vec` or as
+    /// [`ReplyLines`]
+    #[inline]
+    pub fn new<T>(code: ReplyCode, ecode: Option<EnhancedReplyCode<S>>, text: T) -> Reply<S>
+    where
+        T: Into<ReplyLines<S>>,
+    {
+        Reply {
+            code,
+            ecode,
+            text: text.into(),
+        }
+    }
+
     #[inline]
     pub fn parse<'a>(buf: &'a [u8]) -> IResult<&'a [u8], Reply<S>>
     where
@@ -509,11 +665,12 @@ impl<S> Reply<S> {
             |(beg, end)| Reply {
                 code: end.code,
                 ecode: end.ecode,
-                text: beg
-                    .into_iter()
-                    .map(|l| l.text)
-                    .chain(iter::once(end.text))
-                    .collect(),
+                text: ReplyLines::Owned(
+                    beg.into_iter()
+                        .map(|l| l.text)
+                        .chain(iter::once(end.text))
+                        .collect(),
+                ),
             },
         )(buf)
     }
@@ -528,13 +685,36 @@ where
         let code = &self.code;
         let ecode = &self.ecode;
         let last_i = self.text.len() - 1;
-        self.text
-            .iter()
-            .enumerate()
-            .flat_map(move |(i, l)| line_as_io_slices(code, i == last_i, ecode, l))
+        self.text.iter().enumerate().flat_map(move |(i, l)| {
+            let text = match l {
+                MaybeUtf8::Ascii(s) | MaybeUtf8::Utf8(s) => s,
+            };
+            line_as_io_slices(code, i == last_i, ecode, text)
+        })
+    }
+
+    /// Append the reply, as it is sent on the wire, to `buf`. Reusing the same
+    /// `buf` for all the replies avoids allocating once it is large enough.
+    #[inline]
+    pub fn write_into(&self, buf: &mut Vec<u8>) {
+        for s in self.as_io_slices() {
+            buf.extend_from_slice(&s);
+        }
+    }
+}
+
+impl<S> PartialEq for Reply<S>
+where
+    S: AsRef<str> + PartialEq,
+{
+    #[inline]
+    fn eq(&self, other: &Reply<S>) -> bool {
+        self.code == other.code && self.ecode == other.ecode && self.text == other.text
     }
 }
 
+impl<S> Eq for Reply<S> where S: AsRef<str> + Eq {}
+
 impl<S> fmt::Display for Reply<S>
 where
     S: AsRef<str>,
@@ -554,7 +734,7 @@ impl Reply<&str> {
         Reply {
             code: self.code,
             ecode: self.ecode.map(|c| c.to_owned()),
-            text: self.text.into_iter().map(|l| l.to_owned()).collect(),
+            text: self.text.convert(),
         }
     }
 }
@@ -567,15 +747,44 @@ impl<U> Reply<U> {
         Reply {
             code: self.code,
             ecode: self.ecode.map(|e| e.convert()),
-            text: self.text.into_iter().map(|t| t.convert()).collect(),
+            text: self.text.convert(),
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::sync::LazyLock;
+
+    use quickcheck_macros::quickcheck;
+    use regex_automata::Regex;
+
     use super::*;
 
+    static REPLY_CODE: LazyLock<Regex> =
+        LazyLock::new(|| differential::regex(r#"[2-5][0-9][0-9]"#));
+    static EXTENDED_REPLY_CODE: LazyLock<Regex> =
+        LazyLock::new(|| differential::regex(r#"[245]\.[0-9]{1,3}\.[0-9]{1,3}"#));
+    static REPLY_TEXT_ASCII: LazyLock<Regex> = LazyLock::new(|| differential::regex(r#"[\t -~]*"#));
+    static REPLY_TEXT_UTF8: LazyLock<Regex> =
+        LazyLock::new(|| differential::regex(r#"[\t -~[:^ascii:]]*"#));
+
+    #[test]
+    fn scanners_match_regexes() {
+        differential::check_exhaustive(&REPLY_CODE, reply_code);
+        differential::check_exhaustive(&EXTENDED_REPLY_CODE, enhanced_reply_code);
+        differential::check_exhaustive(&REPLY_TEXT_ASCII, reply_text_ascii);
+        differential::check_exhaustive(&REPLY_TEXT_UTF8, reply_text_utf8);
+    }
+
+    #[quickcheck]
+    fn scanners_match_regexes_random(indices: Vec<u8>, raw: Vec<u8>) {
+        differential::check_fragments(&REPLY_CODE, reply_code, &indices, &raw);
+        differential::check_fragments(&EXTENDED_REPLY_CODE, enhanced_reply_code, &indices, &raw);
+        differential::check_fragments(&REPLY_TEXT_ASCII, reply_text_ascii, &indices, &raw);
+        differential::check_fragments(&REPLY_TEXT_UTF8, reply_text_utf8, &indices, &raw);
+    }
+
     #[test]
     fn reply_code_valid() {
         let tests: &[(&[u8], [u8; 3])] = &[(b"523", *b"523"), (b"234", *b"234")];
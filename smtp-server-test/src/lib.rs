@@ -0,0 +1,612 @@
+//! Scripted clients for testing [`smtp_server::Config`] implementations
+//!
+//! A [`Client`] is a list of steps, played against
+//! [`interact`](smtp_server::interact) over an in-memory connection:
+//!
+//! ```
+//! use std::sync::Arc;
+//!
+//! use smtp_server_test::{Client, MockConfig};
+//!
+//! let cfg = Arc::new(MockConfig::default());
+//! let session = smol::block_on(
+//!     Client::new()
+//!         .expect(220)
+//!         .send("EHLO x")
+//!         .expect_code(250)
+//!         .expect_capability("PIPELINING")
+//!         .send("MAIL FROM:<alice@example.org>")
+//!         .expect(250)
+//!         .send("RCPT TO:<bob@example.org>")
+//!         .expect(250)
+//!         .send("DATA")
+//!         .expect(354)
+//!         .send("Hello\r\n.")
+//!         .expect(250)
+//!         .send("QUIT")
+//!         .expect(221)
+//!         .run(cfg.clone(), ()),
+//! )
+//! .expect("script failed");
+//! assert!(session.result.is_ok());
+//! assert_eq!(cfg.mails.mails()[0].data, b"Hello\r\n");
+//! ```
+//!
+//! [`MailRecorder`] and [`MockTls`] can be used by any `Config` to record the
+//! mails it accepts and to go through STARTTLS without certificates.
+//!
+//! Sessions recorded with a [`TranscriptSink`](smtp_server::TranscriptSink)
+//! can be played again with a [`Replay`](replay::Replay), to check that a
+//! `Config` still replies the same way.
+
+mod mock;
+pub mod replay;
+
+use std::{fmt, io, sync::Arc, time::Duration};
+
+use duplexify::Duplex;
+use futures::{AsyncReadExt, AsyncWriteExt};
+use smol::future::FutureExt;
+
+use smtp_message::MaybeUtf8;
+use smtp_server::{interact, Config, IsAlreadyTls, SessionError, TlsInfo};
+
+pub use mock::{
+    Mail, MailRecorder, MockConfig, MockTls, MOCK_TLS_CLIENT_HELLO, MOCK_TLS_SERVER_HELLO,
+};
+
+/// Replies as parsed by the client
+pub type Reply = smtp_server::Reply<String>;
+
+const PIPE_SIZE: usize = 1024 * 1024;
+
+/// Step of a [`Client`] script
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Step {
+    /// Send these bytes
+    Send(Vec<u8>),
+
+    /// Read the next reply and check its code
+    ExpectCode(u16),
+
+    /// Read the next reply and check it is exactly this one
+    ExpectReply(Reply),
+
+    /// Check that the last reply, to an EHLO, advertises this capability
+    ExpectCapability(String),
+
+    /// Check that a line of the last reply contains this text
+    ExpectText(String),
+
+    /// Play the client side of the [`MockTls`] handshake
+    TlsHandshake,
+
+    /// Check that the server closed the connection
+    ExpectClosed,
+}
+
+/// Scripted SMTP client
+///
+/// Each `expect*` step waits for at most `timeout`, so that a script that
+/// does not match what the server does fails instead of hanging.
+#[derive(Clone, Debug)]
+pub struct Client {
+    steps: Vec<Step>,
+    implicit_tls: Option<TlsInfo>,
+    timeout: Duration,
+}
+
+impl Default for Client {
+    fn default() -> Client {
+        Client {
+            steps: Vec::new(),
+            implicit_tls: None,
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl Client {
+    pub fn new() -> Client {
+        Client::default()
+    }
+
+    /// Run the session as if TLS had been negotiated with `info` before
+    /// `interact`
+    pub fn implicit_tls(mut self, info: TlsInfo) -> Client {
+        self.implicit_tls = Some(info);
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Client {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn step(mut self, step: Step) -> Client {
+        self.steps.push(step);
+        self
+    }
+
+    /// Send `line`, followed by CRLF
+    pub fn send(self, line: &str) -> Client {
+        let mut bytes = line.as_bytes().to_vec();
+        bytes.extend_from_slice(b"\r\n");
+        self.step(Step::Send(bytes))
+    }
+
+    /// Send `bytes` as-is
+    pub fn send_raw(self, bytes: &[u8]) -> Client {
+        self.step(Step::Send(bytes.to_vec()))
+    }
+
+    /// Same as [`expect_code`](Client::expect_code)
+    pub fn expect(self, code: u16) -> Client {
+        self.expect_code(code)
+    }
+
+    pub fn expect_code(self, code: u16) -> Client {
+        self.step(Step::ExpectCode(code))
+    }
+
+    pub fn expect_reply(self, reply: Reply) -> Client {
+        self.step(Step::ExpectReply(reply))
+    }
+
+    /// Matches either the keyword of a capability, like `SIZE`, or the whole
+    /// line, like `SIZE 1000000`, ignoring case
+    pub fn expect_capability(self, capability: &str) -> Client {
+        self.step(Step::ExpectCapability(capability.into()))
+    }
+
+    pub fn expect_text(self, text: &str) -> Client {
+        self.step(Step::ExpectText(text.into()))
+    }
+
+    pub fn tls_handshake(self) -> Client {
+        self.step(Step::TlsHandshake)
+    }
+
+    /// Send STARTTLS, expect a 220 and go through the [`MockTls`] handshake
+    pub fn starttls(self) -> Client {
+        self.send("STARTTLS").expect_code(220).tls_handshake()
+    }
+
+    pub fn expect_closed(self) -> Client {
+        self.step(Step::ExpectClosed)
+    }
+
+    /// Play the script against `interact`, then close the connection
+    pub async fn run<Cfg>(
+        self,
+        cfg: Arc<Cfg>,
+        metadata: Cfg::ConnectionUserMeta,
+    ) -> Result<Session, ScriptError>
+    where
+        Cfg: Config,
+    {
+        let (server_in, client_out) = piper::pipe(PIPE_SIZE);
+        let (client_in, server_out) = piper::pipe(PIPE_SIZE);
+        let is_already_tls = match self.implicit_tls {
+            Some(info) => IsAlreadyTls::Yes(info),
+            None => IsAlreadyTls::No,
+        };
+        let mut conn = Connection {
+            reader: client_in,
+            writer: client_out,
+            buf: Vec::new(),
+            replies: Vec::new(),
+            timeout: self.timeout,
+        };
+        let (script, result) = futures::future::join(
+            async move {
+                // Dropping the connection once done lets interact see the end
+                // of the input
+                let res = conn.play(&self.steps).await;
+                (res, conn.replies)
+            },
+            interact(
+                Duplex::new(server_in, server_out),
+                is_already_tls,
+                metadata,
+                cfg,
+            ),
+        )
+        .await;
+        match script {
+            (Ok(()), replies) => Ok(Session { replies, result }),
+            (Err((step, failure)), replies) => Err(ScriptError {
+                step,
+                failure,
+                replies,
+            }),
+        }
+    }
+}
+
+/// Outcome of a [`Client`] script that ran to its end
+#[derive(Debug)]
+pub struct Session {
+    /// All the replies received, in order
+    pub replies: Vec<Reply>,
+
+    /// What `interact` returned
+    pub result: Result<(), SessionError>,
+}
+
+/// Reason why a step of a [`Client`] script failed
+#[derive(Debug)]
+pub enum Failure {
+    UnexpectedCode {
+        expected: u16,
+        got: Reply,
+    },
+    UnexpectedReply {
+        expected: Reply,
+        got: Reply,
+    },
+    MissingCapability {
+        capability: String,
+    },
+    MissingText {
+        text: String,
+    },
+    /// A capability or text was expected before any reply was received
+    NoReply,
+    /// The server sent something that is not a reply
+    InvalidReply(Vec<u8>),
+    /// The server did not start the [`MockTls`] handshake
+    InvalidTlsHandshake(Vec<u8>),
+    /// The server sent this instead of closing the connection
+    NotClosed(Vec<u8>),
+    /// The server closed the connection
+    Closed,
+    Timeout,
+    Io(io::Error),
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Failure::UnexpectedCode { expected, got } => {
+                write!(
+                    f,
+                    "expected a {} reply, got {:?}",
+                    expected,
+                    got.to_string()
+                )
+            }
+            Failure::UnexpectedReply { expected, got } => write!(
+                f,
+                "expected reply {:?}, got {:?}",
+                expected.to_string(),
+                got.to_string()
+            ),
+            Failure::MissingCapability { capability } => {
+                write!(f, "capability {:?} was not advertised", capability)
+            }
+            Failure::MissingText { text } => write!(f, "reply does not contain {:?}", text),
+            Failure::NoReply => write!(f, "no reply was received yet"),
+            Failure::InvalidReply(b) => {
+                write!(f, "invalid reply {:?}", String::from_utf8_lossy(b))
+            }
+            Failure::InvalidTlsHandshake(b) => {
+                write!(f, "invalid TLS handshake {:?}", String::from_utf8_lossy(b))
+            }
+            Failure::NotClosed(b) => write!(
+                f,
+                "expected the connection to be closed, got {:?}",
+                String::from_utf8_lossy(b)
+            ),
+            Failure::Closed => write!(f, "the server closed the connection"),
+            Failure::Timeout => write!(f, "timed out waiting for the server"),
+            Failure::Io(e) => write!(f, "i/o error: {}", e),
+        }
+    }
+}
+
+/// Failure of a [`Client`] script
+#[derive(Debug)]
+pub struct ScriptError {
+    /// Index of the failed step in the script
+    pub step: usize,
+    pub failure: Failure,
+    /// The replies received before the failure
+    pub replies: Vec<Reply>,
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "step {} failed: {}", self.step, self.failure)?;
+        for r in &self.replies {
+            write!(f, "\nreceived: {:?}", r.to_string())?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ScriptError {}
+
+struct Connection {
+    reader: piper::Reader,
+    writer: piper::Writer,
+    buf: Vec<u8>,
+    replies: Vec<Reply>,
+    timeout: Duration,
+}
+
+impl Connection {
+    async fn play(&mut self, steps: &[Step]) -> Result<(), (usize, Failure)> {
+        for (i, step) in steps.iter().enumerate() {
+            self.play_step(step).await.map_err(|f| (i, f))?;
+        }
+        Ok(())
+    }
+
+    async fn play_step(&mut self, step: &Step) -> Result<(), Failure> {
+        match step {
+            Step::Send(bytes) => self.write(bytes).await,
+            Step::ExpectCode(expected) => {
+                let reply = self.read_reply().await?;
+                if reply.code.code() == *expected {
+                    Ok(())
+                } else {
+                    Err(Failure::UnexpectedCode {
+                        expected: *expected,
+                        got: reply,
+                    })
+                }
+            }
+            Step::ExpectReply(expected) => {
+                let reply = self.read_reply().await?;
+                if reply == *expected {
+                    Ok(())
+                } else {
+                    Err(Failure::UnexpectedReply {
+                        expected: expected.clone(),
+                        got: reply,
+                    })
+                }
+            }
+            Step::ExpectCapability(capability) => {
+                let reply = self.replies.last().ok_or(Failure::NoReply)?;
+                let advertised = reply.text.iter().skip(1).any(|line| {
+                    let line = line_str(&line);
+                    line.eq_ignore_ascii_case(capability)
+                        || line
+                            .split(' ')
+                            .next()
+                            .is_some_and(|k| k.eq_ignore_ascii_case(capability))
+                });
+                match advertised {
+                    true => Ok(()),
+                    false => Err(Failure::MissingCapability {
+                        capability: capability.clone(),
+                    }),
+                }
+            }
+            Step::ExpectText(text) => {
+                let reply = self.replies.last().ok_or(Failure::NoReply)?;
+                match reply.text.iter().any(|l| line_str(&l).contains(&**text)) {
+                    true => Ok(()),
+                    false => Err(Failure::MissingText { text: text.clone() }),
+                }
+            }
+            Step::TlsHandshake => {
+                let len = MOCK_TLS_SERVER_HELLO.len();
+                while self.buf.len() < len {
+                    self.fill().await?;
+                }
+                if &self.buf[..len] != MOCK_TLS_SERVER_HELLO {
+                    return Err(Failure::InvalidTlsHandshake(self.buf.clone()));
+                }
+                self.buf.drain(..len);
+                self.write(MOCK_TLS_CLIENT_HELLO).await
+            }
+            Step::ExpectClosed => {
+                if self.buf.is_empty() {
+                    match self.fill().await {
+                        Err(Failure::Closed) => return Ok(()),
+                        Err(e) => return Err(e),
+                        Ok(()) => (),
+                    }
+                }
+                Err(Failure::NotClosed(self.buf.clone()))
+            }
+        }
+    }
+
+    async fn write(&mut self, bytes: &[u8]) -> Result<(), Failure> {
+        let timeout = self.timeout;
+        async { self.writer.write_all(bytes).await.map_err(Failure::Io) }
+            .or(async {
+                smol::Timer::after(timeout).await;
+                Err(Failure::Timeout)
+            })
+            .await
+    }
+
+    /// Read more of the server's output into `buf`
+    async fn fill(&mut self) -> Result<(), Failure> {
+        let timeout = self.timeout;
+        let mut chunk = [0; 1024];
+        let read = async { self.reader.read(&mut chunk).await.map_err(Failure::Io) }
+            .or(async {
+                smol::Timer::after(timeout).await;
+                Err(Failure::Timeout)
+            })
+            .await?;
+        if read == 0 {
+            return Err(Failure::Closed);
+        }
+        self.buf.extend_from_slice(&chunk[..read]);
+        Ok(())
+    }
+
+    async fn read_reply(&mut self) -> Result<Reply, Failure> {
+        let len = loop {
+            if let Some(len) = reply_len(&self.buf) {
+                break len;
+            }
+            self.fill().await?;
+        };
+        let reply = match smtp_server::Reply::<&str>::parse(&self.buf[..len]) {
+            Ok((&[], reply)) => reply.into_owned(),
+            _ => return Err(Failure::InvalidReply(self.buf[..len].to_vec())),
+        };
+        self.buf.drain(..len);
+        self.replies.push(reply.clone());
+        Ok(reply)
+    }
+}
+
+/// Length of the first complete reply in `buf`, if any, ie. up to the first
+/// line whose code is not followed by a `-`
+fn reply_len(buf: &[u8]) -> Option<usize> {
+    let mut start = 0;
+    while let Some(pos) = buf[start..].windows(2).position(|w| w == b"\r\n") {
+        let end = start + pos + 2;
+        if buf[start..end].get(3) != Some(&b'-') {
+            return Some(end);
+        }
+        start = end;
+    }
+    None
+}
+
+fn line_str<'a>(line: &MaybeUtf8<&'a str>) -> &'a str {
+    match line {
+        MaybeUtf8::Ascii(s) | MaybeUtf8::Utf8(s) => s,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use smtp_message::Email;
+
+    fn ehlo() -> Client {
+        Client::new()
+            .expect(220)
+            .send("EHLO client.example.org")
+            .expect(250)
+    }
+
+    #[test]
+    fn records_mails() {
+        let cfg = Arc::new(MockConfig::default());
+        let session = smol::block_on(
+            ehlo()
+                .expect_capability("pipelining")
+                .expect_capability("STARTTLS")
+                .send("MAIL FROM:<alice@example.org>")
+                .expect(250)
+                .send("RCPT TO:<bob@example.org>")
+                .expect(250)
+                .send("RCPT TO:<carol@example.org>")
+                .expect(250)
+                .send("DATA")
+                .expect(354)
+                .send_raw(b"Hello\r\n..world\r\n.\r\n")
+                .expect(250)
+                .send("QUIT")
+                .expect(221)
+                .expect_closed()
+                .run(cfg.clone(), ()),
+        )
+        .unwrap();
+        session.result.unwrap();
+        assert_eq!(session.replies.len(), 8);
+        let email = |s: &str| Email::parse_bracketed(s.as_bytes()).unwrap();
+        assert_eq!(
+            cfg.mails.take(),
+            vec![Mail {
+                from: Some(email("<alice@example.org>")),
+                to: vec![email("<bob@example.org>"), email("<carol@example.org>")],
+                data: b"Hello\r\n.world\r\n".to_vec(),
+                raw: b"Hello\r\n..world\r\n.\r\n".to_vec(),
+                tls: None,
+            }]
+        );
+        assert!(cfg.mails.mails().is_empty());
+    }
+
+    #[test]
+    fn starttls_with_mock_tls() {
+        let cfg = Arc::new(MockConfig::default());
+        let session = smol::block_on(
+            ehlo()
+                .expect_capability("STARTTLS")
+                .starttls()
+                .send("EHLO client.example.org")
+                .expect(250)
+                .expect_text("test.example.org")
+                .send("MAIL FROM:<alice@example.org>")
+                .expect(250)
+                .send("RCPT TO:<bob@example.org>")
+                .expect(250)
+                .send("DATA")
+                .expect(354)
+                .send("Hello\r\n.")
+                .expect(250)
+                .send("QUIT")
+                .expect(221)
+                .run(cfg.clone(), ()),
+        )
+        .unwrap();
+        session.result.unwrap();
+        let mails = cfg.mails.take();
+        assert_eq!(mails.len(), 1);
+        assert_eq!(mails[0].tls, Some(cfg.tls.info.clone()));
+
+        // STARTTLS is not offered again once done
+        let err = smol::block_on(
+            ehlo()
+                .starttls()
+                .send("EHLO client.example.org")
+                .expect(250)
+                .expect_text("test.example.org")
+                .expect_capability("STARTTLS")
+                .run(cfg, ()),
+        )
+        .unwrap_err();
+        assert_eq!(err.step, 9);
+        assert!(matches!(err.failure, Failure::MissingCapability { .. }));
+        assert_eq!(err.replies.len(), 4);
+    }
+
+    #[test]
+    fn reports_unexpected_reply() {
+        let cfg = Arc::new(MockConfig::default());
+        let err = smol::block_on(
+            ehlo()
+                .send("RCPT TO:<bob@example.org>")
+                .expect(250)
+                .run(cfg, ()),
+        )
+        .unwrap_err();
+        assert_eq!(err.step, 4);
+        match err.failure {
+            Failure::UnexpectedCode { expected, got } => {
+                assert_eq!(expected, 250);
+                assert_eq!(got.code.code(), 503);
+            }
+            f => panic!("unexpected failure {:?}", f),
+        }
+    }
+
+    #[test]
+    fn times_out_instead_of_hanging() {
+        let cfg = Arc::new(MockConfig::default());
+        let err = smol::block_on(
+            ehlo()
+                .timeout(Duration::from_millis(50))
+                .expect(250)
+                .run(cfg, ()),
+        )
+        .unwrap_err();
+        assert_eq!(err.step, 3);
+        assert!(matches!(err.failure, Failure::Timeout));
+    }
+}
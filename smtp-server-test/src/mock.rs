@@ -0,0 +1,221 @@
+use std::{
+    io,
+    sync::{Arc, Mutex},
+};
+
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use smtp_message::{DataUnescaper, Email, EscapedDataReader};
+use smtp_server::{protocol, reply, ConnectionMetadata, Decision, MailMetadata, TlsInfo};
+
+/// Sent by [`MockTls::accept`] to open the fake handshake
+pub const MOCK_TLS_SERVER_HELLO: &[u8] = b"<mock tls server>";
+
+/// Expected by [`MockTls::accept`] from the client to finish the fake
+/// handshake, sent by [`Client::tls_handshake`](crate::Client::tls_handshake)
+pub const MOCK_TLS_CLIENT_HELLO: &[u8] = b"<mock tls client>";
+
+/// Fake TLS acceptor, for testing the STARTTLS paths of a `Config` without
+/// certificates
+///
+/// The handshake is the exchange of [`MOCK_TLS_SERVER_HELLO`] and
+/// [`MOCK_TLS_CLIENT_HELLO`], after which the stream is used unencrypted.
+#[derive(Clone, Debug)]
+pub struct MockTls {
+    /// Returned by `accept` as if negotiated with the client, set
+    /// `peer_certificates` and `verified` to test client certificate
+    /// authentication
+    pub info: TlsInfo,
+}
+
+impl Default for MockTls {
+    fn default() -> MockTls {
+        MockTls {
+            info: TlsInfo {
+                protocol_version: Some("mock".into()),
+                ..TlsInfo::default()
+            },
+        }
+    }
+}
+
+impl MockTls {
+    /// To be called from [`Config::tls_accept`](smtp_server::Config::tls_accept),
+    /// with `type TlsStream<IO> = IO`
+    pub async fn accept<IO>(&self, mut io: IO) -> io::Result<(IO, TlsInfo)>
+    where
+        IO: Unpin + AsyncRead + AsyncWrite,
+    {
+        io.write_all(MOCK_TLS_SERVER_HELLO).await?;
+        io.flush().await?;
+        let mut buf = [0; MOCK_TLS_CLIENT_HELLO.len()];
+        io.read_exact(&mut buf).await?;
+        if buf != MOCK_TLS_CLIENT_HELLO {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("got mock TLS handshake {:?}", String::from_utf8_lossy(&buf)),
+            ));
+        }
+        Ok((io, self.info.clone()))
+    }
+}
+
+/// Mail received by [`MailRecorder::record`]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Mail {
+    pub from: Option<Email>,
+    pub to: Vec<Email>,
+    /// Contents of the mail, unescaped and without the final `.\r\n`
+    pub data: Vec<u8>,
+    /// Contents of the mail as received, still escaped and including the
+    /// final `.\r\n`
+    pub raw: Vec<u8>,
+    /// TLS parameters of the connection the mail came through, if encrypted
+    pub tls: Option<TlsInfo>,
+}
+
+/// Shared list of the mails delivered to `handle_mail`
+///
+/// Clones share the same list, so that the test can keep one while the
+/// `Config` records into another.
+#[derive(Clone, Debug, Default)]
+pub struct MailRecorder {
+    mails: Arc<Mutex<Vec<Mail>>>,
+}
+
+impl MailRecorder {
+    pub fn new() -> MailRecorder {
+        MailRecorder::default()
+    }
+
+    /// To be called from [`Config::handle_mail`](smtp_server::Config::handle_mail):
+    /// reads the mail to its end, marks `reader` complete and records the mail
+    pub async fn record<U, C, R>(
+        &self,
+        reader: &mut EscapedDataReader<'_, R>,
+        meta: &MailMetadata<U>,
+        conn_meta: &ConnectionMetadata<C>,
+    ) -> io::Result<()>
+    where
+        R: Send + Unpin + AsyncRead,
+    {
+        let mut raw = Vec::new();
+        reader.read_to_end(&mut raw).await?;
+        reader.complete();
+        let mut data = raw.clone();
+        let unescaped = DataUnescaper::new(true).unescape(&mut data);
+        data.truncate(unescaped.written);
+        self.mails.lock().expect("failed to lock mutex").push(Mail {
+            from: meta.from.clone(),
+            to: meta.to.clone(),
+            data,
+            raw,
+            tls: conn_meta.tls.clone(),
+        });
+        Ok(())
+    }
+
+    /// The mails recorded so far
+    pub fn mails(&self) -> Vec<Mail> {
+        self.mails.lock().expect("failed to lock mutex").clone()
+    }
+
+    /// The mails recorded so far, clearing the list
+    pub fn take(&self) -> Vec<Mail> {
+        std::mem::take(&mut *self.mails.lock().expect("failed to lock mutex"))
+    }
+}
+
+/// SMTP `Config` accepting all mails, recording them in `mails`, and doing
+/// STARTTLS with `tls`
+///
+/// For tests that only need a server to talk to, or as a starting point when
+/// only a few hooks are under test.
+#[derive(Clone, Debug)]
+pub struct MockConfig {
+    pub hostname: String,
+    pub mails: MailRecorder,
+    pub tls: MockTls,
+}
+
+impl Default for MockConfig {
+    fn default() -> MockConfig {
+        MockConfig {
+            hostname: "test.example.org".into(),
+            mails: MailRecorder::default(),
+            tls: MockTls::default(),
+        }
+    }
+}
+
+impl smtp_server::Config for MockConfig {
+    type ConnectionUserMeta = ();
+    type MailUserMeta = ();
+    type Protocol = protocol::Smtp;
+
+    fn hostname(&self, _conn_meta: &ConnectionMetadata<()>) -> &str {
+        &self.hostname
+    }
+
+    type TlsStream<IO>
+        = IO
+    where
+        IO: 'static + Unpin + Send + AsyncRead + AsyncWrite;
+
+    async fn tls_accept<IO>(
+        &self,
+        io: IO,
+        _conn_meta: &mut ConnectionMetadata<()>,
+    ) -> io::Result<(IO, TlsInfo)>
+    where
+        IO: 'static + Unpin + Send + AsyncRead + AsyncWrite,
+    {
+        self.tls.accept(io).await
+    }
+
+    async fn new_mail(&self, _conn_meta: &mut ConnectionMetadata<()>) {}
+
+    async fn filter_from(
+        &self,
+        from: Option<Email>,
+        _meta: &mut MailMetadata<()>,
+        _conn_meta: &mut ConnectionMetadata<()>,
+    ) -> Decision<Option<Email>> {
+        Decision::Accept {
+            reply: reply::okay_from().convert(),
+            res: from,
+        }
+    }
+
+    async fn filter_to(
+        &self,
+        to: Email,
+        _meta: &mut MailMetadata<()>,
+        _conn_meta: &mut ConnectionMetadata<()>,
+    ) -> Decision<Email> {
+        Decision::Accept {
+            reply: reply::okay_to().convert(),
+            res: to,
+        }
+    }
+
+    async fn handle_mail<'resp, R>(
+        &'resp self,
+        reader: &mut EscapedDataReader<'_, R>,
+        meta: MailMetadata<()>,
+        conn_meta: &'resp mut ConnectionMetadata<()>,
+    ) -> Decision<()>
+    where
+        R: Send + Unpin + AsyncRead,
+    {
+        match self.mails.record(reader, &meta, conn_meta).await {
+            Ok(()) => Decision::Accept {
+                reply: reply::okay_mail().convert(),
+                res: (),
+            },
+            Err(_) => Decision::Reject {
+                reply: reply::internal_server_error().convert(),
+            },
+        }
+    }
+}
@@ -0,0 +1,353 @@
+use std::{fmt, sync::Arc, time::Duration};
+
+use smtp_server::{
+    transcript::{Entry, Event},
+    Config, TlsInfo,
+};
+
+use crate::{Client, Failure, Reply, Session, Step};
+
+/// Sent in place of redacted AUTH credentials, `redacted` in base64
+pub const REDACTED_PLACEHOLDER: &[u8] = b"cmVkYWN0ZWQ=";
+
+/// Line repeated in place of the truncated part of DATA bodies, so that the
+/// replayed mail keeps its size
+const TRUNCATED_FILLER: &[u8] =
+    b"xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx\r\n";
+
+/// How the replies received during a replay are compared to the recorded ones
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Compare {
+    /// Code, enhanced code and text must all be the same
+    Exact,
+
+    /// Only the codes must be the same, for configurations whose texts vary
+    /// from one session to the other
+    Code,
+}
+
+/// Client side of a recorded session, to be played against any `Config`
+///
+/// The client sends what the recorded client sent, waiting for each recorded
+/// reply before going on, and stops at the first reply that differs from the
+/// recorded one. Replies the server sends after the last recorded one are not
+/// checked.
+///
+/// As recorded transcripts have no TLS handshake, STARTTLS is replayed with
+/// the [`MockTls`](crate::MockTls) handshake, which the `Config` under test
+/// must thus use.
+#[derive(Clone, Debug)]
+pub struct Replay {
+    client: Client,
+    /// Index in the transcript of the event each step comes from
+    events: Vec<usize>,
+}
+
+/// The transcript has a sent event that is not a single reply
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct InvalidTranscript {
+    pub event: usize,
+}
+
+impl fmt::Display for InvalidTranscript {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "event {} of the transcript is not a valid reply",
+            self.event
+        )
+    }
+}
+
+impl std::error::Error for InvalidTranscript {}
+
+impl Replay {
+    pub fn new(transcript: &[Entry], compare: Compare) -> Result<Replay, InvalidTranscript> {
+        let mut client = Client::new();
+        let mut events = Vec::new();
+        let mut last_sent: &[u8] = b"";
+        for (i, entry) in transcript.iter().enumerate() {
+            let step = match &entry.event {
+                Event::Received(bytes) => {
+                    last_sent = bytes;
+                    Step::Send(bytes.to_vec())
+                }
+                Event::Redacted => Step::Send(REDACTED_PLACEHOLDER.to_vec()),
+                Event::Truncated(len) => Step::Send(filler(*len)),
+                Event::Sent(bytes) => {
+                    let reply = match smtp_server::Reply::<&str>::parse(bytes) {
+                        Ok((&[], reply)) => reply.into_owned(),
+                        _ => return Err(InvalidTranscript { event: i }),
+                    };
+                    let is_starttls = reply.code.code() == 220
+                        && last_sent.to_ascii_uppercase().ends_with(b"STARTTLS\r\n");
+                    client = client.step(match compare {
+                        Compare::Exact => Step::ExpectReply(reply),
+                        Compare::Code => Step::ExpectCode(reply.code.code()),
+                    });
+                    events.push(i);
+                    if !is_starttls {
+                        continue;
+                    }
+                    Step::TlsHandshake
+                }
+            };
+            client = client.step(step);
+            events.push(i);
+        }
+        Ok(Replay { client, events })
+    }
+
+    pub fn implicit_tls(mut self, info: TlsInfo) -> Replay {
+        self.client = self.client.implicit_tls(info);
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Replay {
+        self.client = self.client.timeout(timeout);
+        self
+    }
+
+    /// Replay the session against `interact`, returning the first divergence
+    /// from the recorded session, if any
+    pub async fn run<Cfg>(
+        self,
+        cfg: Arc<Cfg>,
+        metadata: Cfg::ConnectionUserMeta,
+    ) -> Result<Session, Divergence>
+    where
+        Cfg: Config,
+    {
+        let events = self.events;
+        self.client
+            .run(cfg, metadata)
+            .await
+            .map_err(|e| Divergence {
+                event: events[e.step],
+                failure: e.failure,
+                replies: e.replies,
+            })
+    }
+}
+
+fn filler(len: usize) -> Vec<u8> {
+    TRUNCATED_FILLER.iter().copied().cycle().take(len).collect()
+}
+
+/// First difference between a replayed session and the recorded one
+#[derive(Debug)]
+pub struct Divergence {
+    /// Index in the transcript of the event at which the replay diverged
+    pub event: usize,
+    pub failure: Failure,
+    /// The replies received before the divergence
+    pub replies: Vec<Reply>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "diverged at event {}: {}", self.event, self.failure)
+    }
+}
+
+impl std::error::Error for Divergence {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use smtp_message::{Email, EscapedDataReader};
+    use smtp_server::{
+        reply, transcript::MemorySink, ConnectionMetadata, Decision, MailMetadata, TlsInfo,
+        TranscriptSink,
+    };
+
+    use futures::{AsyncRead, AsyncWrite};
+
+    use crate::MockConfig;
+
+    /// Mock configuration, recording its sessions and rejecting the
+    /// recipients in `banned`
+    struct Policy {
+        mock: MockConfig,
+        banned: &'static str,
+        sink: Option<MemorySink>,
+    }
+
+    impl Config for Policy {
+        type ConnectionUserMeta = ();
+        type MailUserMeta = ();
+        type Protocol = smtp_server::protocol::Smtp;
+
+        fn hostname(&self, _conn_meta: &ConnectionMetadata<()>) -> &str {
+            "test.example.org"
+        }
+
+        fn transcript_sink(
+            &self,
+            _conn_meta: &ConnectionMetadata<()>,
+        ) -> Option<Box<dyn TranscriptSink>> {
+            self.sink
+                .clone()
+                .map(|s| Box::new(s.with_max_data_len(8)) as Box<dyn TranscriptSink>)
+        }
+
+        type TlsStream<IO>
+            = IO
+        where
+            IO: 'static + Unpin + Send + AsyncRead + AsyncWrite;
+
+        async fn tls_accept<IO>(
+            &self,
+            io: IO,
+            _conn_meta: &mut ConnectionMetadata<()>,
+        ) -> std::io::Result<(IO, TlsInfo)>
+        where
+            IO: 'static + Unpin + Send + AsyncRead + AsyncWrite,
+        {
+            self.mock.tls.accept(io).await
+        }
+
+        async fn new_mail(&self, _conn_meta: &mut ConnectionMetadata<()>) {}
+
+        async fn filter_from(
+            &self,
+            from: Option<Email>,
+            _meta: &mut MailMetadata<()>,
+            _conn_meta: &mut ConnectionMetadata<()>,
+        ) -> Decision<Option<Email>> {
+            Decision::Accept {
+                reply: reply::okay_from().convert(),
+                res: from,
+            }
+        }
+
+        async fn filter_to(
+            &self,
+            to: Email,
+            _meta: &mut MailMetadata<()>,
+            _conn_meta: &mut ConnectionMetadata<()>,
+        ) -> Decision<Email> {
+            if to.localpart.raw() == self.banned {
+                Decision::Reject {
+                    reply: reply::bad_sequence().convert(),
+                }
+            } else {
+                Decision::Accept {
+                    reply: reply::okay_to().convert(),
+                    res: to,
+                }
+            }
+        }
+
+        async fn handle_mail<'resp, R>(
+            &'resp self,
+            reader: &mut EscapedDataReader<'_, R>,
+            meta: MailMetadata<()>,
+            conn_meta: &'resp mut ConnectionMetadata<()>,
+        ) -> Decision<()>
+        where
+            R: Send + Unpin + AsyncRead,
+        {
+            self.mock
+                .mails
+                .record(reader, &meta, conn_meta)
+                .await
+                .unwrap();
+            Decision::Accept {
+                reply: reply::okay_mail().convert(),
+                res: (),
+            }
+        }
+    }
+
+    fn record() -> Vec<Entry> {
+        let sink = MemorySink::new();
+        let cfg = Arc::new(Policy {
+            mock: MockConfig::default(),
+            banned: "eve",
+            sink: Some(sink.clone()),
+        });
+        smol::block_on(
+            Client::new()
+                .expect(220)
+                .send("EHLO client.example.org")
+                .expect(250)
+                .starttls()
+                .send("EHLO client.example.org")
+                .expect(250)
+                .send("AUTH PLAIN c2VjcmV0")
+                .expect(500)
+                .send("MAIL FROM:<alice@example.org>")
+                .expect(250)
+                .send("RCPT TO:<bob@example.org>\r\nRCPT TO:<eve@example.org>")
+                .expect(250)
+                .expect(503)
+                .send("DATA")
+                .expect(354)
+                .send("Subject: a rather long mail\r\n\r\nHello\r\n.")
+                .expect(250)
+                .send("QUIT")
+                .expect(221)
+                .run(cfg.clone(), ()),
+        )
+        .unwrap()
+        .result
+        .unwrap();
+        let mails = cfg.mock.mails.take();
+        assert_eq!(mails.len(), 1);
+        assert_eq!(mails[0].raw.len(), 41);
+        sink.entries()
+    }
+
+    #[test]
+    fn replays_recorded_session() {
+        let transcript = record();
+        assert!(transcript.iter().any(|e| e.event == Event::Redacted));
+        assert!(transcript.iter().any(|e| e.event == Event::Truncated(28)));
+
+        let cfg = Arc::new(Policy {
+            mock: MockConfig::default(),
+            banned: "eve",
+            sink: None,
+        });
+        let session = smol::block_on(
+            Replay::new(&transcript, Compare::Exact)
+                .unwrap()
+                .run(cfg.clone(), ()),
+        )
+        .unwrap();
+        session.result.unwrap();
+        assert_eq!(session.replies.len(), 11);
+        // The truncated body is replaced with as many bytes
+        let mails = cfg.mock.mails.take();
+        assert_eq!(mails[0].raw.len(), 41);
+    }
+
+    #[test]
+    fn reports_first_divergence() {
+        let transcript = record();
+        let cfg = Arc::new(Policy {
+            mock: MockConfig::default(),
+            banned: "bob",
+            sink: None,
+        });
+        let divergence = smol::block_on(
+            Replay::new(&transcript, Compare::Code)
+                .unwrap()
+                .run(cfg, ()),
+        )
+        .unwrap_err();
+        assert_eq!(
+            transcript[divergence.event].event,
+            Event::Sent(b"250 2.1.5 Okay\r\n"[..].into())
+        );
+        match divergence.failure {
+            Failure::UnexpectedCode { expected, got } => {
+                assert_eq!(expected, 250);
+                assert_eq!(got.code.code(), 503);
+            }
+            f => panic!("unexpected failure {:?}", f),
+        }
+    }
+}
@@ -0,0 +1,121 @@
+use std::io;
+
+/// Serializable version of an `io::Error`, that keeps its kind and message
+///
+/// The kind is stored under a fixed name, that does not depend on how
+/// `io::ErrorKind` is printed. Kinds without such a name are serialized as
+/// `Other`, and names that are not known to this version of the crate are
+/// deserialized as `io::ErrorKind::Other`.
+#[derive(Clone, Debug, Eq, PartialEq, serde::Serialize)]
+pub struct SerializableIoError {
+    pub kind: String,
+    pub message: String,
+}
+
+// Also accept a bare message, as sent before the kind was kept
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum AnyIoError {
+    Full { kind: String, message: String },
+    Message(String),
+}
+
+impl<'de> serde::Deserialize<'de> for SerializableIoError {
+    fn deserialize<D>(deserializer: D) -> Result<SerializableIoError, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        Ok(match AnyIoError::deserialize(deserializer)? {
+            AnyIoError::Full { kind, message } => SerializableIoError { kind, message },
+            AnyIoError::Message(message) => SerializableIoError {
+                kind: kind_name(io::ErrorKind::Other).to_owned(),
+                message,
+            },
+        })
+    }
+}
+
+// The names are spelled out rather than derived from the variants, so that
+// they stay the same whatever happens to `io::ErrorKind`
+macro_rules! error_kinds {
+    ($($kind:ident => $name:literal,)*) => {
+        fn kind_name(kind: io::ErrorKind) -> &'static str {
+            match kind {
+                $(io::ErrorKind::$kind => $name,)*
+                _ => "Other",
+            }
+        }
+
+        fn kind_from_name(name: &str) -> io::ErrorKind {
+            match name {
+                $($name => io::ErrorKind::$kind,)*
+                _ => io::ErrorKind::Other,
+            }
+        }
+    };
+}
+
+error_kinds!(
+    NotFound => "NotFound",
+    PermissionDenied => "PermissionDenied",
+    ConnectionRefused => "ConnectionRefused",
+    ConnectionReset => "ConnectionReset",
+    ConnectionAborted => "ConnectionAborted",
+    NotConnected => "NotConnected",
+    AddrInUse => "AddrInUse",
+    AddrNotAvailable => "AddrNotAvailable",
+    BrokenPipe => "BrokenPipe",
+    AlreadyExists => "AlreadyExists",
+    WouldBlock => "WouldBlock",
+    InvalidInput => "InvalidInput",
+    InvalidData => "InvalidData",
+    TimedOut => "TimedOut",
+    WriteZero => "WriteZero",
+    Interrupted => "Interrupted",
+    Unsupported => "Unsupported",
+    UnexpectedEof => "UnexpectedEof",
+    OutOfMemory => "OutOfMemory",
+    Other => "Other",
+);
+
+impl From<&io::Error> for SerializableIoError {
+    fn from(e: &io::Error) -> SerializableIoError {
+        SerializableIoError {
+            kind: kind_name(e.kind()).to_owned(),
+            message: e.to_string(),
+        }
+    }
+}
+
+impl From<SerializableIoError> for io::Error {
+    fn from(e: SerializableIoError) -> io::Error {
+        io::Error::new(kind_from_name(&e.kind), e.message)
+    }
+}
+
+/// For use with `#[serde(with = "...")]` on an `io::Result<()>`
+pub(crate) mod io_result {
+    use std::io;
+
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use super::SerializableIoError;
+
+    pub fn serialize<S>(res: &io::Result<()>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let res: Result<(), SerializableIoError> = match res {
+            Ok(()) => Ok(()),
+            Err(e) => Err(e.into()),
+        };
+        res.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<io::Result<()>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Ok(Result::<(), SerializableIoError>::deserialize(deserializer)?.map_err(io::Error::from))
+    }
+}
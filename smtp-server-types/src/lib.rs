@@ -1,12 +1,27 @@
-use std::io;
+use std::{borrow::Cow, fmt, io};
 
-use smtp_message::{Email, Hostname, Reply};
+use smtp_message::{Email, EnhancedReplyCodeClass, Hostname, ReplyCode};
 
+mod io_error;
 pub mod reply;
 
-// TODO: add sanity checks that Accept is a 2xx reply, and Reject/Kill are not
+pub use io_error::SerializableIoError;
+
+/// Reply sent by the server
+///
+/// Its strings can be borrowed from `'static` constants, so that the replies
+/// of the [`reply`] module cost nothing to build, `.convert()` included.
+pub type Reply<S = Cow<'static, str>> = smtp_message::Reply<S>;
+
+/// Outcome of a `Config` hook
+///
+/// The variants can be built directly, but the [`accept`](Decision::accept),
+/// [`reject`](Decision::reject) and [`kill`](Decision::kill) constructors
+/// check that the reply matches the decision.
+///
+/// The error of a `Kill` is serialized as a [`SerializableIoError`].
 #[must_use]
-#[derive(Debug)]
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
 pub enum Decision<T> {
     Accept {
         reply: Reply,
@@ -17,41 +32,125 @@ pub enum Decision<T> {
     },
     Kill {
         reply: Option<Reply>,
+        #[serde(with = "io_error::io_result")]
         res: io::Result<()>,
     },
 }
 
-// TODO: add sanity checks that Accept is a 2xx reply, and Reject/Kill are not
-// TODO: merge with Decision (blocked on https://github.com/serde-rs/serde/issues/1940)
-#[must_use]
-#[derive(Debug, serde::Deserialize, serde::Serialize)]
-pub enum SerializableDecision<T> {
-    Accept {
-        reply: Reply,
-        res: T,
-    },
-    Reject {
-        reply: Reply,
-    },
-    Kill {
-        reply: Option<Reply>,
-        res: Result<(), String>,
+impl<T> Decision<T> {
+    /// Accept with `reply`, which must be positive (2xx or 3xx)
+    pub fn accept(reply: Reply, res: T) -> Result<Decision<T>, DecisionError> {
+        check_reply(&reply, true)?;
+        Ok(Decision::Accept { reply, res })
+    }
+
+    /// Reject with `reply`, which must be negative (4xx or 5xx)
+    pub fn reject(reply: Reply) -> Result<Decision<T>, DecisionError> {
+        check_reply(&reply, false)?;
+        Ok(Decision::Reject { reply })
+    }
+
+    /// Close the connection after sending `reply`, if any. The reply must be
+    /// negative, unless `res` is `Ok`: closing after a 221 reply to QUIT is
+    /// also a `Kill`.
+    pub fn kill(reply: Option<Reply>, res: io::Result<()>) -> Result<Decision<T>, DecisionError> {
+        if let Some(reply) = &reply {
+            check_kill_reply(reply, res.is_ok())?;
+        }
+        Ok(Decision::Kill { reply, res })
+    }
+
+    /// Run the checks of the constructors on an already built decision
+    pub fn validate(&self) -> Result<(), DecisionError> {
+        match self {
+            Decision::Accept { reply, .. } => check_reply(reply, true),
+            Decision::Reject { reply } => check_reply(reply, false),
+            Decision::Kill {
+                reply: Some(reply),
+                res,
+            } => check_kill_reply(reply, res.is_ok()),
+            Decision::Kill { reply: None, .. } => Ok(()),
+        }
+    }
+}
+
+/// Reason why a reply cannot be used for a [`Decision`]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum DecisionError {
+    /// The decision needs a positive reply (2xx or 3xx)
+    NotPositive(ReplyCode),
+
+    /// The decision needs a negative reply (4xx or 5xx)
+    NotNegative(ReplyCode),
+
+    /// The class of the enhanced status code does not match the reply code
+    EnhancedClassMismatch {
+        code: ReplyCode,
+        class: EnhancedReplyCodeClass,
     },
 }
 
-impl<T> From<SerializableDecision<T>> for Decision<T> {
-    fn from(d: SerializableDecision<T>) -> Decision<T> {
-        match d {
-            SerializableDecision::Accept { reply, res } => Decision::Accept { reply, res },
-            SerializableDecision::Reject { reply } => Decision::Reject { reply },
-            SerializableDecision::Kill { reply, res } => Decision::Kill {
-                reply,
-                res: res.map_err(|msg| io::Error::new(io::ErrorKind::Other, msg)),
-            },
+impl fmt::Display for DecisionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecisionError::NotPositive(code) => {
+                write!(f, "expected a positive reply, got {}", code.code())
+            }
+            DecisionError::NotNegative(code) => {
+                write!(f, "expected a negative reply, got {}", code.code())
+            }
+            DecisionError::EnhancedClassMismatch { code, class } => write!(
+                f,
+                "enhanced status code of class {} does not match reply code {}",
+                *class as u8,
+                code.code()
+            ),
         }
     }
 }
 
+impl std::error::Error for DecisionError {}
+
+fn check_reply(reply: &Reply, positive: bool) -> Result<(), DecisionError> {
+    let (is_positive, class) = match reply.code.0[0] {
+        b'2' | b'3' => (true, EnhancedReplyCodeClass::Success),
+        b'4' => (false, EnhancedReplyCodeClass::PersistentTransient),
+        b'5' => (false, EnhancedReplyCodeClass::PermanentFailure),
+        // Not a valid reply code at all, so neither positive nor negative
+        _ if positive => return Err(DecisionError::NotPositive(reply.code)),
+        _ => return Err(DecisionError::NotNegative(reply.code)),
+    };
+    if is_positive != positive {
+        return Err(if positive {
+            DecisionError::NotPositive(reply.code)
+        } else {
+            DecisionError::NotNegative(reply.code)
+        });
+    }
+    match &reply.ecode {
+        Some(ecode) if ecode.class != class => Err(DecisionError::EnhancedClassMismatch {
+            code: reply.code,
+            class: ecode.class,
+        }),
+        _ => Ok(()),
+    }
+}
+
+fn check_kill_reply(reply: &Reply, is_ok: bool) -> Result<(), DecisionError> {
+    if is_ok && reply.code.0[0] == b'2' {
+        check_reply(reply, true)
+    } else {
+        check_reply(reply, false)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+pub enum ProtocolName {
+    #[default]
+    Smtp,
+    Lmtp,
+}
+
 #[derive(Debug, serde::Deserialize, serde::Serialize)]
 pub struct MailMetadata<U> {
     pub user: U,
@@ -67,9 +166,142 @@ pub struct HelloInfo {
     pub hostname: Hostname,
 }
 
+/// Number of errors the client made during the session so far
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+pub struct ErrorCounters {
+    /// Syntax errors, unrecognized commands, too long lines and commands
+    /// sent out of sequence
+    pub protocol_errors: u32,
+
+    /// RCPT TO that were rejected by `filter_to`
+    pub rejected_recipients: u32,
+}
+
+impl ErrorCounters {
+    pub fn total(&self) -> u32 {
+        self.protocol_errors
+            .saturating_add(self.rejected_recipients)
+    }
+}
+
+/// Parameters negotiated during the TLS handshake
+#[derive(Clone, Debug, Default, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct TlsInfo {
+    /// Name of the protocol version, e.g. `TLSv1_3`
+    pub protocol_version: Option<String>,
+
+    /// Name of the cipher suite, e.g. `TLS13_AES_256_GCM_SHA384`
+    pub cipher_suite: Option<String>,
+
+    /// Server name the client asked for through SNI
+    pub sni: Option<String>,
+
+    /// DER-encoded certificate chain presented by the client, starting with
+    /// its own certificate, or empty if it did not present any
+    pub peer_certificates: Vec<Vec<u8>>,
+
+    /// Whether `peer_certificates` was verified against trusted roots during
+    /// the handshake, without which it is only the client's claim
+    #[serde(default)]
+    pub verified: bool,
+}
+
 #[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
 pub struct ConnectionMetadata<U> {
     pub user: U,
     pub hello: Option<HelloInfo>,
+    /// Protocol spoken in this session
+    #[serde(default)]
+    pub protocol: ProtocolName,
     pub is_encrypted: bool,
+    /// Set from `IsAlreadyTls::Yes` for implicit TLS, or after a successful
+    /// STARTTLS
+    #[serde(default)]
+    pub tls: Option<TlsInfo>,
+    /// Identity the client authenticated as, for instance by presenting a
+    /// client certificate
+    #[serde(default)]
+    pub identity: Option<String>,
+    #[serde(default)]
+    pub errors: ErrorCounters,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decision_constructors() {
+        assert!(Decision::accept(reply::okay_from().convert(), ()).is_ok());
+        assert!(Decision::<()>::accept(reply::okay_data().convert(), ()).is_ok());
+        assert_eq!(
+            Decision::accept(reply::bad_sequence().convert(), ()).unwrap_err(),
+            DecisionError::NotPositive(ReplyCode::BAD_SEQUENCE)
+        );
+        assert!(Decision::<()>::reject(reply::bad_sequence().convert()).is_ok());
+        assert_eq!(
+            Decision::<()>::reject(reply::okay_from().convert()).unwrap_err(),
+            DecisionError::NotNegative(ReplyCode::OKAY)
+        );
+        let mut mismatched = reply::bad_sequence();
+        mismatched.code = ReplyCode::LOCAL_ERROR;
+        assert_eq!(
+            Decision::<()>::reject(mismatched.convert()).unwrap_err(),
+            DecisionError::EnhancedClassMismatch {
+                code: ReplyCode::LOCAL_ERROR,
+                class: EnhancedReplyCodeClass::PermanentFailure,
+            }
+        );
+        assert!(Decision::<()>::kill(Some(reply::okay_quit().convert()), Ok(())).is_ok());
+        assert!(Decision::<()>::kill(
+            Some(reply::okay_quit().convert()),
+            Err(io::Error::other("oops"))
+        )
+        .is_err());
+        assert!(Decision::<()>::kill(None, Ok(())).is_ok());
+    }
+
+    #[test]
+    fn decision_serde_roundtrip() {
+        let kill: Decision<()> = Decision::Kill {
+            reply: Some(reply::internal_server_error().convert()),
+            res: Err(io::Error::new(io::ErrorKind::TimedOut, "backend timed out")),
+        };
+        let json = serde_json::to_string(&kill).unwrap();
+        assert!(json.contains(r#""kind":"TimedOut""#));
+        match serde_json::from_str::<Decision<()>>(&json).unwrap() {
+            Decision::Kill {
+                reply: Some(reply),
+                res: Err(e),
+            } => {
+                assert_eq!(reply, reply::internal_server_error().convert());
+                assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+                assert_eq!(e.to_string(), "backend timed out");
+            }
+            d => panic!("unexpected decision {:?}", d),
+        }
+
+        // Errors sent as a bare message are still understood
+        let json = r#"{"Kill":{"reply":null,"res":{"Err":"policy engine crashed"}}}"#;
+        match serde_json::from_str::<Decision<()>>(json).unwrap() {
+            Decision::Kill {
+                reply: None,
+                res: Err(e),
+            } => {
+                assert_eq!(e.kind(), io::ErrorKind::Other);
+                assert_eq!(e.to_string(), "policy engine crashed");
+            }
+            d => panic!("unexpected decision {:?}", d),
+        }
+
+        let accept: Decision<u32> = Decision::Accept {
+            reply: reply::okay_from().convert(),
+            res: 42,
+        };
+        let json = serde_json::to_string(&accept).unwrap();
+        assert!(matches!(
+            serde_json::from_str(&json).unwrap(),
+            Decision::Accept { res: 42, .. }
+        ));
+    }
 }
@@ -1,11 +1,18 @@
-use smtp_message::{EnhancedReplyCode, MaybeUtf8, Reply, ReplyCode};
+use std::borrow::Cow;
+
+use smtp_message::{EnhancedReplyCode, MaybeUtf8, ReplyCode, ReplyLines};
+
+use crate::Reply;
 
 #[inline]
 pub fn welcome_banner(hostname: &str, banner: &str) -> Reply {
     Reply {
         code: ReplyCode::SERVICE_READY,
         ecode: None,
-        text: vec![MaybeUtf8::Utf8(String::from(hostname) + " " + banner)],
+        text: vec![MaybeUtf8::Utf8(Cow::Owned(
+            String::from(hostname) + " " + banner,
+        ))]
+        .into(),
     }
 }
 
@@ -22,7 +29,7 @@ pub fn okay_hello(
         built_banner += " ";
         built_banner += banner;
     }
-    let mut text = vec![MaybeUtf8::Utf8(built_banner)];
+    let mut text = vec![MaybeUtf8::Utf8(Cow::Owned(built_banner))];
     if is_extended {
         text.push(MaybeUtf8::Ascii("8BITMIME".into()));
         text.push(MaybeUtf8::Ascii("ENHANCEDSTATUSCODES".into()));
@@ -35,7 +42,7 @@ pub fn okay_hello(
     Reply {
         code: ReplyCode::OKAY,
         ecode: None,
-        text,
+        text: text.into(),
     }
 }
 
@@ -44,7 +51,7 @@ pub fn okay(ecode: EnhancedReplyCode<&'static str>) -> Reply<&'static str> {
     Reply {
         code: ReplyCode::OKAY,
         ecode: Some(ecode),
-        text: vec![MaybeUtf8::Ascii("Okay")],
+        text: ReplyLines::Static(&[MaybeUtf8::Ascii("Okay")]),
     }
 }
 
@@ -66,7 +73,7 @@ pub fn okay_data() -> Reply<&'static str> {
     Reply {
         code: ReplyCode::START_MAIL_INPUT,
         ecode: None,
-        text: vec![MaybeUtf8::Ascii("Start mail input; end with <CRLF>.<CRLF>")],
+        text: ReplyLines::Static(&[MaybeUtf8::Ascii("Start mail input; end with <CRLF>.<CRLF>")]),
     }
 }
 
@@ -82,7 +89,7 @@ pub fn okay_starttls() -> Reply<&'static str> {
     Reply {
         code: ReplyCode::SERVICE_READY,
         ecode: Some(EnhancedReplyCode::SUCCESS_UNDEFINED),
-        text: vec![MaybeUtf8::Ascii("Ready to start TLS")],
+        text: ReplyLines::Static(&[MaybeUtf8::Ascii("Ready to start TLS")]),
     }
 }
 
@@ -98,9 +105,9 @@ pub fn ignore_vrfy() -> Reply<&'static str> {
     Reply {
         code: ReplyCode::CANNOT_VRFY_BUT_PLEASE_TRY,
         ecode: Some(EnhancedReplyCode::SUCCESS_DEST_VALID),
-        text: vec![MaybeUtf8::Ascii(
+        text: ReplyLines::Static(&[MaybeUtf8::Ascii(
             "Cannot VRFY user, but will accept message and attempt delivery",
-        )],
+        )]),
     }
 }
 
@@ -111,7 +118,7 @@ pub fn ignore_help() -> Reply<&'static str> {
     Reply {
         code: ReplyCode::HELP_MESSAGE,
         ecode: Some(EnhancedReplyCode::SUCCESS_UNDEFINED),
-        text: vec![MaybeUtf8::Ascii("See https://tools.ietf.org/html/rfc5321")],
+        text: ReplyLines::Static(&[MaybeUtf8::Ascii("See https://tools.ietf.org/html/rfc5321")]),
     }
 }
 
@@ -127,7 +134,7 @@ pub fn okay_quit() -> Reply<&'static str> {
     Reply {
         code: ReplyCode::CLOSING_CHANNEL,
         ecode: Some(EnhancedReplyCode::SUCCESS_UNDEFINED),
-        text: vec![MaybeUtf8::Utf8("Bye")],
+        text: ReplyLines::Static(&[MaybeUtf8::Utf8("Bye")]),
     }
 }
 
@@ -139,7 +146,7 @@ pub fn bad_sequence() -> Reply<&'static str> {
     Reply {
         code: ReplyCode::BAD_SEQUENCE,
         ecode: Some(EnhancedReplyCode::PERMANENT_INVALID_COMMAND),
-        text: vec![MaybeUtf8::Ascii("Bad sequence of commands")],
+        text: ReplyLines::Static(&[MaybeUtf8::Ascii("Bad sequence of commands")]),
     }
 }
 
@@ -148,7 +155,7 @@ pub fn command_unimplemented() -> Reply<&'static str> {
     Reply {
         code: ReplyCode::COMMAND_UNIMPLEMENTED,
         ecode: Some(EnhancedReplyCode::PERMANENT_INVALID_COMMAND),
-        text: vec![MaybeUtf8::Ascii("Command not implemented")],
+        text: ReplyLines::Static(&[MaybeUtf8::Ascii("Command not implemented")]),
     }
 }
 
@@ -157,7 +164,7 @@ pub fn command_unrecognized() -> Reply<&'static str> {
     Reply {
         code: ReplyCode::COMMAND_UNRECOGNIZED,
         ecode: Some(EnhancedReplyCode::PERMANENT_INVALID_COMMAND),
-        text: vec![MaybeUtf8::Ascii("Command not recognized")],
+        text: ReplyLines::Static(&[MaybeUtf8::Ascii("Command not recognized")]),
     }
 }
 
@@ -166,7 +173,7 @@ pub fn command_not_supported() -> Reply<&'static str> {
     Reply {
         code: ReplyCode::COMMAND_UNIMPLEMENTED,
         ecode: Some(EnhancedReplyCode::PERMANENT_INVALID_COMMAND),
-        text: vec![MaybeUtf8::Ascii("Command not supported")],
+        text: ReplyLines::Static(&[MaybeUtf8::Ascii("Command not supported")]),
     }
 }
 
@@ -175,7 +182,7 @@ pub fn pipeline_forbidden_after_starttls() -> Reply<&'static str> {
     Reply {
         code: ReplyCode::BAD_SEQUENCE,
         ecode: Some(EnhancedReplyCode::PERMANENT_INVALID_COMMAND),
-        text: vec![MaybeUtf8::Ascii("Pipelining after starttls is forbidden")],
+        text: ReplyLines::Static(&[MaybeUtf8::Ascii("Pipelining after starttls is forbidden")]),
     }
 }
 
@@ -184,7 +191,7 @@ pub fn line_too_long() -> Reply<&'static str> {
     Reply {
         code: ReplyCode::COMMAND_UNRECOGNIZED,
         ecode: Some(EnhancedReplyCode::PERMANENT_UNDEFINED),
-        text: vec![MaybeUtf8::Ascii("Line too long")],
+        text: ReplyLines::Static(&[MaybeUtf8::Ascii("Line too long")]),
     }
 }
 
@@ -193,7 +200,7 @@ pub fn internal_server_error() -> Reply<&'static str> {
     Reply {
         code: ReplyCode::LOCAL_ERROR,
         ecode: Some(EnhancedReplyCode::TRANSIENT_UNDEFINED),
-        text: vec![MaybeUtf8::Ascii("Internal server error")],
+        text: ReplyLines::Static(&[MaybeUtf8::Ascii("Internal server error")]),
     }
 }
 
@@ -202,6 +209,62 @@ pub fn handle_mail_did_not_call_complete() -> Reply<&'static str> {
     Reply {
         code: ReplyCode::LOCAL_ERROR,
         ecode: Some(EnhancedReplyCode::TRANSIENT_SYSTEM_INCORRECTLY_CONFIGURED),
-        text: vec![MaybeUtf8::Ascii("System incorrectly configured")],
+        text: ReplyLines::Static(&[MaybeUtf8::Ascii("System incorrectly configured")]),
+    }
+}
+
+#[inline]
+pub fn missing_decision() -> Reply<&'static str> {
+    Reply {
+        code: ReplyCode::LOCAL_ERROR,
+        ecode: Some(EnhancedReplyCode::TRANSIENT_SYSTEM_INCORRECTLY_CONFIGURED),
+        text: ReplyLines::Static(&[MaybeUtf8::Ascii("System incorrectly configured")]),
+    }
+}
+
+#[inline]
+pub fn too_many_connections() -> Reply<&'static str> {
+    Reply {
+        code: ReplyCode::SERVICE_NOT_AVAILABLE,
+        ecode: Some(EnhancedReplyCode::TRANSIENT_POLICY_OTHER),
+        text: ReplyLines::Static(&[MaybeUtf8::Ascii("Too many connections")]),
+    }
+}
+
+#[inline]
+pub fn service_shutting_down() -> Reply<&'static str> {
+    Reply {
+        code: ReplyCode::SERVICE_NOT_AVAILABLE,
+        ecode: Some(EnhancedReplyCode::TRANSIENT_SYSTEM_NOT_ACCEPTING_MESSAGES),
+        text: ReplyLines::Static(&[MaybeUtf8::Ascii("Service shutting down")]),
+    }
+}
+
+#[inline]
+pub fn early_talker() -> Reply<&'static str> {
+    Reply {
+        code: ReplyCode::TRANSACTION_FAILED,
+        ecode: Some(EnhancedReplyCode::PERMANENT_INVALID_COMMAND),
+        text: ReplyLines::Static(&[MaybeUtf8::Ascii(
+            "Protocol error: talked before the greeting",
+        )]),
+    }
+}
+
+#[inline]
+pub fn too_many_errors() -> Reply<&'static str> {
+    Reply {
+        code: ReplyCode::SERVICE_NOT_AVAILABLE,
+        ecode: Some(EnhancedReplyCode::TRANSIENT_POLICY_OTHER),
+        text: ReplyLines::Static(&[MaybeUtf8::Ascii("Too many errors, closing connection")]),
+    }
+}
+
+#[inline]
+pub fn no_smtp_service() -> Reply<&'static str> {
+    Reply {
+        code: ReplyCode::TRANSACTION_FAILED,
+        ecode: Some(EnhancedReplyCode::PERMANENT_SYSTEM_NOT_ACCEPTING_MESSAGES),
+        text: ReplyLines::Static(&[MaybeUtf8::Ascii("No SMTP service here")]),
     }
 }
@@ -0,0 +1,77 @@
+//! Checks that the constant replies are sent without allocating, in their own
+//! test binary so that the counting allocator does not apply to other tests
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    borrow::Cow,
+    cell::Cell,
+};
+
+use smtp_message::{EnhancedReplyCode, ReplyLines};
+use smtp_server_types::{reply, Reply};
+
+/// Counts the allocations of each thread, as tests run in parallel
+struct CountingAlloc;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|a| a.set(a.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|a| a.set(a.get() + 1));
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+/// Runs `f`, returning its result along with the number of allocations
+/// it made
+fn count_allocations<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    let before = ALLOCATIONS.with(Cell::get);
+    let res = f();
+    (res, ALLOCATIONS.with(Cell::get) - before)
+}
+
+#[test]
+fn constant_replies_do_not_allocate() {
+    let (okay, allocations): (Reply, _) = count_allocations(|| reply::okay_to().convert());
+    assert_eq!(allocations, 0);
+    let mut buf = Vec::with_capacity(64);
+    let ((), allocations) = count_allocations(|| okay.write_into(&mut buf));
+    assert_eq!(allocations, 0);
+    assert_eq!(buf, b"250 2.1.5 Okay\r\n");
+    assert!(matches!(okay.text, ReplyLines::Static(_)));
+    assert!(matches!(
+        okay.ecode,
+        Some(EnhancedReplyCode {
+            raw: Cow::Borrowed(_),
+            ..
+        })
+    ));
+    assert_eq!(okay.to_string(), "250 2.1.5 Okay\r\n");
+
+    let parsed = Reply::<String>::parse(b"250 2.1.5 Okay\r\n").unwrap().1;
+    assert!(matches!(parsed.text, ReplyLines::Owned(_)));
+    assert_eq!(okay.text, parsed.text);
+
+    let mut extended = okay;
+    // Copying the lines out to modify them does allocate
+    let ((), allocations) = count_allocations(|| extended.text.to_mut().push("Welcome".into()));
+    assert!(allocations > 0);
+    assert_eq!(
+        extended.to_string(),
+        "250-2.1.5 Okay\r\n250 2.1.5 Welcome\r\n"
+    );
+}
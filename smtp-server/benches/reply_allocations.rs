@@ -0,0 +1,230 @@
+//! Counts the heap allocations made while serializing replies and while
+//! running whole SMTP transactions through `interact`.
+//!
+//! The baseline is the way `send_reply!` used to send replies, collecting
+//! `Reply::as_io_slices` into a new `Vec` for each of them, which is compared
+//! to `Reply::write_into` a reused buffer as done now. As the old `interact`
+//! cannot run anymore, its count is estimated by adding back the difference
+//! between the two for the replies of a transaction.
+//!
+//! Run with `cargo bench -p smtp-server --bench reply_allocations`.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    io::IoSlice,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use duplexify::Duplex;
+use futures::{io, AsyncRead, AsyncReadExt, AsyncWrite};
+
+use smtp_message::{Email, EscapedDataReader};
+use smtp_server::{
+    interact, reply, ConnectionMetadata, Decision, IsAlreadyTls, MailMetadata, Reply, TlsInfo,
+};
+
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+fn allocations() -> usize {
+    ALLOCATIONS.load(Ordering::Relaxed)
+}
+
+struct BenchConfig;
+
+impl smtp_server::Config for BenchConfig {
+    type ConnectionUserMeta = ();
+    type MailUserMeta = ();
+    type Protocol = smtp_server::protocol::Smtp;
+
+    fn hostname(&self, _conn_meta: &ConnectionMetadata<()>) -> &str {
+        "bench.example.org"
+    }
+
+    async fn new_mail(&self, _conn_meta: &mut ConnectionMetadata<()>) {}
+
+    type TlsStream<IO>
+        = IO
+    where
+        IO: 'static + Unpin + Send + AsyncRead + AsyncWrite;
+
+    async fn tls_accept<IO>(
+        &self,
+        _io: IO,
+        _conn_meta: &mut ConnectionMetadata<()>,
+    ) -> io::Result<(IO, TlsInfo)>
+    where
+        IO: 'static + Unpin + Send + AsyncRead + AsyncWrite,
+    {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "tls not implemented for bench",
+        ))
+    }
+
+    async fn filter_from(
+        &self,
+        from: Option<Email>,
+        _meta: &mut MailMetadata<()>,
+        _conn_meta: &mut ConnectionMetadata<()>,
+    ) -> Decision<Option<Email>> {
+        Decision::Accept {
+            reply: reply::okay_from().convert(),
+            res: from,
+        }
+    }
+
+    async fn filter_to(
+        &self,
+        to: Email,
+        _meta: &mut MailMetadata<()>,
+        _conn_meta: &mut ConnectionMetadata<()>,
+    ) -> Decision<Email> {
+        Decision::Accept {
+            reply: reply::okay_to().convert(),
+            res: to,
+        }
+    }
+
+    async fn handle_mail<'resp, R>(
+        &'resp self,
+        reader: &mut EscapedDataReader<'_, R>,
+        _mail: MailMetadata<()>,
+        _conn_meta: &'resp mut ConnectionMetadata<()>,
+    ) -> Decision<()>
+    where
+        R: Send + Unpin + AsyncRead,
+    {
+        let mut buf = [0; 1024];
+        while reader.read(&mut buf).await.unwrap_or(0) > 0 {}
+        reader.complete();
+        Decision::Accept {
+            reply: reply::okay_mail().convert(),
+            res: (),
+        }
+    }
+}
+
+const TRANSACTION: &[u8] = b"MAIL FROM:<alice@example.org>\r\n\
+    RCPT TO:<bob@example.org>\r\n\
+    RCPT TO:<carol@example.org>\r\n\
+    DATA\r\n\
+    Subject: hello\r\n\
+    \r\n\
+    Hello world!\r\n\
+    .\r\n";
+
+/// Allocations made by a session running `transactions` transactions
+fn session_allocations(transactions: usize) -> usize {
+    let mut input = b"EHLO client.example.org\r\n".to_vec();
+    for _ in 0..transactions {
+        input.extend_from_slice(TRANSACTION);
+    }
+    input.extend_from_slice(b"QUIT\r\n");
+    let io = Duplex::new(io::Cursor::new(input), io::sink());
+    let cfg = Arc::new(BenchConfig);
+
+    let before = allocations();
+    smol::block_on(interact(io, IsAlreadyTls::No, (), cfg)).expect("session failed");
+    allocations() - before
+}
+
+fn replies() -> Vec<Reply> {
+    vec![
+        reply::okay_from().convert(),
+        reply::okay_to().convert(),
+        reply::okay_to().convert(),
+        reply::okay_data().convert(),
+        reply::okay_mail().convert(),
+    ]
+}
+
+/// Allocations made serializing `replies` `rounds` times, either the way
+/// replies used to be sent or into a reused buffer
+fn reply_allocations(replies: &[Reply], rounds: usize, reuse_buffer: bool) -> (usize, f64) {
+    let mut buf = Vec::new();
+    let mut written = 0;
+    let before = allocations();
+    let start = Instant::now();
+    for _ in 0..rounds {
+        for r in replies {
+            if reuse_buffer {
+                buf.clear();
+                r.write_into(&mut buf);
+                written += buf.len();
+            } else {
+                let slices = r.as_io_slices().collect::<Vec<IoSlice>>();
+                written += slices.iter().map(|s| s.len()).sum::<usize>();
+            }
+        }
+    }
+    let elapsed = start.elapsed();
+    assert!(written > 0);
+    let per_reply = (rounds * replies.len()) as f64;
+    (
+        allocations() - before,
+        elapsed.as_nanos() as f64 / per_reply,
+    )
+}
+
+fn main() {
+    const ROUNDS: usize = 100_000;
+    // One round serializes the replies to a transaction
+    let replies = replies();
+    let mut per_transaction = [0.; 2];
+    for (i, (name, reuse)) in [("collected IoSlices", false), ("reused buffer", true)]
+        .into_iter()
+        .enumerate()
+    {
+        let (allocs, ns) = reply_allocations(&replies, ROUNDS, reuse);
+        per_transaction[i] = allocs as f64 / ROUNDS as f64;
+        println!(
+            "reply serialization, {:<18}: {:>6.3} allocations/transaction, {:>6.1} ns/reply",
+            name, per_transaction[i], ns
+        );
+    }
+    let [before, after] = per_transaction;
+
+    // The difference between two session lengths removes the per-session
+    // setup from the count
+    const SHORT: usize = 10;
+    const LONG: usize = 1010;
+    let short = session_allocations(SHORT);
+    let long = session_allocations(LONG);
+    let now = long.saturating_sub(short) as f64 / (LONG - SHORT) as f64;
+    println!(
+        "interact, {:<33}: {:>6.3} allocations/transaction",
+        "measured", now
+    );
+    // Not a measurement: the old `interact` is gone, so this only adds back
+    // the serialization difference counted above
+    println!(
+        "interact, {:<33}: {:>6.3} allocations/transaction",
+        "estimated with collected IoSlices",
+        now + before - after
+    );
+}
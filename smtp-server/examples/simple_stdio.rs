@@ -1,17 +1,17 @@
 #![type_length_limit = "200000000"]
 
-use std::{pin::Pin, sync::Arc};
+use std::sync::Arc;
 
-use async_trait::async_trait;
 use duplexify::Duplex;
 use futures::{executor, io, AsyncRead, AsyncReadExt, AsyncWrite};
 
 use smtp_message::{Email, EscapedDataReader, Reply, ReplyCode};
-use smtp_server::{interact, reply, ConnectionMetadata, Decision, IsAlreadyTls, MailMetadata};
+use smtp_server::{
+    interact, reply, ConnectionMetadata, Decision, IsAlreadyTls, MailMetadata, TlsInfo,
+};
 
 struct SimpleConfig;
 
-#[async_trait]
 impl smtp_server::Config for SimpleConfig {
     type ConnectionUserMeta = ();
     type MailUserMeta = ();
@@ -23,15 +23,18 @@ impl smtp_server::Config for SimpleConfig {
 
     async fn new_mail(&self, _conn_meta: &mut ConnectionMetadata<()>) {}
 
+    type TlsStream<IO>
+        = IO
+    where
+        IO: 'static + Unpin + Send + AsyncRead + AsyncWrite;
+
     async fn tls_accept<IO>(
         &self,
         _io: IO,
         _conn_meta: &mut ConnectionMetadata<()>,
-    ) -> io::Result<
-        duplexify::Duplex<Pin<Box<dyn Send + AsyncRead>>, Pin<Box<dyn Send + AsyncWrite>>>,
-    >
+    ) -> io::Result<(IO, TlsInfo)>
     where
-        IO: Send + AsyncRead + AsyncWrite,
+        IO: 'static + Unpin + Send + AsyncRead + AsyncWrite,
     {
         Err(io::Error::new(
             io::ErrorKind::InvalidInput,
@@ -58,7 +61,7 @@ impl smtp_server::Config for SimpleConfig {
             reply: Reply {
                 code: ReplyCode::POLICY_REASON,
                 ecode: None,
-                text: vec!["The sender is not the 'whitelisted' user".into()],
+                text: vec!["The sender is not the 'whitelisted' user".into()].into(),
             },
         }
     }
@@ -80,7 +83,7 @@ impl smtp_server::Config for SimpleConfig {
                 reply: Reply {
                     code: ReplyCode::POLICY_REASON,
                     ecode: None,
-                    text: vec!["The 'forbidden' user is forbidden".into()],
+                    text: vec!["The 'forbidden' user is forbidden".into()].into(),
                 },
             }
         }
@@ -101,7 +104,7 @@ impl smtp_server::Config for SimpleConfig {
                 reply: Reply {
                     code: ReplyCode::POLICY_REASON,
                     ecode: None,
-                    text: vec!["io error".into()],
+                    text: vec!["io error".into()].into(),
                 },
             };
         }
@@ -111,7 +114,7 @@ impl smtp_server::Config for SimpleConfig {
                 reply: Reply {
                     code: ReplyCode::POLICY_REASON,
                     ecode: None,
-                    text: vec!["No 'swearwords' here".into()],
+                    text: vec!["No 'swearwords' here".into()].into(),
                 },
             }
         } else {
@@ -127,5 +130,6 @@ fn main() -> io::Result<()> {
     let reader = io::AllowStdIo::new(std::io::stdin());
     let writer = io::AllowStdIo::new(std::io::stdout());
     let io = Duplex::new(reader, writer);
-    executor::block_on(interact(io, IsAlreadyTls::No, (), Arc::new(SimpleConfig)))
+    executor::block_on(interact(io, IsAlreadyTls::No, (), Arc::new(SimpleConfig)))?;
+    Ok(())
 }
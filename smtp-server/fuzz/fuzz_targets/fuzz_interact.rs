@@ -1,9 +1,8 @@
 #![no_main]
 #![type_length_limit = "200000000"]
 
-use std::{pin::Pin, sync::Arc};
+use std::sync::Arc;
 
-use async_trait::async_trait;
 use duplexify::Duplex;
 use futures::{
     executor,
@@ -14,11 +13,12 @@ use futures_test::io::AsyncReadTestExt;
 use libfuzzer_sys::fuzz_target;
 
 use smtp_message::{Email, EscapedDataReader, Reply, ReplyCode};
-use smtp_server::{interact, reply, ConnectionMetadata, Decision, IsAlreadyTls, MailMetadata};
+use smtp_server::{
+    interact, reply, ConnectionMetadata, Decision, IsAlreadyTls, MailMetadata, TlsInfo,
+};
 
 struct FuzzConfig;
 
-#[async_trait]
 impl smtp_server::Config for FuzzConfig {
     type ConnectionUserMeta = ();
     type MailUserMeta = ();
@@ -30,13 +30,16 @@ impl smtp_server::Config for FuzzConfig {
 
     async fn new_mail(&self, _conn_meta: &mut ConnectionMetadata<()>) {}
 
+    type TlsStream<IO>
+        = IO
+    where
+        IO: 'static + Unpin + Send + AsyncRead + AsyncWrite;
+
     async fn tls_accept<IO>(
         &self,
         _io: IO,
         _conn_meta: &mut ConnectionMetadata<()>,
-    ) -> io::Result<
-        duplexify::Duplex<Pin<Box<dyn Send + AsyncRead>>, Pin<Box<dyn Send + AsyncWrite>>>,
-    >
+    ) -> io::Result<(IO, TlsInfo)>
     where
         IO: 'static + Unpin + Send + AsyncRead + AsyncWrite,
     {
@@ -64,7 +67,7 @@ impl smtp_server::Config for FuzzConfig {
                     reply: Reply {
                         code: ReplyCode::POLICY_REASON,
                         ecode: None,
-                        text: vec!["forbidden user".into()],
+                        text: vec!["forbidden user".into()].into(),
                     },
                 }
             }
@@ -93,7 +96,7 @@ impl smtp_server::Config for FuzzConfig {
                 reply: Reply {
                     code: ReplyCode::POLICY_REASON,
                     ecode: None,
-                    text: vec!["forbidden user".into()],
+                    text: vec!["forbidden user".into()].into(),
                 },
             }
         }
@@ -115,7 +118,7 @@ impl smtp_server::Config for FuzzConfig {
                 reply: Reply {
                     code: ReplyCode::POLICY_REASON,
                     ecode: None,
-                    text: vec!["io error".into()],
+                    text: vec!["io error".into()].into(),
                 },
             };
         }
@@ -127,7 +130,7 @@ impl smtp_server::Config for FuzzConfig {
                 reply: Reply {
                     code: ReplyCode::POLICY_REASON,
                     ecode: None,
-                    text: vec!["too many recipients".into()],
+                    text: vec!["too many recipients".into()].into(),
                 },
             }
         } else {
@@ -0,0 +1,144 @@
+use std::{fmt, io};
+
+use crate::{ProtocolName, Reply};
+
+/// Part of the session during which the client closed the connection
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SessionPhase {
+    /// While sending a command line
+    Command,
+
+    /// While sending the contents of a mail after DATA
+    Data,
+}
+
+/// Reason why [`interact`](crate::interact) terminated a session with an
+/// error
+#[derive(Debug)]
+pub enum SessionError {
+    /// The client did not send a command within
+    /// [`Config::command_read_timeout`](crate::Config::command_read_timeout)
+    CommandTimeout,
+
+    /// A reply could not be sent within
+    /// [`Config::reply_write_timeout`](crate::Config::reply_write_timeout)
+    ReplyTimeout,
+
+    /// The client closed the connection in the middle of something
+    ClientAborted { phase: SessionPhase },
+
+    /// [`Config::tls_accept`](crate::Config::tls_accept) failed
+    TlsFailed(io::Error),
+
+    /// The `Config` returned a [`Decision::Kill`](crate::Decision::Kill) with
+    /// an error
+    ///
+    /// A `Kill` with an `Ok` outcome, like the one of the default
+    /// [`Config::handle_quit`](crate::Config::handle_quit), ends the session
+    /// with `Ok(())` and is not told apart from a normal end. `Config`s that
+    /// need to tell them apart can record it in the connection metadata.
+    Killed {
+        reply: Option<Reply>,
+        error: io::Error,
+    },
+
+    /// The session was asked to speak `requested`, but the `Config` only
+    /// speaks `supported`, or it was not asked for any protocol while the
+    /// `Config` uses the [`Dynamic`](crate::protocol::Dynamic) one
+    ///
+    /// This is detected before anything is sent, and without calling any
+    /// `Config` hook.
+    ProtocolMismatch {
+        requested: Option<ProtocolName>,
+        supported: Option<ProtocolName>,
+    },
+
+    /// Reading from or writing to the connection failed
+    Io(io::Error),
+}
+
+impl SessionError {
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            SessionError::CommandTimeout | SessionError::ReplyTimeout => io::ErrorKind::TimedOut,
+            SessionError::ClientAborted { .. } => io::ErrorKind::ConnectionAborted,
+            SessionError::ProtocolMismatch { .. } => io::ErrorKind::InvalidInput,
+            SessionError::TlsFailed(e)
+            | SessionError::Killed { error: e, .. }
+            | SessionError::Io(e) => e.kind(),
+        }
+    }
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SessionError::CommandTimeout => write!(f, "timed out waiting for a command"),
+            SessionError::ReplyTimeout => write!(f, "timed out sending a reply"),
+            SessionError::ClientAborted {
+                phase: SessionPhase::Command,
+            } => write!(f, "connection shutdown with partial command"),
+            SessionError::ClientAborted {
+                phase: SessionPhase::Data,
+            } => write!(f, "connection shutdown during email reception"),
+            SessionError::TlsFailed(e) => write!(f, "TLS handshake failed: {}", e),
+            SessionError::Killed {
+                reply: Some(reply),
+                error,
+            } => write!(
+                f,
+                "killed by configuration with reply {:?}: {}",
+                reply.to_string(),
+                error
+            ),
+            SessionError::Killed { reply: None, error } => {
+                write!(f, "killed by configuration: {}", error)
+            }
+            SessionError::ProtocolMismatch {
+                requested: None,
+                supported: _,
+            } => write!(
+                f,
+                "a Config with the Dynamic protocol needs interact_with_protocol"
+            ),
+            SessionError::ProtocolMismatch {
+                requested: Some(requested),
+                supported,
+            } => write!(
+                f,
+                "asked for a {:?} session with a {:?}-only Config",
+                requested,
+                supported.unwrap_or(*requested)
+            ),
+            SessionError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for SessionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SessionError::TlsFailed(e)
+            | SessionError::Killed { error: e, .. }
+            | SessionError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SessionError {
+    fn from(e: io::Error) -> SessionError {
+        SessionError::Io(e)
+    }
+}
+
+impl From<SessionError> for io::Error {
+    fn from(e: SessionError) -> io::Error {
+        match e {
+            SessionError::TlsFailed(e)
+            | SessionError::Killed { error: e, .. }
+            | SessionError::Io(e) => e,
+            e => io::Error::new(e.kind(), e.to_string()),
+        }
+    }
+}
@@ -1,32 +1,60 @@
 #![cfg_attr(test, feature(negative_impls))]
 #![type_length_limit = "200000000"]
 
+mod error;
+pub mod observer;
 pub mod protocol;
+pub mod server;
+mod shutdown;
+#[cfg(feature = "rustls")]
+pub mod tls;
+mod trace;
+pub mod transcript;
 
-use std::{cmp, io, ops::Range, pin::Pin, sync::Arc};
-use std::io::Error;
-use async_trait::async_trait;
 use chrono::Utc;
 use futures::{
+    future::Either,
     io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     StreamExt,
 };
+use log::{error, trace};
 use smol::future::FutureExt;
-use log::trace;
 use smtp_message::{
-    next_crlf, nom, Command, Email, EscapedDataReader, Hostname, MaybeUtf8, NextCrLfState, Reply,
+    next_crlf, nom, Command, Email, EscapedDataReader, Hostname, MaybeUtf8, NextCrLfState,
+    ReplyCode,
+};
+use std::io::Error;
+use std::{
+    cmp, future::Future, io, net::SocketAddr, ops::Range, pin::Pin, sync::Arc, time::Instant,
 };
 
-pub use smtp_server_types::{reply, ConnectionMetadata, Decision, HelloInfo, MailMetadata};
+pub use smtp_server_types::{
+    reply, ConnectionMetadata, Decision, ErrorCounters, HelloInfo, MailMetadata, Reply, TlsInfo,
+};
 
+pub use error::{SessionError, SessionPhase};
+pub use observer::SessionObserver;
+use observer::{Observer, TransactionOutcome};
 pub use protocol::{Protocol, ProtocolName};
+pub use server::Server;
+pub use shutdown::{Shutdown, ShutdownReason, ShutdownSignal};
+#[cfg(feature = "rustls")]
+pub use tls::RustlsAcceptor;
+use trace::Trace;
+use transcript::Tapped;
+pub use transcript::TranscriptSink;
 
 pub const RDBUF_SIZE: usize = 16 * 1024;
 const MINIMUM_FREE_BUFSPACE: usize = 128;
+// Large enough for the EHLO reply, the longest one the server sends by itself
+const WRBUF_SIZE: usize = 1024;
 
-#[async_trait]
+/// Policy of the server
+///
+/// The hooks returning futures can be implemented with plain `async fn`s. The
+/// futures must be `Send`, so that the ones returned by `interact` are too.
 pub trait Config: Send + Sync {
-    type Protocol: for<'resp> Protocol<'resp>;
+    type Protocol: Protocol;
 
     type ConnectionUserMeta: Send;
     type MailUserMeta: Send;
@@ -44,11 +72,42 @@ pub trait Config: Send + Sync {
         "Service ready"
     }
 
+    /// Rejecting here with a 554 reply (for instance
+    /// [`reply::no_smtp_service`]) makes the session answer every command but
+    /// QUIT with `command_after_refused_banner`, as per RFC 5321 §3.1. These
+    /// answers do not count as client errors. Rejections with any other reply
+    /// code (for instance a 421) close the connection right after the reply.
     fn welcome_banner_reply(
         &self,
         conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
-    ) -> Reply {
-        reply::welcome_banner(self.hostname(conn_meta), self.welcome_banner(conn_meta))
+    ) -> Decision<()> {
+        Decision::Accept {
+            reply: reply::welcome_banner(self.hostname(conn_meta), self.welcome_banner(conn_meta)),
+            res: (),
+        }
+    }
+
+    /// Time to wait before sending the welcome banner. Clients that send
+    /// anything during this delay are reported to
+    /// [`early_talker`](Config::early_talker).
+    #[allow(unused_variables)]
+    fn greeting_delay(
+        &self,
+        conn_meta: &ConnectionMetadata<Self::ConnectionUserMeta>,
+    ) -> chrono::Duration {
+        chrono::Duration::zero()
+    }
+
+    /// Called when the client sends data before the end of the
+    /// [`greeting_delay`](Config::greeting_delay). Returning a reply sends it
+    /// and closes the connection, while returning `None` lets the session go on
+    /// as usual, eg. after having recorded the fact in `conn_meta`.
+    #[allow(unused_variables)]
+    fn early_talker(
+        &self,
+        conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
+    ) -> impl Future<Output = Option<Reply>> + Send {
+        async move { Some(reply::early_talker().convert()) }
     }
 
     /// Note: this function is only ever used for the default implementations of
@@ -60,29 +119,31 @@ pub trait Config: Send + Sync {
     }
 
     #[allow(unused_variables)]
-    async fn filter_hello(
+    fn filter_hello(
         &self,
         is_extended: bool,
         hostname: Hostname,
         conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
-    ) -> Decision<HelloInfo> {
-        // Set `conn_meta.hello` early so that can_do_tls can use it below
-        conn_meta.hello = Some(HelloInfo {
-            is_extended,
-            hostname: hostname.clone(),
-        });
-        Decision::Accept {
-            reply: reply::okay_hello(
+    ) -> impl Future<Output = Decision<HelloInfo>> + Send {
+        async move {
+            // Set `conn_meta.hello` early so that can_do_tls can use it below
+            conn_meta.hello = Some(HelloInfo {
                 is_extended,
-                self.hostname(conn_meta),
-                self.hello_banner(conn_meta),
-                self.can_do_tls(conn_meta),
-            )
-            .convert(),
-            res: HelloInfo {
-                is_extended,
-                hostname,
-            },
+                hostname: hostname.clone(),
+            });
+            Decision::Accept {
+                reply: reply::okay_hello(
+                    is_extended,
+                    self.hostname(conn_meta),
+                    self.hello_banner(conn_meta),
+                    self.can_do_tls(conn_meta),
+                )
+                .convert(),
+                res: HelloInfo {
+                    is_extended,
+                    hostname,
+                },
+            }
         }
     }
 
@@ -96,66 +157,94 @@ pub trait Config: Send + Sync {
                 .unwrap_or(false)
     }
 
-    // TODO: when GATs are here, we can remove the trait object and return
-    // Self::TlsStream<IO> (or maybe we should refactor Config to be Config<IO>? but
-    // that's ugly). At that time we can probably get rid of all that duplexify
-    // mess... or maybe when we can do trait objects with more than one trait
+    /// Stream returned by `tls_accept` for a connection on `IO`
+    ///
+    /// Configurations that do not implement TLS can just set it to `IO`.
+    type TlsStream<IO>: 'static + Unpin + Send + AsyncRead + AsyncWrite
+    where
+        IO: 'static + Unpin + Send + AsyncRead + AsyncWrite;
+
     /// Note: if you don't want to implement TLS, you should override
     /// `can_do_tls` to return `false` so that STARTTLS is not advertized. This
     /// being said, returning an error here should have the same result in
     /// practice, except clients will try STARTTLS and fail
-    async fn tls_accept<IO>(
+    ///
+    /// With the `rustls` feature, this can be implemented by forwarding to
+    /// [`RustlsAcceptor::accept`](tls::RustlsAcceptor::accept).
+    ///
+    /// The returned [`TlsInfo`] is stored in `conn_meta.tls`.
+    fn tls_accept<IO>(
         &self,
         io: IO,
         conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
-    ) -> io::Result<
-        duplexify::Duplex<Pin<Box<dyn Send + AsyncRead>>, Pin<Box<dyn Send + AsyncWrite>>>,
-    >
+    ) -> impl Future<Output = io::Result<(Self::TlsStream<IO>, TlsInfo)>> + Send
     where
         IO: 'static + Unpin + Send + AsyncRead + AsyncWrite;
 
-    async fn new_mail(
+    /// Called after a TLS handshake in which the client presented a
+    /// certificate (see `conn_meta.tls`), to map it to the identity stored in
+    /// `conn_meta.identity`, for instance by its fingerprint or subject.
+    ///
+    /// This is only called if `tls_accept` marked the certificate as
+    /// `verified`, which [`RustlsAcceptor`](tls::RustlsAcceptor) only does
+    /// when configured with a client CA.
+    #[allow(unused_variables)]
+    fn authenticate_client_certificate(
+        &self,
+        conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
+    ) -> impl Future<Output = Option<String>> + Send {
+        async move { None }
+    }
+
+    fn new_mail(
         &self,
         conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
-    ) -> Self::MailUserMeta;
+    ) -> impl Future<Output = Self::MailUserMeta> + Send;
 
-    async fn filter_from(
+    fn filter_from(
         &self,
         from: Option<Email>,
         meta: &mut MailMetadata<Self::MailUserMeta>,
         conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
-    ) -> Decision<Option<Email>>;
+    ) -> impl Future<Output = Decision<Option<Email>>> + Send;
 
-    async fn filter_to(
+    fn filter_to(
         &self,
         to: Email,
         meta: &mut MailMetadata<Self::MailUserMeta>,
         conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
-    ) -> Decision<Email>;
+    ) -> impl Future<Output = Decision<Email>> + Send;
 
     #[allow(unused_variables)]
-    async fn filter_data(
+    fn filter_data(
         &self,
         meta: &mut MailMetadata<Self::MailUserMeta>,
         conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
-    ) -> Decision<()> {
-        Decision::Accept {
-            reply: reply::okay_data().convert(),
-            res: (),
+    ) -> impl Future<Output = Decision<()>> + Send {
+        async move {
+            Decision::Accept {
+                reply: reply::okay_data().convert(),
+                res: (),
+            }
         }
     }
 
     /// `handle_mail` is an async function that returns either a single decision
     /// in the case of the SMTP protocol, or an async stream of decisions in the
-    /// case of the LMTP protocol.
+    /// case of the LMTP protocol. With the [`Dynamic`](protocol::Dynamic)
+    /// protocol, it returns a [`MailDecisions`](protocol::MailDecisions) that
+    /// can be either, depending on `conn_meta.protocol`.
     ///
     /// For LMTP: there must be one such decision for each RCPT TO command that
     /// succeeded (i.e. for each accepted recipient), which allows to indicate
     /// that the message could be stored in the mailbox of some users, but not
     /// in that of other users. This can happen for instance if their mail
-    /// quota is used up. The lifetimes of the borrow on the mail's data stream
-    /// is limited to the duration of the async call; this reference may not be
-    /// retained by the returned stream. The async function must consume the
+    /// quota is used up. Returning a different number of decisions is
+    /// reported through `wrong_decision_count`.
+    ///
+    /// The lifetimes of the borrow on the mail's data stream is limited to the
+    /// duration of the async call; this reference may not be retained by the
+    /// returned stream. The async function must consume the
     /// entire data stream before returning its stream of responses. The
     /// recommended implementation of this function would start by reading the
     /// message's content to a temporary file, and then produce a stream that
@@ -168,103 +257,199 @@ pub trait Config: Send + Sync {
     /// Also, note that there is no timeout applied here, so the implementation
     /// of this function is responsible for making sure that the client does not
     /// just stop sending anything to DOS the system.
-    async fn handle_mail<'resp, R>(
+    fn handle_mail<'resp, R>(
         &'resp self,
         stream: &mut EscapedDataReader<'_, R>, // not borrowed for whole 'resp lifetime
         meta: MailMetadata<Self::MailUserMeta>,
         conn_meta: &'resp mut ConnectionMetadata<Self::ConnectionUserMeta>,
-    ) -> <Self::Protocol as Protocol<'resp>>::HandleMailReturnType
+    ) -> impl Future<Output = <Self::Protocol as Protocol>::HandleMailReturnType<'resp>> + Send
     where
-        R: Send + Unpin + AsyncRead,
-        Self: 'resp;
+        R: Send + Unpin + AsyncRead;
 
     #[allow(unused_variables)]
-    async fn handle_rset(
+    fn handle_rset(
         &self,
         meta: &mut Option<MailMetadata<Self::MailUserMeta>>,
         conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
-    ) -> Decision<()> {
-        Decision::Accept {
-            reply: reply::okay_rset().convert(),
-            res: (),
+    ) -> impl Future<Output = Decision<()>> + Send {
+        async move {
+            Decision::Accept {
+                reply: reply::okay_rset().convert(),
+                res: (),
+            }
         }
     }
 
     #[allow(unused_variables)]
-    async fn handle_starttls(
+    fn handle_starttls(
         &self,
         conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
-    ) -> Decision<()> {
-        if self.can_do_tls(conn_meta) {
-            Decision::Accept {
-                reply: reply::okay_starttls().convert(),
-                res: (),
-            }
-        } else {
-            Decision::Reject {
-                reply: reply::command_not_supported().convert(),
+    ) -> impl Future<Output = Decision<()>> + Send {
+        async move {
+            if self.can_do_tls(conn_meta) {
+                Decision::Accept {
+                    reply: reply::okay_starttls().convert(),
+                    res: (),
+                }
+            } else {
+                Decision::Reject {
+                    reply: reply::command_not_supported().convert(),
+                }
             }
         }
     }
 
     #[allow(unused_variables)]
-    async fn handle_expn(
+    fn handle_expn(
         &self,
         name: MaybeUtf8<&str>,
         conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
-    ) -> Decision<()> {
-        Decision::Reject {
-            reply: reply::command_unimplemented().convert(),
+    ) -> impl Future<Output = Decision<()>> + Send {
+        async move {
+            Decision::Reject {
+                reply: reply::command_unimplemented().convert(),
+            }
         }
     }
 
     #[allow(unused_variables)]
-    async fn handle_vrfy(
+    fn handle_vrfy(
         &self,
         name: MaybeUtf8<&str>,
         conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
-    ) -> Decision<()> {
-        Decision::Accept {
-            reply: reply::ignore_vrfy().convert(),
-            res: (),
+    ) -> impl Future<Output = Decision<()>> + Send {
+        async move {
+            Decision::Accept {
+                reply: reply::ignore_vrfy().convert(),
+                res: (),
+            }
         }
     }
 
     #[allow(unused_variables)]
-    async fn handle_help(
+    fn handle_help(
         &self,
         subject: MaybeUtf8<&str>,
         conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
-    ) -> Decision<()> {
-        Decision::Accept {
-            reply: reply::ignore_help().convert(),
-            res: (),
+    ) -> impl Future<Output = Decision<()>> + Send {
+        async move {
+            Decision::Accept {
+                reply: reply::ignore_help().convert(),
+                res: (),
+            }
         }
     }
 
     #[allow(unused_variables)]
-    async fn handle_noop(
+    fn handle_noop(
         &self,
         string: MaybeUtf8<&str>,
         conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
-    ) -> Decision<()> {
-        Decision::Accept {
-            reply: reply::okay_noop().convert(),
-            res: (),
+    ) -> impl Future<Output = Decision<()>> + Send {
+        async move {
+            Decision::Accept {
+                reply: reply::okay_noop().convert(),
+                res: (),
+            }
         }
     }
 
     #[allow(unused_variables)]
-    async fn handle_quit(
+    fn handle_quit(
         &self,
         conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
-    ) -> Decision<()> {
-        Decision::Kill {
-            reply: Some(reply::okay_quit().convert()),
-            res: Ok(()),
+    ) -> impl Future<Output = Decision<()>> + Send {
+        async move {
+            Decision::Kill {
+                reply: Some(reply::okay_quit().convert()),
+                res: Ok(()),
+            }
+        }
+    }
+
+    /// Called once at the start of each session, before the welcome banner is
+    /// sent
+    #[allow(unused_variables)]
+    fn on_connect(
+        &self,
+        conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
+    ) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    /// Called once at the start of each session, after `on_connect`, to get
+    /// where to record its transcript, if anywhere
+    #[allow(unused_variables)]
+    fn transcript_sink(
+        &self,
+        conn_meta: &ConnectionMetadata<Self::ConnectionUserMeta>,
+    ) -> Option<Box<dyn TranscriptSink>> {
+        None
+    }
+
+    /// Called once at the start of each session, after `on_connect`, to get
+    /// the observer to report its commands, replies and transactions to, if
+    /// any
+    ///
+    /// Returning a shared [`observer::Metrics`] gathers metrics over all
+    /// sessions.
+    #[allow(unused_variables)]
+    fn session_observer(
+        &self,
+        conn_meta: &ConnectionMetadata<Self::ConnectionUserMeta>,
+    ) -> Option<Arc<dyn SessionObserver>> {
+        None
+    }
+
+    /// Called whenever a `MailMetadata` built by `new_mail` is dropped without
+    /// being given to `handle_mail`, so that any resource reserved for it can
+    /// be released
+    #[allow(unused_variables)]
+    fn transaction_aborted(
+        &self,
+        meta: MailMetadata<Self::MailUserMeta>,
+        reason: TransactionAbortReason,
+        conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
+    ) -> impl Future<Output = ()> + Send {
+        async move {}
+    }
+
+    /// Called when `handle_mail` returned a number of decisions different from
+    /// the number of accepted recipients, which is a bug in the `Config`
+    ///
+    /// Missing decisions are replaced with `missing_decision`, and extra
+    /// decisions are read until the end of the stream so that `got` is the
+    /// actual count, but not sent to the client.
+    #[allow(unused_variables)]
+    fn wrong_decision_count(
+        &self,
+        expected: usize,
+        got: usize,
+        conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
+    ) -> impl Future<Output = ()> + Send {
+        async move {
+            error!(
+                "handle_mail returned {} decisions, expected {}",
+                got, expected
+            );
         }
     }
 
+    /// Called once at the end of each session, whether it ended successfully
+    /// or not, with the final `ConnectionMetadata`
+    ///
+    /// It is not called if the future returned by `interact` is dropped
+    /// before completing, eg. when the task running it is cancelled, as it
+    /// cannot run from a destructor.
+    #[allow(unused_variables)]
+    fn on_disconnect(
+        &self,
+        conn_meta: ConnectionMetadata<Self::ConnectionUserMeta>,
+        res: &Result<(), SessionError>,
+    ) -> impl Future<Output = ()> + Send {
+        async move {}
+    }
+
     #[allow(unused_variables)]
     fn already_did_hello(
         &self,
@@ -342,6 +527,14 @@ pub trait Config: Send + Sync {
         reply::line_too_long().convert()
     }
 
+    #[allow(unused_variables)]
+    fn command_after_refused_banner(
+        &self,
+        conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
+    ) -> Reply {
+        reply::bad_sequence().convert()
+    }
+
     #[allow(unused_variables)]
     fn handle_mail_did_not_call_complete(
         &self,
@@ -350,6 +543,78 @@ pub trait Config: Send + Sync {
         reply::handle_mail_did_not_call_complete().convert()
     }
 
+    /// Reply sent for each recipient `handle_mail` did not return a decision
+    /// for
+    #[allow(unused_variables)]
+    fn missing_decision(
+        &self,
+        conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
+    ) -> Reply {
+        reply::missing_decision().convert()
+    }
+
+    /// Number of errors (see [`ErrorCounters::total`]) after which the replies
+    /// to further errors start being delayed by `tarpit_delay`. Defaults to
+    /// `None`, which disables tarpitting
+    fn soft_error_limit(&self) -> Option<u32> {
+        None
+    }
+
+    /// Number of errors after which the session is closed with
+    /// `too_many_errors`. Defaults to `None`, which never closes it
+    fn hard_error_limit(&self) -> Option<u32> {
+        None
+    }
+
+    /// Delay to wait for before replying to an error. Defaults to one more
+    /// second for each error past `soft_error_limit`, if there is one, up to
+    /// `reply_write_timeout`
+    fn tarpit_delay(
+        &self,
+        conn_meta: &ConnectionMetadata<Self::ConnectionUserMeta>,
+    ) -> chrono::Duration {
+        match self.soft_error_limit() {
+            Some(soft) => {
+                let over = conn_meta.errors.total().saturating_sub(soft);
+                chrono::Duration::seconds(over.into()).min(self.reply_write_timeout())
+            }
+            None => chrono::Duration::zero(),
+        }
+    }
+
+    #[allow(unused_variables)]
+    fn too_many_errors(
+        &self,
+        conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
+    ) -> Reply {
+        reply::too_many_errors().convert()
+    }
+
+    /// Reply sent by [`Server`](Server) before closing connections that go
+    /// over its connection limits
+    #[allow(unused_variables)]
+    fn too_many_connections(&self, peer_addr: &SocketAddr) -> Reply {
+        reply::too_many_connections().convert()
+    }
+
+    /// Reply sent before closing a session that was asked to stop through the
+    /// [`ShutdownSignal`](ShutdownSignal) given to
+    /// [`interact_with_shutdown`](interact_with_shutdown)
+    #[allow(unused_variables)]
+    fn service_shutting_down(
+        &self,
+        reason: ShutdownReason,
+        conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
+    ) -> Reply {
+        reply::service_shutting_down().convert()
+    }
+
+    /// Time given to a session that is inside DATA when shutdown is requested
+    /// to finish handling the mail, before it gets killed
+    fn shutdown_grace_period(&self) -> chrono::Duration {
+        chrono::Duration::minutes(1)
+    }
+
     fn reply_write_timeout(&self) -> chrono::Duration {
         chrono::Duration::minutes(5)
     }
@@ -363,7 +628,7 @@ async fn advance_until_crlf<R>(
     r: &mut R,
     buf: &mut [u8],
     unhandled: &mut Range<usize>,
-) -> io::Result<()>
+) -> Result<(), SessionError>
 where
     R: Unpin + AsyncRead,
 {
@@ -375,59 +640,304 @@ where
         } else {
             let read = r.read(buf).await?;
             if read == 0 {
-                return Err(io::Error::new(
-                    io::ErrorKind::ConnectionAborted,
-                    "connection shutdown while waiting for crlf after invalid command",
-                ));
+                // Connection shutdown while waiting for crlf after invalid command
+                return Err(SessionError::ClientAborted {
+                    phase: SessionPhase::Command,
+                });
             }
             *unhandled = 0..read;
         }
     }
 }
 
-#[derive(Clone, Copy, Eq, PartialEq)]
+async fn authenticate_client_certificate<Cfg>(
+    cfg: &Cfg,
+    conn_meta: &mut ConnectionMetadata<Cfg::ConnectionUserMeta>,
+) where
+    Cfg: Config,
+{
+    // Unverified certificates are only the client's claim, and say nothing
+    // about who it is
+    let has_certificate = conn_meta
+        .tls
+        .as_ref()
+        .map(|tls| tls.verified && !tls.peer_certificates.is_empty())
+        .unwrap_or(false);
+    if has_certificate {
+        conn_meta.identity = cfg.authenticate_client_certificate(conn_meta).await;
+    }
+}
+
+#[derive(Clone, Debug)]
 pub enum IsAlreadyTls {
-    Yes,
+    /// The connection is already encrypted, eg. for implicit TLS, with the
+    /// given handshake parameters, which end up in `conn_meta.tls`
+    Yes(TlsInfo),
     No,
 }
 
+/// TLS state of a connection when its session starts
+pub(crate) enum SessionTls {
+    /// As given by the caller of `interact`
+    Given(IsAlreadyTls),
+
+    /// Implicit TLS, with the handshake done through `Config::tls_accept`
+    /// before the banner is sent
+    Handshake,
+}
+
+/// Reason given to [`Config::transaction_aborted`](Config::transaction_aborted)
+/// for dropping a mail transaction before it reached `handle_mail`
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TransactionAbortReason {
+    /// `filter_from` did not accept the MAIL FROM
+    FromRejected,
+
+    /// DATA was sent while no recipient had been accepted
+    NoRecipients,
+
+    /// `handle_rset` accepted a RSET
+    Rset,
+
+    /// A STARTTLS succeeded
+    Starttls,
+
+    /// The session ended, for whatever reason, with the transaction still open
+    SessionEnded,
+}
+
+/// Runs an SMTP session on `io`
+///
+/// To have the address of the client in the `smtp_session` span with the
+/// `tracing` feature, use [`interact_with_shutdown`](interact_with_shutdown).
 pub async fn interact<IO, Cfg>(
     io: IO,
     is_already_tls: IsAlreadyTls,
     metadata: Cfg::ConnectionUserMeta,
     cfg: Arc<Cfg>,
-) -> io::Result<()>
+) -> Result<(), SessionError>
 where
     IO: 'static + Send + AsyncRead + AsyncWrite,
     Cfg: Config,
 {
-    let (io_r, io_w) = io.split();
-    let mut io = duplexify::Duplex::new(
-        Box::pin(io_r) as Pin<Box<dyn Send + AsyncRead>>,
-        Box::pin(io_w) as Pin<Box<dyn Send + AsyncWrite>>,
-    );
+    interact_with_shutdown(
+        io,
+        is_already_tls,
+        None,
+        metadata,
+        ShutdownSignal::never(),
+        cfg,
+    )
+    .await
+}
 
-    let rdbuf = &mut [0; RDBUF_SIZE];
-    let mut unhandled = 0..0;
-    // TODO: should have a wrslices: Vec<IoSlice> here, so that we don't allocate
-    // for each write, but it looks like the API for reusing a Vec's backing
-    // allocation isn't ready yet and IoSlice's lifetime is going to make this
-    // impossible. Maybe this would require writing a crate that allows such vec
-    // storage recycling, as there doesn't appear to be any on crates.io. Having
-    // the wrslices would allow us to avoid all the allocations at each
-    // .collect() (present in `send_reply()`)
+/// Same as [`interact`](interact), but closes the session with
+/// [`Config::service_shutting_down`](Config::service_shutting_down) once
+/// `shutdown` triggers, and recording `peer` as the address of the client in
+/// the `tracing` spans.
+///
+/// Sessions are closed as soon as they wait for the client, be it during the
+/// greeting delay, between two commands or in the middle of a command line,
+/// except when inside DATA, where the mail is given
+/// [`Config::shutdown_grace_period`] to be handled.
+pub async fn interact_with_shutdown<IO, Cfg>(
+    io: IO,
+    is_already_tls: IsAlreadyTls,
+    peer: Option<SocketAddr>,
+    metadata: Cfg::ConnectionUserMeta,
+    shutdown: ShutdownSignal,
+    cfg: Arc<Cfg>,
+) -> Result<(), SessionError>
+where
+    IO: 'static + Send + AsyncRead + AsyncWrite,
+    Cfg: Config,
+{
+    interact_session(
+        io,
+        SessionTls::Given(is_already_tls),
+        None,
+        peer,
+        metadata,
+        shutdown,
+        cfg,
+    )
+    .await
+}
+
+/// Same as [`interact_with_shutdown`](interact_with_shutdown), but speaking
+/// `protocol`, for a `Config` whose `Protocol` is
+/// [`Dynamic`](protocol::Dynamic)
+///
+/// Fails with [`SessionError::ProtocolMismatch`] if `Config::Protocol` is a
+/// static protocol other than `protocol`.
+pub async fn interact_with_protocol<IO, Cfg>(
+    io: IO,
+    is_already_tls: IsAlreadyTls,
+    protocol: ProtocolName,
+    peer: Option<SocketAddr>,
+    metadata: Cfg::ConnectionUserMeta,
+    shutdown: ShutdownSignal,
+    cfg: Arc<Cfg>,
+) -> Result<(), SessionError>
+where
+    IO: 'static + Send + AsyncRead + AsyncWrite,
+    Cfg: Config,
+{
+    interact_session(
+        io,
+        SessionTls::Given(is_already_tls),
+        Some(protocol),
+        peer,
+        metadata,
+        shutdown,
+        cfg,
+    )
+    .await
+}
+
+/// `peer` is only used for tracing
+pub(crate) async fn interact_session<IO, Cfg>(
+    io: IO,
+    tls: SessionTls,
+    protocol: Option<ProtocolName>,
+    peer: Option<SocketAddr>,
+    metadata: Cfg::ConnectionUserMeta,
+    shutdown: ShutdownSignal,
+    cfg: Arc<Cfg>,
+) -> Result<(), SessionError>
+where
+    IO: 'static + Send + AsyncRead + AsyncWrite,
+    Cfg: Config,
+{
+    let protocol = protocol::session_protocol::<Cfg::Protocol>(protocol)?;
+    let trace = Trace::new(peer, protocol);
+    trace
+        .instrument(run_session(
+            io,
+            tls,
+            protocol,
+            metadata,
+            shutdown,
+            cfg,
+            trace.clone(),
+        ))
+        .await
+}
+
+async fn run_session<IO, Cfg>(
+    io: IO,
+    tls: SessionTls,
+    protocol: ProtocolName,
+    metadata: Cfg::ConnectionUserMeta,
+    shutdown: ShutdownSignal,
+    cfg: Arc<Cfg>,
+    trace: Trace,
+) -> Result<(), SessionError>
+where
+    IO: 'static + Send + AsyncRead + AsyncWrite,
+    Cfg: Config,
+{
+    let (handshake, tls_info) = match tls {
+        SessionTls::Given(IsAlreadyTls::Yes(info)) => (false, Some(info)),
+        SessionTls::Given(IsAlreadyTls::No) => (false, None),
+        SessionTls::Handshake => (true, None),
+    };
     let mut conn_meta = ConnectionMetadata {
         user: metadata,
         hello: None,
-        is_encrypted: is_already_tls == IsAlreadyTls::Yes,
+        protocol,
+        is_encrypted: tls_info.is_some(),
+        tls: tls_info,
+        identity: None,
+        errors: ErrorCounters::default(),
     };
     let mut mail_meta = None;
+    let mut transaction_start = None;
+    let session_start = Instant::now();
+
+    // Boxed once so that the session works with !Unpin streams, the TLS stream
+    // replaces it on STARTTLS
+    let io = if handshake {
+        let (tls_io, tls_info) = cfg
+            .tls_accept(Box::pin(io), &mut conn_meta)
+            .await
+            .map_err(SessionError::TlsFailed)?;
+        conn_meta.is_encrypted = true;
+        conn_meta.tls = Some(tls_info);
+        Either::Right(tls_io)
+    } else {
+        Either::<_, Cfg::TlsStream<Pin<Box<IO>>>>::Left(Box::pin(io))
+    };
 
+    cfg.on_connect(&mut conn_meta).await;
+    authenticate_client_certificate(&*cfg, &mut conn_meta).await;
+    if let Some(tls) = &conn_meta.tls {
+        trace.tls(tls);
+    }
+
+    let observer = Observer::new(cfg.session_observer(&conn_meta), trace);
+    observer.session_started();
+    let io = Tapped::new(io, cfg.transcript_sink(&conn_meta), observer.clone());
+
+    let res = handle_commands(
+        io,
+        &mut conn_meta,
+        &mut mail_meta,
+        &mut transaction_start,
+        &observer,
+        shutdown,
+        &*cfg,
+    )
+    .await;
+
+    if let Some(mail_meta) = mail_meta.take() {
+        cfg.transaction_aborted(
+            mail_meta,
+            TransactionAbortReason::SessionEnded,
+            &mut conn_meta,
+        )
+        .await;
+    }
+    // Also reports transactions whose mail was being handled when the session
+    // ended
+    observer.transaction_finished(
+        &mut transaction_start,
+        TransactionOutcome::Aborted(TransactionAbortReason::SessionEnded),
+    );
+    observer.session_finished(session_start, &res);
+    cfg.on_disconnect(conn_meta, &res).await;
+    res
+}
+
+/// Connection of a session, the plain stream being replaced by the TLS one on
+/// STARTTLS
+type SessionIo<IO, Cfg> = Tapped<Either<Pin<Box<IO>>, <Cfg as Config>::TlsStream<Pin<Box<IO>>>>>;
+
+/// Sends the banner and handles commands until the end of the session
+async fn handle_commands<IO, Cfg>(
+    mut io: SessionIo<IO, Cfg>,
+    conn_meta: &mut ConnectionMetadata<Cfg::ConnectionUserMeta>,
+    mail_meta: &mut Option<MailMetadata<Cfg::MailUserMeta>>,
+    transaction_start: &mut Option<Instant>,
+    observer: &Observer,
+    shutdown: ShutdownSignal,
+    cfg: &Cfg,
+) -> Result<(), SessionError>
+where
+    IO: 'static + Send + AsyncRead + AsyncWrite,
+    Cfg: Config,
+{
+    let protocol = conn_meta.protocol;
+    let rdbuf = &mut [0; RDBUF_SIZE];
+    let mut unhandled = 0..0;
+    // Replies are rendered into this buffer before being written, so that
+    // sending a reply does not allocate once it has grown to the usual size
+    let mut wrbuf = Vec::with_capacity(WRBUF_SIZE);
     let mut waiting_for_command_since = Utc::now();
 
     macro_rules! read_for_command {
         ($e:expr) => {
-            $e.or(async {
+            async { $e.await.map_err(SessionError::from) }.or(async {
                 // TODO: this should be smol::Timer::at, but we would need to convert from
                 // Chrono::DateTime<Utc> to std::time::Instant and I can't find how right now
                 let max_delay: std::time::Duration =
@@ -435,10 +945,7 @@ where
                         .to_std()
                         .unwrap_or(std::time::Duration::from_secs(0));
                 smol::Timer::after(max_delay).await;
-                Err(io::Error::new(
-                    io::ErrorKind::TimedOut,
-                    "timed out waiting for a command",
-                ))
+                Err(SessionError::CommandTimeout)
             })
         };
     }
@@ -447,9 +954,12 @@ where
         ($writer:expr, $reply:expr) => {
             smol::future::or(
                 async {
-                    $writer
-                        .write_all_vectored(&mut $reply.as_io_slices().collect::<Vec<_>>())
-                        .await?;
+                    let reply = $reply;
+                    wrbuf.clear();
+                    reply.write_into(&mut wrbuf);
+                    $writer.write_all(&wrbuf).await?;
+                    // Only replies that made it to the connection are recorded
+                    $writer.sent(reply.code, &wrbuf);
                     waiting_for_command_since = Utc::now();
                     Ok(())
                 },
@@ -460,10 +970,7 @@ where
                             .unwrap_or(std::time::Duration::from_secs(0)),
                     )
                     .await;
-                    Err(io::Error::new(
-                        io::ErrorKind::TimedOut,
-                        "timed out sending a reply",
-                    ))
+                    Err(SessionError::ReplyTimeout)
                 },
             )
         };
@@ -483,18 +990,50 @@ where
             $e:expr,
             Reject($reply_r:pat) => $reject:block
             Accept($reply_a:pat, $res_a:pat) => $accept:block
-        ) => {
-            match $e {
+        ) => {{
+            let decision = $e;
+            if cfg!(debug_assertions) {
+                if let Err(e) = decision.validate() {
+                    panic!("Config returned an invalid decision: {}", e);
+                }
+            }
+            match decision {
                 Decision::Accept { reply: $reply_a, res: $res_a } => $accept,
                 Decision::Reject { reply: $reply_r } => $reject,
                 Decision::Kill { reply, res } => {
-                    if let Some(r) = reply {
+                    if let Some(ref r) = reply {
                         send_reply!(io, r).await?;
                     }
-                    return res;
+                    return res.map_err(|error| SessionError::Killed { reply, error });
                 }
             }
-        };
+        }};
+    }
+
+    // Counts an error made by the client, and replies to it once the tarpit
+    // delay is over, or closes the session if there were too many of them
+    macro_rules! send_error_reply {
+        ($counter:ident, $reply:expr) => {{
+            conn_meta.errors.$counter = conn_meta.errors.$counter.saturating_add(1);
+            if cfg
+                .hard_error_limit()
+                .map(|limit| conn_meta.errors.total() >= limit)
+                .unwrap_or(false)
+            {
+                let reply = cfg.too_many_errors(conn_meta);
+                send_reply!(io, reply).await?;
+                return Ok(());
+            }
+            let delay = cfg
+                .tarpit_delay(conn_meta)
+                .to_std()
+                .unwrap_or(std::time::Duration::from_secs(0));
+            if !delay.is_zero() {
+                smol::Timer::after(delay).await;
+            }
+            let reply = $reply;
+            send_reply!(io, reply).await?;
+        }};
     }
 
     macro_rules! simple_handler {
@@ -508,29 +1047,107 @@ where
         };
     }
 
-    match send_reply!(io, cfg.welcome_banner_reply(&mut conn_meta)).await {
-        Ok(_) => {}
-        Err(err) => return if err.kind() == io::ErrorKind::BrokenPipe {
-            trace!("Client closed connection before sending welcome banner - possibly a health probe");
-            Ok(())
-        } else {
-            Err(err)
-        }
+    macro_rules! close_for_shutdown {
+        ($reason:expr) => {{
+            let reply = cfg.service_shutting_down($reason, conn_meta);
+            send_reply!(io, reply).await?;
+            return Ok(());
+        }};
     }
 
-    loop {
-        if unhandled.is_empty() {
-            unhandled = 0..read_for_command!(io.read(rdbuf)).await?;
-            if unhandled.is_empty() {
-                return Ok(());
+    let greeting_delay = cfg
+        .greeting_delay(conn_meta)
+        .to_std()
+        .unwrap_or(std::time::Duration::from_secs(0));
+    if !greeting_delay.is_zero() {
+        let greeting_end = Instant::now() + greeting_delay;
+        // Anything read here is kept in `unhandled`, to be handled as commands if
+        // early_talker lets the client go on
+        let read = async { Ok::<_, io::Error>(Some(Some(io.read(rdbuf).await?))) }
+            .or(async {
+                smol::Timer::at(greeting_end).await;
+                Ok(Some(None))
+            })
+            .or(async {
+                shutdown.wait().await;
+                Ok(None)
+            })
+            .await?;
+        match read {
+            None => close_for_shutdown!(ShutdownReason::Idle),
+            Some(None) => (),
+            Some(Some(0)) => return Ok(()),
+            Some(Some(read)) => {
+                unhandled = 0..read;
+                if let Some(reply) = cfg.early_talker(conn_meta).await {
+                    send_reply!(io, reply).await?;
+                    return Ok(());
+                }
+                // The banner is still only sent once the delay is over
+                let delay_over = async {
+                    smol::Timer::at(greeting_end).await;
+                    true
+                }
+                .or(async {
+                    shutdown.wait().await;
+                    false
+                })
+                .await;
+                if !delay_over {
+                    close_for_shutdown!(ShutdownReason::Idle);
+                }
             }
         }
+    }
 
-        let cmd = match Command::<&str>::parse(&rdbuf[unhandled.clone()]) {
-            Err(nom::Err::Incomplete(n)) => {
-                // Don't have enough data to handle command, let's fetch more
-                if unhandled.start != 0 {
-                    // Do we have to copy the data to the beginning of the buffer?
+    let (banner, refused, close) = dispatch_decision! {
+        cfg.welcome_banner_reply(conn_meta),
+        Reject(reply) => {
+            let refused = reply.code == ReplyCode::TRANSACTION_FAILED;
+            (reply, refused, !refused)
+        }
+        Accept(reply, ()) => {
+            (reply, false, false)
+        }
+    };
+    match send_reply!(io, banner).await {
+        Ok(_) => {}
+        Err(err) => {
+            return if err.kind() == io::ErrorKind::BrokenPipe {
+                trace!("Client closed connection before sending welcome banner - possibly a health probe");
+                Ok(())
+            } else {
+                Err(err)
+            }
+        }
+    }
+    if close {
+        return Ok(());
+    }
+
+    loop {
+        if shutdown.is_triggered() {
+            close_for_shutdown!(ShutdownReason::Idle);
+        }
+        if unhandled.is_empty() {
+            let read = read_for_command!(async { Ok::<_, io::Error>(Some(io.read(rdbuf).await?)) })
+                .or(async {
+                    shutdown.wait().await;
+                    Ok(None)
+                })
+                .await?;
+            match read {
+                None => close_for_shutdown!(ShutdownReason::Idle),
+                Some(0) => return Ok(()),
+                Some(read) => unhandled = 0..read,
+            }
+        }
+
+        let cmd = match Command::<&str>::parse(&rdbuf[unhandled.clone()]) {
+            Err(nom::Err::Incomplete(n)) => {
+                // Don't have enough data to handle command, let's fetch more
+                if unhandled.start != 0 {
+                    // Do we have to copy the data to the beginning of the buffer?
                     let missing = match n {
                         nom::Needed::Unknown => MINIMUM_FREE_BUFSPACE,
                         nom::Needed::Size(s) => cmp::max(MINIMUM_FREE_BUFSPACE, s.into()),
@@ -546,28 +1163,48 @@ where
                     // basically the full buffer. Which means that we have to
                     // error out that the line is too long.
                     read_for_command!(advance_until_crlf(&mut io, rdbuf, &mut unhandled)).await?;
-                    send_reply!(io, cfg.line_too_long(&mut conn_meta)).await?;
+                    observer.command(None);
+                    if refused {
+                        send_reply!(io, cfg.command_after_refused_banner(conn_meta)).await?;
+                    } else {
+                        send_error_reply!(protocol_errors, cfg.line_too_long(conn_meta));
+                    }
                 } else {
-                    let read = read_for_command!(io.read(&mut rdbuf[unhandled.end..])).await?;
-                    if read == 0 {
-                        return Err(io::Error::new(
-                            io::ErrorKind::ConnectionAborted,
-                            "connection shutdown with partial command",
-                        ));
+                    let read = read_for_command!(async {
+                        Ok::<_, io::Error>(Some(io.read(&mut rdbuf[unhandled.end..]).await?))
+                    })
+                    .or(async {
+                        shutdown.wait().await;
+                        Ok(None)
+                    })
+                    .await?;
+                    match read {
+                        None => close_for_shutdown!(ShutdownReason::Idle),
+                        Some(0) => {
+                            return Err(SessionError::ClientAborted {
+                                phase: SessionPhase::Command,
+                            })
+                        }
+                        Some(read) => unhandled.end += read,
                     }
-                    unhandled.end += read;
                 }
                 None
             }
             Err(_) => {
                 // Syntax error
                 read_for_command!(advance_until_crlf(&mut io, rdbuf, &mut unhandled)).await?;
-                send_reply!(io, cfg.command_unrecognized(&mut conn_meta)).await?;
+                observer.command(None);
+                if refused {
+                    send_reply!(io, cfg.command_after_refused_banner(conn_meta)).await?;
+                } else {
+                    send_error_reply!(protocol_errors, cfg.command_unrecognized(conn_meta));
+                }
                 None
             }
             Ok((rem, cmd)) => {
                 // Got a command
                 unhandled.start = unhandled.end - rem.len();
+                observer.command(Some(cmd.verb()));
                 Some(cmd)
             }
         };
@@ -576,6 +1213,13 @@ where
         // could have been included directly in the Ok((rem, cmd)) branch above.
         // Unfortunately we can't make it a function, because `cmd` borrows `rdbuf`, and
         // we need to use `rdbuf` in the `Command::Data` branch here
+        // The client was told there is no service, so these are not counted as
+        // errors, lest it gets disconnected before sending QUIT
+        if refused && !matches!(cmd, None | Some(Command::Quit)) {
+            send_reply!(io, cfg.command_after_refused_banner(conn_meta)).await?;
+            continue;
+        }
+
         match cmd {
             None => (),
 
@@ -586,17 +1230,18 @@ where
                     Command::Lhlo { hostname } => (ProtocolName::Lmtp, true, hostname),
                     _ => unreachable!(),
                 };
-                if cmd_proto != <Cfg::Protocol as Protocol<'static>>::PROTOCOL {
-                    send_reply!(io, cfg.command_unrecognized(&mut conn_meta)).await?;
+                if cmd_proto != protocol {
+                    send_error_reply!(protocol_errors, cfg.command_unrecognized(conn_meta));
                 } else {
                     match conn_meta.hello {
                         Some(_) => {
-                            send_reply!(io, cfg.already_did_hello(&mut conn_meta)).await?;
+                            send_error_reply!(protocol_errors, cfg.already_did_hello(conn_meta));
                         }
                         None => dispatch_decision! {
-                            cfg.filter_hello(is_extended, hostname.into_owned(), &mut conn_meta)
+                            cfg.filter_hello(is_extended, hostname.into_owned(), conn_meta)
                                 .await,
                             Accept(reply, res) => {
+                                observer.hello(&res);
                                 conn_meta.hello = Some(res);
                                 send_reply!(io, reply).await?;
                             }
@@ -611,30 +1256,47 @@ where
                 params: _params,
             }) => {
                 if conn_meta.hello.is_none() {
-                    send_reply!(io, cfg.mail_before_hello(&mut conn_meta)).await?;
+                    send_error_reply!(protocol_errors, cfg.mail_before_hello(conn_meta));
                 } else {
                     match mail_meta {
                         Some(_) => {
                             // Both postfix and OpenSMTPD just return an error and ignore further
                             // MAIL FROM when there is already a MAIL FROM running
-                            send_reply!(io, cfg.already_in_mail(&mut conn_meta)).await?;
+                            send_error_reply!(protocol_errors, cfg.already_in_mail(conn_meta));
                         }
                         None => {
-                            let mut mail_metadata = MailMetadata {
-                                user: cfg.new_mail(&mut conn_meta).await,
+                            // Stored right away so that the session end reports it if
+                            // filter_from kills the connection
+                            observer.transaction_started(transaction_start);
+                            let mail_metadata = mail_meta.insert(MailMetadata {
+                                user: cfg.new_mail(conn_meta).await,
                                 from: None,
                                 to: Vec::with_capacity(4),
-                            };
+                            });
                             dispatch_decision! {
                                 cfg.filter_from(
                                     email.as_ref().map(|e| e.clone().into_owned()),
-                                    &mut mail_metadata,
-                                    &mut conn_meta,
+                                    mail_metadata,
+                                    conn_meta,
                                 )
                                 .await,
+                                Reject(reply) => {
+                                    let aborted = mail_meta.take().unwrap();
+                                    cfg.transaction_aborted(
+                                        aborted,
+                                        TransactionAbortReason::FromRejected,
+                                        conn_meta,
+                                    )
+                                    .await;
+                                    observer.transaction_finished(
+                                        transaction_start,
+                                        TransactionOutcome::Aborted(TransactionAbortReason::FromRejected),
+                                    );
+                                    send_reply!(io, reply).await?;
+                                }
                                 Accept(reply, res) => {
+                                    observer.mail_from(res.as_ref());
                                     mail_metadata.from = res;
-                                    mail_meta = Some(mail_metadata);
                                     send_reply!(io, reply).await?;
                                 }
                             }
@@ -649,12 +1311,16 @@ where
                 params: _params,
             }) => match mail_meta {
                 None => {
-                    send_reply!(io, cfg.rcpt_before_mail(&mut conn_meta)).await?;
+                    send_error_reply!(protocol_errors, cfg.rcpt_before_mail(conn_meta));
                 }
                 Some(ref mut mail_meta_unw) => dispatch_decision! {
-                    cfg.filter_to(email.into_owned(), mail_meta_unw, &mut conn_meta).await,
+                    cfg.filter_to(email.into_owned(), mail_meta_unw, conn_meta).await,
+                    Reject(reply) => {
+                        send_error_reply!(rejected_recipients, reply);
+                    }
                     Accept(reply, res) => {
                         mail_meta_unw.to.push(res);
+                        observer.recipient_accepted(mail_meta_unw.to.len());
                         send_reply!(io, reply).await?;
                     }
                 },
@@ -662,28 +1328,67 @@ where
 
             Some(Command::Data) => match mail_meta.take() {
                 None => {
-                    send_reply!(io, cfg.data_before_mail(&mut conn_meta)).await?;
+                    send_error_reply!(protocol_errors, cfg.data_before_mail(conn_meta));
                 }
-                Some(ref mail_meta_unw) if mail_meta_unw.to.is_empty() => {
-                    send_reply!(io, cfg.data_before_rcpt(&mut conn_meta)).await?;
+                Some(mail_meta_unw) if mail_meta_unw.to.is_empty() => {
+                    cfg.transaction_aborted(
+                        mail_meta_unw,
+                        TransactionAbortReason::NoRecipients,
+                        conn_meta,
+                    )
+                    .await;
+                    observer.transaction_finished(
+                        transaction_start,
+                        TransactionOutcome::Aborted(TransactionAbortReason::NoRecipients),
+                    );
+                    send_error_reply!(protocol_errors, cfg.data_before_rcpt(conn_meta));
                 }
-                Some(mut mail_meta_unw) => {
+                Some(mail_meta_unw) => {
+                    // Put back until DATA is accepted, so that the transaction is
+                    // kept on reject and reported if filter_data kills the connection
+                    let mail_meta_ref = mail_meta.insert(mail_meta_unw);
                     dispatch_decision! {
-                        cfg.filter_data(&mut mail_meta_unw, &mut conn_meta).await,
-                        Reject(reply) => {
-                            mail_meta = Some(mail_meta_unw);
-                            send_reply!(io, reply).await?;
-                        }
+                        cfg.filter_data(mail_meta_ref, conn_meta).await,
                         Accept(reply, ()) => {
+                            let mail_meta_unw = mail_meta.take().unwrap();
                             send_reply!(io, reply).await?;
+                            let data_start = Instant::now();
+                            // Offset in the stream of the first byte of the mail
+                            let data_offset = io.received() - unhandled.len() as u64;
+                            let mut accepted = false;
                             let mut reader =
                                 EscapedDataReader::new(rdbuf, unhandled.clone(), &mut io);
-                            let expected_n_decisions = match <Cfg::Protocol as Protocol<'static>>::PROTOCOL {
+                            let expected_n_decisions = match protocol {
                                 ProtocolName::Smtp => 1,
                                 ProtocolName::Lmtp => mail_meta_unw.to.len(),
                             };
-                            let mut decision_stream = <Cfg::Protocol as Protocol<'_>>::handle_mail_return_type_as_stream(cfg
-                                .handle_mail(&mut reader, mail_meta_unw, &mut conn_meta).await);
+                            // Only starts counting once shutdown is requested
+                            let shutdown_grace = async {
+                                shutdown.wait().await;
+                                smol::Timer::after(
+                                    cfg.shutdown_grace_period()
+                                        .to_std()
+                                        .unwrap_or(std::time::Duration::from_secs(0)),
+                                )
+                                .await;
+                            };
+                            futures::pin_mut!(shutdown_grace);
+                            let decision_stream = async {
+                                Some(<Cfg::Protocol as Protocol>::handle_mail_return_type_as_stream(
+                                    cfg.handle_mail(&mut reader, mail_meta_unw, conn_meta).await,
+                                ))
+                            }
+                            .or(async {
+                                shutdown_grace.as_mut().await;
+                                None
+                            })
+                            .await;
+                            if decision_stream.is_none() {
+                                // Grace period expired while still handling the mail
+                                drop(decision_stream);
+                                close_for_shutdown!(ShutdownReason::GracePeriodExpired);
+                            }
+                            let mut decision_stream = decision_stream.unwrap();
                             // This variable is a trick because otherwise rustc thinks the `reader`
                             // borrow is still alive across await points and makes `interact: !Send`
                             let reader_was_completed = if let Some(u) = reader.get_unhandled() {
@@ -701,14 +1406,37 @@ where
                                 // Couldn't find the RFC reference
                                 // anywhere, though.
                                 let mut n_decisions = 0;
-                                while let Some(decision) = decision_stream.next().await {
+                                loop {
+                                    let decision = match async { Some(decision_stream.next().await) }
+                                        .or(async {
+                                            shutdown_grace.as_mut().await;
+                                            None
+                                        })
+                                        .await
+                                    {
+                                        Some(Some(decision)) => decision,
+                                        Some(None) => break,
+                                        None => {
+                                            drop(decision_stream);
+                                            close_for_shutdown!(ShutdownReason::GracePeriodExpired);
+                                        }
+                                    };
                                     n_decisions += 1;
                                     if n_decisions > expected_n_decisions {
-                                        panic!("got more decisions in handle_mail return than the expected {}", expected_n_decisions);
+                                        // The client already got all the replies it waits for, so
+                                        // the extra decisions are only counted
+                                        continue;
                                     }
+                                    accepted |= matches!(decision, Decision::Accept { .. });
                                     simple_handler!(decision);
                                 }
-                                assert_eq!(n_decisions, expected_n_decisions, "got {} decisions in handle_mail return, expected {}", n_decisions, expected_n_decisions);
+                                drop(decision_stream);
+                                if n_decisions != expected_n_decisions {
+                                    cfg.wrong_decision_count(expected_n_decisions, n_decisions, conn_meta).await;
+                                    for _i in n_decisions..expected_n_decisions {
+                                        send_reply!(io, cfg.missing_decision(conn_meta)).await?;
+                                    }
+                                }
                             } else {
                                 // handle_mail did not call complete, let's read until the end and
                                 // then return an error
@@ -718,67 +1446,131 @@ where
                                 // timeout here... giving as much time for sending the whole DATA
                                 // message may be a bit too little? but then it only happens when
                                 // handle_mail breaks anyway, so...
-                                while read_for_command!(reader.read(ignore_buf)).await? != 0 {}
+                                loop {
+                                    match read_for_command!(reader.read(ignore_buf)).await {
+                                        Ok(0) => break,
+                                        Ok(_) => (),
+                                        Err(SessionError::Io(e))
+                                            if e.kind() == io::ErrorKind::ConnectionAborted =>
+                                        {
+                                            return Err(SessionError::ClientAborted {
+                                                phase: SessionPhase::Data,
+                                            });
+                                        }
+                                        Err(e) => return Err(e),
+                                    }
+                                }
                                 if !reader.is_finished() {
                                     // Stream cut mid-connection
-                                    return Err(io::Error::new(
-                                        io::ErrorKind::ConnectionAborted,
-                                        "connection shutdown during email reception",
-                                    ));
+                                    return Err(SessionError::ClientAborted {
+                                        phase: SessionPhase::Data,
+                                    });
                                 }
                                 reader.complete();
                                 unhandled = reader.get_unhandled().unwrap();
                                 // TODO: rustc complains if we don't drop(decision_stream) here, why?
                                 drop(decision_stream);
                                 for _i in 0..expected_n_decisions {
-                                    send_reply!(io, cfg.handle_mail_did_not_call_complete(&mut conn_meta)).await?;
+                                    send_reply!(io, cfg.handle_mail_did_not_call_complete(conn_meta)).await?;
                                 }
                             };
+                            observer.data_finished(
+                                data_start,
+                                io.received() - unhandled.len() as u64 - data_offset,
+                            );
+                            observer.transaction_finished(
+                                transaction_start,
+                                if accepted {
+                                    TransactionOutcome::Accepted
+                                } else {
+                                    TransactionOutcome::Rejected
+                                },
+                            );
                         }
                     }
                 }
             },
 
             Some(Command::Rset) => dispatch_decision! {
-                cfg.handle_rset(&mut mail_meta, &mut conn_meta).await,
+                cfg.handle_rset(mail_meta, conn_meta).await,
                 Accept(reply, ()) => {
-                    mail_meta = None;
+                    if let Some(aborted) = mail_meta.take() {
+                        cfg.transaction_aborted(
+                            aborted,
+                            TransactionAbortReason::Rset,
+                            conn_meta,
+                        )
+                        .await;
+                        observer.transaction_finished(
+                            transaction_start,
+                            TransactionOutcome::Aborted(TransactionAbortReason::Rset),
+                        );
+                    }
                     send_reply!(io, reply).await?;
                 }
             },
 
             Some(Command::Starttls) => {
-                if !cfg.can_do_tls(&conn_meta) {
-                    send_reply!(io, cfg.starttls_unsupported(&mut conn_meta)).await?;
+                // Even if can_do_tls allows it, TLS cannot be nested
+                if !cfg.can_do_tls(conn_meta)
+                    || conn_meta.is_encrypted
+                    || matches!(io.inner, Either::Right(_))
+                {
+                    send_error_reply!(protocol_errors, cfg.starttls_unsupported(conn_meta));
                 } else if !unhandled.is_empty() {
-                    send_reply!(io, cfg.pipeline_forbidden_after_starttls(&mut conn_meta)).await?;
+                    send_error_reply!(
+                        protocol_errors,
+                        cfg.pipeline_forbidden_after_starttls(conn_meta)
+                    );
                 } else {
                     dispatch_decision! {
-                        cfg.handle_starttls(&mut conn_meta).await,
+                        cfg.handle_starttls(conn_meta).await,
                         Accept(reply, ()) => {
                             send_reply!(io, reply).await?;
-                            io = cfg.tls_accept(io, &mut conn_meta).await?;
-                            mail_meta = None;
+                            let plain_io = match io.inner {
+                                Either::Left(plain_io) => plain_io,
+                                Either::Right(_) => unreachable!(),
+                            };
+                            let (tls_io, tls_info) = cfg
+                                .tls_accept(plain_io, conn_meta)
+                                .await
+                                .map_err(SessionError::TlsFailed)?;
+                            io.inner = Either::Right(tls_io);
+                            observer.tls_upgraded(&tls_info);
+                            if let Some(aborted) = mail_meta.take() {
+                                cfg.transaction_aborted(
+                                    aborted,
+                                    TransactionAbortReason::Starttls,
+                                    conn_meta,
+                                )
+                                .await;
+                                observer.transaction_finished(
+                                    transaction_start,
+                                    TransactionOutcome::Aborted(TransactionAbortReason::Starttls),
+                                );
+                            }
                             conn_meta.is_encrypted = true;
+                            conn_meta.tls = Some(tls_info);
                             conn_meta.hello = None;
+                            authenticate_client_certificate(cfg, conn_meta).await;
                         }
                     }
                 }
             }
 
             Some(Command::Expn { name }) => {
-                simple_handler!(cfg.handle_expn(name, &mut conn_meta).await)
+                simple_handler!(cfg.handle_expn(name, conn_meta).await)
             }
             Some(Command::Vrfy { name }) => {
-                simple_handler!(cfg.handle_vrfy(name, &mut conn_meta).await)
+                simple_handler!(cfg.handle_vrfy(name, conn_meta).await)
             }
             Some(Command::Help { subject }) => {
-                simple_handler!(cfg.handle_help(subject, &mut conn_meta).await)
+                simple_handler!(cfg.handle_help(subject, conn_meta).await)
             }
             Some(Command::Noop { string }) => {
-                simple_handler!(cfg.handle_noop(string, &mut conn_meta).await)
+                simple_handler!(cfg.handle_noop(string, conn_meta).await)
             }
-            Some(Command::Quit) => simple_handler!(cfg.handle_quit(&mut conn_meta).await),
+            Some(Command::Quit) => simple_handler!(cfg.handle_quit(conn_meta).await),
         }
     }
 }
@@ -792,7 +1584,6 @@ mod tests {
         sync::{Arc, Mutex},
     };
 
-    use async_trait::async_trait;
     use duplexify::Duplex;
     use futures::executor;
 
@@ -809,11 +1600,19 @@ mod tests {
         }
     }
 
+    #[derive(Default)]
     struct TestConfig {
         mails: Arc<Mutex<Vec<(Option<Email>, Vec<Email>, Vec<u8>)>>>,
+        events: Arc<Mutex<Vec<String>>>,
+        greeting_delay: chrono::Duration,
+        error_limits: Option<(u32, u32)>,
+        banner_rejection: Option<Reply>,
+        transcript: Option<transcript::MemorySink>,
+        metrics: Option<Arc<observer::Metrics>>,
+        timeouts: Option<chrono::Duration>,
+        allow_early_talkers: bool,
     }
 
-    #[async_trait]
     impl Config for TestConfig {
         type ConnectionUserMeta = ();
         type MailUserMeta = ();
@@ -823,15 +1622,119 @@ mod tests {
             "test.example.org".into()
         }
 
+        fn welcome_banner_reply(&self, conn_meta: &mut ConnectionMetadata<()>) -> Decision<()> {
+            if let Some(reply) = &self.banner_rejection {
+                Decision::Reject {
+                    reply: reply.clone(),
+                }
+            } else {
+                Decision::Accept {
+                    reply: reply::welcome_banner(self.hostname(conn_meta), "Service ready"),
+                    res: (),
+                }
+            }
+        }
+
+        fn transcript_sink(
+            &self,
+            _conn_meta: &ConnectionMetadata<()>,
+        ) -> Option<Box<dyn TranscriptSink>> {
+            self.transcript
+                .clone()
+                .map(|s| Box::new(s) as Box<dyn TranscriptSink>)
+        }
+
+        fn session_observer(
+            &self,
+            _conn_meta: &ConnectionMetadata<()>,
+        ) -> Option<Arc<dyn SessionObserver>> {
+            self.metrics.clone().map(|m| m as Arc<dyn SessionObserver>)
+        }
+
+        fn greeting_delay(&self, _conn_meta: &ConnectionMetadata<()>) -> chrono::Duration {
+            self.greeting_delay
+        }
+
+        async fn early_talker(&self, _conn_meta: &mut ConnectionMetadata<()>) -> Option<Reply> {
+            self.events.lock().unwrap().push("early talker".into());
+            match self.allow_early_talkers {
+                true => None,
+                false => Some(reply::early_talker().convert()),
+            }
+        }
+
+        fn reply_write_timeout(&self) -> chrono::Duration {
+            self.timeouts.unwrap_or(chrono::Duration::minutes(5))
+        }
+
+        fn command_read_timeout(&self) -> chrono::Duration {
+            self.timeouts.unwrap_or(chrono::Duration::minutes(5))
+        }
+
+        fn soft_error_limit(&self) -> Option<u32> {
+            self.error_limits.map(|(soft, _)| soft)
+        }
+
+        fn hard_error_limit(&self) -> Option<u32> {
+            self.error_limits.map(|(_, hard)| hard)
+        }
+
+        // Same as the default, but with milliseconds instead of seconds
+        fn tarpit_delay(&self, conn_meta: &ConnectionMetadata<()>) -> chrono::Duration {
+            match self.soft_error_limit() {
+                Some(soft) => {
+                    let over = conn_meta.errors.total().saturating_sub(soft);
+                    chrono::Duration::milliseconds(over.into()).min(self.reply_write_timeout())
+                }
+                None => chrono::Duration::zero(),
+            }
+        }
+
         async fn new_mail(&self, _conn_meta: &mut ConnectionMetadata<()>) {}
 
+        async fn on_connect(&self, _conn_meta: &mut ConnectionMetadata<()>) {
+            self.events.lock().unwrap().push("connect".into());
+        }
+
+        async fn transaction_aborted(
+            &self,
+            _meta: MailMetadata<()>,
+            reason: TransactionAbortReason,
+            _conn_meta: &mut ConnectionMetadata<()>,
+        ) {
+            self.events
+                .lock()
+                .unwrap()
+                .push(format!("aborted {:?}", reason));
+        }
+
+        async fn on_disconnect(
+            &self,
+            conn_meta: ConnectionMetadata<()>,
+            res: &Result<(), SessionError>,
+        ) {
+            if let Some(tls) = conn_meta.tls {
+                self.events.lock().unwrap().push(format!(
+                    "tls {:?} as {:?}",
+                    tls.protocol_version, conn_meta.identity
+                ));
+            }
+            self.events
+                .lock()
+                .unwrap()
+                .push(format!("disconnect {}", res.is_ok()));
+        }
+
+        type TlsStream<IO>
+            = IO
+        where
+            IO: 'static + Unpin + Send + AsyncRead + AsyncWrite;
+
         async fn tls_accept<IO>(
             &self,
             mut io: IO,
             _conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
-        ) -> io::Result<
-            duplexify::Duplex<Pin<Box<dyn Send + AsyncRead>>, Pin<Box<dyn Send + AsyncWrite>>>,
-        >
+        ) -> io::Result<(IO, TlsInfo)>
         where
             IO: 'static + Unpin + Send + AsyncRead + AsyncWrite,
         {
@@ -844,8 +1747,32 @@ mod tests {
                 "got TLS handshake that is not <tls client>: {:?}",
                 show_bytes(&buf)
             );
-            let (r, w) = io.split();
-            Ok(duplexify::Duplex::new(Box::pin(r), Box::pin(w)))
+            let info = TlsInfo {
+                protocol_version: Some("fake".into()),
+                peer_certificates: vec![b"<client cert>".to_vec()],
+                verified: true,
+                ..TlsInfo::default()
+            };
+            Ok((io, info))
+        }
+
+        async fn authenticate_client_certificate(
+            &self,
+            conn_meta: &mut ConnectionMetadata<()>,
+        ) -> Option<String> {
+            let tls = conn_meta.tls.as_ref().unwrap();
+            // Real certificates are matched by their subject name, that shows
+            // up as is in the DER encoding
+            let cert = &tls.peer_certificates[0];
+            if cert == b"<client cert>"
+                || cert
+                    .windows(b"partner.example.org".len())
+                    .any(|w| w == b"partner.example.org")
+            {
+                Some("partner".into())
+            } else {
+                None
+            }
         }
 
         async fn filter_from(
@@ -861,7 +1788,7 @@ mod tests {
                     reply: Reply {
                         code: ReplyCode::POLICY_REASON,
                         ecode: None,
-                        text: vec!["User 'bad' banned".into()],
+                        text: vec!["User 'bad' banned".into()].into(),
                     },
                 }
             } else {
@@ -883,7 +1810,7 @@ mod tests {
                     reply: Reply {
                         code: ReplyCode::MAILBOX_UNAVAILABLE,
                         ecode: None,
-                        text: vec!["No user 'baz'".into()],
+                        text: vec!["No user 'baz'".into()].into(),
                     },
                 }
             } else {
@@ -920,7 +1847,7 @@ mod tests {
                     reply: Reply {
                         code: ReplyCode::BAD_SEQUENCE,
                         ecode: None,
-                        text: vec!["Closed the channel before end of message".into()],
+                        text: vec!["Closed the channel before end of message".into()].into(),
                     },
                 }
             } else if mail_text.windows(5).position(|x| x == b"World").is_some() {
@@ -928,7 +1855,7 @@ mod tests {
                     reply: Reply {
                         code: ReplyCode::POLICY_REASON,
                         ecode: None,
-                        text: vec!["Don't you dare say 'World'!".into()],
+                        text: vec!["Don't you dare say 'World'!".into()].into(),
                     },
                 }
             } else {
@@ -944,6 +1871,104 @@ mod tests {
         }
     }
 
+    /// Configuration serving both SMTP and LMTP, whose `handle_mail` returns
+    /// `n_decisions` decisions in LMTP, whatever the number of recipients
+    struct DynamicTestConfig {
+        n_decisions: usize,
+        wrong_counts: Arc<Mutex<Vec<(usize, usize)>>>,
+    }
+
+    impl Config for DynamicTestConfig {
+        type ConnectionUserMeta = ();
+        type MailUserMeta = ();
+        type Protocol = protocol::Dynamic;
+
+        fn hostname(&self, _conn_meta: &ConnectionMetadata<()>) -> &str {
+            "test.example.org"
+        }
+
+        type TlsStream<IO>
+            = IO
+        where
+            IO: 'static + Unpin + Send + AsyncRead + AsyncWrite;
+
+        async fn tls_accept<IO>(
+            &self,
+            _io: IO,
+            _conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
+        ) -> io::Result<(IO, TlsInfo)>
+        where
+            IO: 'static + Unpin + Send + AsyncRead + AsyncWrite,
+        {
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "DynamicTestConfig does not do TLS",
+            ))
+        }
+
+        async fn new_mail(&self, _conn_meta: &mut ConnectionMetadata<()>) {}
+
+        async fn filter_from(
+            &self,
+            addr: Option<Email>,
+            _meta: &mut MailMetadata<()>,
+            _conn_meta: &mut ConnectionMetadata<()>,
+        ) -> Decision<Option<Email>> {
+            Decision::Accept {
+                reply: reply::okay_from().convert(),
+                res: addr,
+            }
+        }
+
+        async fn filter_to(
+            &self,
+            email: Email,
+            _meta: &mut MailMetadata<()>,
+            _conn_meta: &mut ConnectionMetadata<()>,
+        ) -> Decision<Email> {
+            Decision::Accept {
+                reply: reply::okay_to().convert(),
+                res: email,
+            }
+        }
+
+        async fn handle_mail<'resp, R>(
+            &'resp self,
+            reader: &mut EscapedDataReader<'_, R>,
+            _meta: MailMetadata<()>,
+            conn_meta: &'resp mut ConnectionMetadata<()>,
+        ) -> protocol::MailDecisions<'resp>
+        where
+            R: Send + Unpin + AsyncRead,
+        {
+            let mut mail_text = Vec::new();
+            reader
+                .read_to_end(&mut mail_text)
+                .await
+                .expect("reading mail");
+            reader.complete();
+            let accept = || Decision::Accept {
+                reply: reply::okay_mail().convert(),
+                res: (),
+            };
+            match conn_meta.protocol {
+                ProtocolName::Smtp => protocol::MailDecisions::Single(accept()),
+                ProtocolName::Lmtp => protocol::MailDecisions::PerRecipient(Box::pin(
+                    futures::stream::iter((0..self.n_decisions).map(move |_| accept())),
+                )),
+            }
+        }
+
+        async fn wrong_decision_count(
+            &self,
+            expected: usize,
+            got: usize,
+            _conn_meta: &mut ConnectionMetadata<()>,
+        ) {
+            self.wrong_counts.lock().unwrap().push((expected, got));
+        }
+    }
+
     #[test]
     fn interacts_ok() {
         let tests: &[(&[&[u8]], &[u8], &[(Option<&[u8]>, &[&[u8]], &[u8])])] = &[
@@ -1141,6 +2166,7 @@ mod tests {
             let resp_mail = Arc::new(Mutex::new(Vec::new()));
             let cfg = Arc::new(TestConfig {
                 mails: resp_mail.clone(),
+                ..TestConfig::default()
             });
             let (inp_pipe_r, mut inp_pipe_w) = piper::pipe(1024 * 1024);
             let (mut out_pipe_r, out_pipe_w) = piper::pipe(1024 * 1024);
@@ -1199,33 +2225,808 @@ mod tests {
         }
     }
 
-    // Fuzzer-found
     #[test]
-    fn interrupted_data() {
-        let inp: &[u8] = b"MAIL FROM:foo\r\n\
-                           RCPT TO:bar\r\n\
-                           DATA\r\n\
-                           hello";
+    fn lifecycle_hooks() {
+        let inp: &[&[u8]] = &[
+            b"EHLO test\r\n\
+              MAIL FROM:<bad@quux.example.org>\r\n\
+              MAIL FROM:<foo@bar.example.org>\r\n\
+              RSET\r\n\
+              MAIL FROM:<foo@bar.example.org>\r\n\
+              DATA\r\n\
+              MAIL FROM:<foo@bar.example.org>\r\n\
+              STARTTLS\r\n",
+            b"<tls client>",
+            b"EHLO test\r\n\
+              MAIL FROM:<foo@bar.example.org>\r\n\
+              RCPT TO:<foo2@bar.example.org>\r\n",
+        ];
+        let events = Arc::new(Mutex::new(Vec::new()));
         let cfg = Arc::new(TestConfig {
-            mails: Arc::new(Mutex::new(Vec::new())),
+            events: events.clone(),
+            ..TestConfig::default()
         });
         let (inp_pipe_r, mut inp_pipe_w) = piper::pipe(1024 * 1024);
         let (_out_pipe_r, out_pipe_w) = piper::pipe(1024 * 1024);
         let io = Duplex::new(inp_pipe_r, out_pipe_w);
-        let err_kind = executor::block_on(async move {
+        smol::block_on(futures::future::join(
+            async move {
+                for i in inp {
+                    for _ in 0..100usize {
+                        smol::future::yield_now().await;
+                    }
+                    inp_pipe_w
+                        .write_all(i)
+                        .await
+                        .expect("writing to input pipe");
+                }
+            },
+            async move {
+                interact(io, IsAlreadyTls::No, (), cfg)
+                    .await
+                    .expect("calling interact");
+            },
+        ));
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                "connect",
+                "aborted FromRejected",
+                "aborted Rset",
+                "aborted NoRecipients",
+                "aborted Starttls",
+                "aborted SessionEnded",
+                "tls Some(\"fake\") as Some(\"partner\")",
+                "disconnect true",
+            ]
+        );
+    }
+
+    #[test]
+    fn starttls_records_tls_info_and_identity() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let cfg = Arc::new(TestConfig {
+            events: events.clone(),
+            ..TestConfig::default()
+        });
+        let (inp_pipe_r, mut inp_pipe_w) = piper::pipe(1024 * 1024);
+        let (_out_pipe_r, out_pipe_w) = piper::pipe(1024 * 1024);
+        let io = Duplex::new(inp_pipe_r, out_pipe_w);
+        smol::block_on(futures::future::join(
+            async move {
+                for i in [&b"EHLO test\r\nSTARTTLS\r\n"[..], b"<tls client>"] {
+                    for _ in 0..100usize {
+                        smol::future::yield_now().await;
+                    }
+                    inp_pipe_w
+                        .write_all(i)
+                        .await
+                        .expect("writing to input pipe");
+                }
+            },
+            async move {
+                interact(io, IsAlreadyTls::No, (), cfg)
+                    .await
+                    .expect("calling interact");
+            },
+        ));
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                "connect",
+                "tls Some(\"fake\") as Some(\"partner\")",
+                "disconnect true"
+            ]
+        );
+    }
+
+    /// What the client of a session run by `interact_steps` does next
+    enum Step<'a> {
+        /// Sends these bytes, once the server had time to handle the previous
+        /// ones
+        Send(&'a [u8]),
+        /// Triggers the shutdown signal, once the server had time to handle the
+        /// previous step
+        Shutdown,
+        /// Keeps the input open until the session ends, instead of closing it
+        /// after the last step
+        Hold,
+    }
+
+    /// Runs a session with `cfg` for a client at `peer` that goes through
+    /// `steps`, and returns how it ended along with the output, that is read
+    /// only then through a pipe of `out_capacity` bytes
+    fn interact_steps<Cfg>(
+        steps: &[Step<'_>],
+        peer: Option<SocketAddr>,
+        out_capacity: usize,
+        cfg: Arc<Cfg>,
+    ) -> (Result<(), SessionError>, Vec<u8>)
+    where
+        Cfg: Config<ConnectionUserMeta = ()>,
+    {
+        let (shutdown, shutdown_signal) = Shutdown::new();
+        let (inp_pipe_r, mut inp_pipe_w) = piper::pipe(1024 * 1024);
+        let (mut out_pipe_r, out_pipe_w) = piper::pipe(out_capacity);
+        let io = Duplex::new(inp_pipe_r, out_pipe_w);
+        // Dropping the trigger would shut the session down
+        let shutdown = &shutdown;
+        let (_held, res) = smol::block_on(futures::future::join(
+            async move {
+                for step in steps {
+                    for _ in 0..100usize {
+                        smol::future::yield_now().await;
+                    }
+                    match step {
+                        Step::Send(i) => inp_pipe_w
+                            .write_all(i)
+                            .await
+                            .expect("writing to input pipe"),
+                        Step::Shutdown => shutdown.trigger(),
+                        Step::Hold => return Some(inp_pipe_w),
+                    }
+                }
+                None
+            },
+            async move {
+                let res =
+                    interact_with_shutdown(io, IsAlreadyTls::No, peer, (), shutdown_signal, cfg)
+                        .await;
+                let mut resp = Vec::new();
+                out_pipe_r
+                    .read_to_end(&mut resp)
+                    .await
+                    .expect("reading from output pipe");
+                (res, resp)
+            },
+        ));
+        res
+    }
+
+    /// Runs a session with `cfg` for a client at `peer`, sending the chunks of
+    /// `inp` one after the other to leave time for the replies in between, and
+    /// returns the output
+    fn interact_staged<Cfg>(inp: &[&[u8]], peer: Option<SocketAddr>, cfg: Arc<Cfg>) -> Vec<u8>
+    where
+        Cfg: Config<ConnectionUserMeta = ()>,
+    {
+        let steps = inp.iter().map(|i| Step::Send(i)).collect::<Vec<_>>();
+        let (res, resp) = interact_steps(&steps, peer, 1024 * 1024, cfg);
+        res.expect("calling interact");
+        resp
+    }
+
+    #[test]
+    fn records_transcript() {
+        let sink = transcript::MemorySink::new();
+        let cfg = Arc::new(TestConfig {
+            transcript: Some(sink.clone()),
+            ..TestConfig::default()
+        });
+        let inp: &[&[u8]] = &[
+            b"EHLO test\r\n\
+              MAIL FROM:<>\r\n\
+              RCPT TO:<foo2@bar.example.org>\r\n\
+              DATA\r\n",
+            b"Hello world\r\n.\r\nQUIT\r\n",
+        ];
+        let resp = interact_staged(inp, None, cfg);
+
+        let entries = sink.entries();
+        assert!(entries.windows(2).all(|w| w[0].time <= w[1].time));
+        let (mut received, mut sent) = (Vec::new(), Vec::new());
+        for e in &entries {
+            match &e.event {
+                transcript::Event::Received(b) => received.extend_from_slice(b),
+                transcript::Event::Sent(b) => sent.extend_from_slice(b),
+                e => panic!("unexpected event {:?}", e),
+            }
+        }
+        assert_eq!(received, inp.concat());
+        assert_eq!(sent, resp);
+        let body = entries
+            .iter()
+            .position(
+                |e| matches!(&e.event, transcript::Event::Received(b) if b.starts_with(b"Hello")),
+            )
+            .expect("the body was not recorded");
+        assert_eq!(
+            entries[body - 1].event,
+            transcript::Event::Sent(b"354 Start mail input; end with <CRLF>.<CRLF>\r\n"[..].into())
+        );
+        assert_eq!(
+            entries[body].event.direction(),
+            transcript::Direction::Received
+        );
+    }
+
+    #[test]
+    fn unsent_replies_are_not_recorded() {
+        let sink = transcript::MemorySink::new();
+        let cfg = Arc::new(TestConfig {
+            transcript: Some(sink.clone()),
+            ..TestConfig::default()
+        });
+        let (out_pipe_r, out_pipe_w) = piper::pipe(1024 * 1024);
+        // Nobody reads the replies
+        std::mem::drop(out_pipe_r);
+        let io = Duplex::new(futures::io::Cursor::new(b"EHLO test\r\n"), out_pipe_w);
+        let res = executor::block_on(interact(io, IsAlreadyTls::No, (), cfg));
+        assert!(res.is_err());
+        assert!(sink
+            .entries()
+            .iter()
+            .all(|e| !matches!(e.event, transcript::Event::Sent(_))));
+    }
+
+    #[test]
+    fn observes_session() {
+        let inp: &[u8] = b"HELO test\r\n\
+                           MAIL FROM:<bad@quux.example.org>\r\n\
+                           MAIL FROM:<foo@bar.example.org>\r\n\
+                           RCPT TO:<foo2@bar.example.org>\r\n\
+                           DATA\r\n\
+                           Hello\r\n.\r\n\
+                           FOO\r\n\
+                           MAIL FROM:<foo@bar.example.org>\r\n\
+                           QUIT\r\n";
+        let metrics = Arc::new(observer::Metrics::new());
+        let cfg = Arc::new(TestConfig {
+            metrics: Some(metrics.clone()),
+            ..TestConfig::default()
+        });
+        interact_staged(&[inp], None, cfg);
+        let rendered = metrics.render();
+        for line in [
+            "smtp_sessions_total 1".to_string(),
+            format!("smtp_received_bytes_total {}", inp.len()),
+            "smtp_commands_total{verb=\"DATA\"} 1".into(),
+            "smtp_commands_total{verb=\"MAIL\"} 3".into(),
+            "smtp_commands_total{verb=\"invalid\"} 1".into(),
+            "smtp_replies_total{class=\"2xx\"} 7".into(),
+            "smtp_replies_total{class=\"3xx\"} 1".into(),
+            "smtp_replies_total{class=\"5xx\"} 2".into(),
+            "smtp_transactions_total{outcome=\"aborted\"} 2".into(),
+            "smtp_transactions_total{outcome=\"accepted\"} 1".into(),
+            "smtp_transaction_duration_seconds_count 3".into(),
+            "smtp_data_duration_seconds_count 1".into(),
+            "smtp_timeouts_total{kind=\"command\"} 0".into(),
+        ] {
+            assert!(
+                rendered.lines().any(|l| l == line),
+                "missing line {:?}",
+                line
+            );
+        }
+    }
+
+    /// Fields of a span, and the id of its parent
+    #[cfg(feature = "tracing")]
+    type RecordedSpan = (String, Option<u64>);
+
+    /// Id of the span an event happened in, and its fields
+    #[cfg(feature = "tracing")]
+    type RecordedEvent = (Option<u64>, String);
+
+    /// Subscriber keeping the spans, with their parent, and the events, with
+    /// the span they happened in
+    #[cfg(feature = "tracing")]
+    #[derive(Clone, Default)]
+    struct TraceRecorder {
+        spans: Arc<Mutex<Vec<RecordedSpan>>>,
+        events: Arc<Mutex<Vec<RecordedEvent>>>,
+        entered: Arc<Mutex<Vec<u64>>>,
+    }
+
+    #[cfg(feature = "tracing")]
+    struct Fields<'a>(&'a mut String);
+
+    #[cfg(feature = "tracing")]
+    impl tracing::field::Visit for Fields<'_> {
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+            self.0.push_str(&format!(" {}={:?}", field.name(), value));
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    impl tracing::Subscriber for TraceRecorder {
+        fn enabled(&self, _metadata: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            let mut text = span.metadata().name().to_string();
+            span.record(&mut Fields(&mut text));
+            let parent = match span.parent() {
+                Some(p) => Some(p.into_u64()),
+                None => self.entered.lock().unwrap().last().copied(),
+            };
+            let mut spans = self.spans.lock().unwrap();
+            spans.push((text, parent));
+            tracing::span::Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, span: &tracing::span::Id, values: &tracing::span::Record<'_>) {
+            let mut spans = self.spans.lock().unwrap();
+            values.record(&mut Fields(&mut spans[span.into_u64() as usize - 1].0));
+        }
+
+        fn record_follows_from(&self, _span: &tracing::span::Id, _follows: &tracing::span::Id) {}
+
+        fn event(&self, event: &tracing::Event<'_>) {
+            let mut text = String::new();
+            event.record(&mut Fields(&mut text));
+            let span = match event.parent() {
+                Some(p) => Some(p.into_u64()),
+                None => self.entered.lock().unwrap().last().copied(),
+            };
+            self.events.lock().unwrap().push((span, text));
+        }
+
+        fn enter(&self, span: &tracing::span::Id) {
+            self.entered.lock().unwrap().push(span.into_u64());
+        }
+
+        fn exit(&self, _span: &tracing::span::Id) {
+            self.entered.lock().unwrap().pop();
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn traces_session() {
+        let inp: &[u8] = b"HELO test\r\n\
+                           MAIL FROM:<foo@bar.example.org>\r\n\
+                           RCPT TO:<foo2@bar.example.org>\r\n\
+                           DATA\r\n\
+                           Hello\r\n.\r\n\
+                           QUIT\r\n";
+        let recorder = TraceRecorder::default();
+        let cfg = Arc::new(TestConfig::default());
+        let peer = "192.0.2.1:4242".parse().unwrap();
+        tracing::subscriber::with_default(recorder.clone(), || {
+            interact_staged(&[inp], Some(peer), cfg)
+        });
+        let spans = recorder.spans.lock().unwrap();
+        assert_eq!(
+            *spans,
+            vec![
+                (
+                    "smtp_session protocol=Smtp peer=192.0.2.1:4242 helo=test".to_string(),
+                    None
+                ),
+                (
+                    "smtp_transaction recipients=0 from=<foo@bar.example.org> recipients=1 \
+                     size=10 outcome=Accepted"
+                        .to_string(),
+                    Some(1)
+                ),
+            ]
+        );
+        let events = recorder.events.lock().unwrap();
+        let event = |text: &str| {
+            events
+                .iter()
+                .find(|(_, e)| e.contains(text))
+                .unwrap_or_else(|| panic!("no event {:?}", text))
+                .0
+        };
+        assert_eq!(event("command=\"HELO\""), Some(1));
+        assert_eq!(event("command=\"RCPT\""), Some(2));
+        assert_eq!(event("code=354"), Some(2));
+        assert_eq!(event("transaction finished"), Some(2));
+        assert_eq!(event("command=\"QUIT\""), Some(1));
+        assert_eq!(event("reply=221 2.0.0 Bye"), Some(1));
+    }
+
+    #[test]
+    fn early_talker_rejected() {
+        let cfg = Arc::new(TestConfig {
+            greeting_delay: chrono::Duration::seconds(10),
+            ..TestConfig::default()
+        });
+        let resp = interact_staged(&[b"HELO test\r\n"], None, cfg);
+        println!("Got: {:?}", show_bytes(&resp));
+        assert_eq!(
+            resp,
+            b"554 5.5.1 Protocol error: talked before the greeting\r\n"
+        );
+    }
+
+    #[test]
+    fn early_talker_allowed() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let cfg = Arc::new(TestConfig {
+            events: events.clone(),
+            greeting_delay: chrono::Duration::milliseconds(200),
+            allow_early_talkers: true,
+            ..TestConfig::default()
+        });
+        let (inp_pipe_r, mut inp_pipe_w) = piper::pipe(1024 * 1024);
+        let (mut out_pipe_r, out_pipe_w) = piper::pipe(1024 * 1024);
+        let io = Duplex::new(inp_pipe_r, out_pipe_w);
+        let start = Instant::now();
+        let (resp, banner_delay) = executor::block_on(async move {
+            inp_pipe_w
+                .write_all(b"HELO test\r\nQUIT\r\n")
+                .await
+                .expect("writing to input pipe");
+            let session = smol::spawn(interact(io, IsAlreadyTls::No, (), cfg));
+            let mut banner = [0; 36];
+            out_pipe_r
+                .read_exact(&mut banner)
+                .await
+                .expect("reading banner");
+            let banner_delay = start.elapsed();
+            session.await.expect("calling interact");
+            let mut resp = banner.to_vec();
+            out_pipe_r
+                .read_to_end(&mut resp)
+                .await
+                .expect("reading from output pipe");
+            (resp, banner_delay)
+        });
+        assert_eq!(
+            resp,
+            &b"220 test.example.org Service ready\r\n\
+               250 test.example.org\r\n\
+               221 2.0.0 Bye\r\n"[..],
+            "got {:?}",
+            show_bytes(&resp)
+        );
+        assert!(
+            banner_delay >= std::time::Duration::from_millis(200),
+            "banner sent after {:?}",
+            banner_delay
+        );
+        assert_eq!(events.lock().unwrap()[..2], ["connect", "early talker"]);
+    }
+
+    #[test]
+    fn too_many_errors() {
+        let cfg = Arc::new(TestConfig {
+            error_limits: Some((1, 4)),
+            ..TestConfig::default()
+        });
+        let inp: &[u8] = b"EHLO test\r\n\
+                           MAIL FROM:<foo@test.example.org>\r\n\
+                           RCPT TO:<baz@test.example.org>\r\n\
+                           FOOBAR\r\n\
+                           RCPT TO:<baz@test.example.org>\r\n\
+                           FOOBAR\r\n";
+        let start = std::time::Instant::now();
+        let resp = interact_staged(&[inp], None, cfg);
+        println!("Got: {:?}", show_bytes(&resp));
+        assert_eq!(
+            resp,
+            &b"220 test.example.org Service ready\r\n\
+               250-test.example.org\r\n\
+               250-8BITMIME\r\n\
+               250-ENHANCEDSTATUSCODES\r\n\
+               250-PIPELINING\r\n\
+               250-SMTPUTF8\r\n\
+               250 STARTTLS\r\n\
+               250 2.0.0 Okay\r\n\
+               550 No user 'baz'\r\n\
+               500 5.5.1 Command not recognized\r\n\
+               550 No user 'baz'\r\n\
+               421 4.7.0 Too many errors, closing connection\r\n"[..]
+        );
+        // The second and third errors are past the soft limit, and get delayed
+        // by 1ms then 2ms, where the same delay twice would only add up to 2ms
+        assert!(start.elapsed() >= std::time::Duration::from_millis(3));
+    }
+
+    #[test]
+    fn refused_banner() {
+        // Refused mode replies are not errors, so the limits are not reached
+        let cfg = Arc::new(TestConfig {
+            banner_rejection: Some(reply::no_smtp_service().convert()),
+            error_limits: Some((1, 2)),
+            ..TestConfig::default()
+        });
+        let mut inp = b"EHLO test\r\n\
+                        MAIL FROM:<foo@test.example.org>\r\n\
+                        FOOBAR\r\n\
+                        NOOP "
+            .to_vec();
+        inp.extend_from_slice(&[b'a'; RDBUF_SIZE]);
+        inp.extend_from_slice(b"\r\nQUIT\r\n");
+        let resp = interact_staged(&[&inp], None, cfg);
+        println!("Got: {:?}", show_bytes(&resp));
+        assert_eq!(
+            resp,
+            &b"554 5.3.2 No SMTP service here\r\n\
+               503 5.5.1 Bad sequence of commands\r\n\
+               503 5.5.1 Bad sequence of commands\r\n\
+               503 5.5.1 Bad sequence of commands\r\n\
+               503 5.5.1 Bad sequence of commands\r\n\
+               221 2.0.0 Bye\r\n"[..]
+        );
+    }
+
+    #[test]
+    fn rejected_banner_closes() {
+        let cfg = Arc::new(TestConfig {
+            banner_rejection: Some(reply::service_shutting_down().convert()),
+            ..TestConfig::default()
+        });
+        // The input is kept open, so it is the server that ends the session
+        let (res, resp) = interact_steps(&[Step::Hold], None, 1024 * 1024, cfg);
+        res.expect("calling interact");
+        println!("Got: {:?}", show_bytes(&resp));
+        assert_eq!(resp, b"421 4.3.2 Service shutting down\r\n");
+    }
+
+    /// Runs `inp` through a `DynamicTestConfig` speaking `protocol`, returning
+    /// the output along with the calls to `wrong_decision_count`
+    fn interact_dynamic(
+        inp: &[u8],
+        protocol: ProtocolName,
+        n_decisions: usize,
+    ) -> (Vec<u8>, Vec<(usize, usize)>) {
+        let wrong_counts = Arc::new(Mutex::new(Vec::new()));
+        let cfg = Arc::new(DynamicTestConfig {
+            n_decisions,
+            wrong_counts: wrong_counts.clone(),
+        });
+        let (inp_pipe_r, mut inp_pipe_w) = piper::pipe(1024 * 1024);
+        let (mut out_pipe_r, out_pipe_w) = piper::pipe(1024 * 1024);
+        let io = Duplex::new(inp_pipe_r, out_pipe_w);
+        let resp = executor::block_on(async move {
             inp_pipe_w
                 .write_all(inp)
                 .await
                 .expect("writing to input pipe");
-            std::mem::drop(inp_pipe_w);
-            interact(io, IsAlreadyTls::No, (), cfg)
+            interact_with_protocol(
+                io,
+                IsAlreadyTls::No,
+                protocol,
+                None,
+                (),
+                ShutdownSignal::never(),
+                cfg,
+            )
+            .await
+            .expect("calling interact");
+            let mut resp = Vec::new();
+            out_pipe_r
+                .read_to_end(&mut resp)
                 .await
-                .expect_err("calling interact")
-                .kind()
+                .expect("reading from output pipe");
+            resp
         });
+        println!("Got: {:?}", show_bytes(&resp));
+        let wrong_counts = wrong_counts.lock().unwrap().clone();
+        (resp, wrong_counts)
+    }
+
+    #[test]
+    fn wrong_decision_count() {
+        let inp: &[u8] = b"LHLO test\r\n\
+                           MAIL FROM:<>\r\n\
+                           RCPT TO:<foo@test.example.org>\r\n\
+                           RCPT TO:<bar@test.example.org>\r\n\
+                           DATA\r\n\
+                           Hello\r\n\
+                           .\r\n\
+                           QUIT\r\n";
+        let prefix: &[u8] = b"220 test.example.org Service ready\r\n\
+                              250-test.example.org\r\n\
+                              250-8BITMIME\r\n\
+                              250-ENHANCEDSTATUSCODES\r\n\
+                              250-PIPELINING\r\n\
+                              250-SMTPUTF8\r\n\
+                              250 STARTTLS\r\n\
+                              250 2.0.0 Okay\r\n\
+                              250 2.1.5 Okay\r\n\
+                              250 2.1.5 Okay\r\n\
+                              354 Start mail input; end with <CRLF>.<CRLF>\r\n";
+
+        let (resp, wrong_counts) = interact_dynamic(inp, ProtocolName::Lmtp, 1);
+        assert_eq!(
+            resp,
+            [
+                prefix,
+                b"250 2.0.0 Okay\r\n\
+                  451 4.3.5 System incorrectly configured\r\n\
+                  221 2.0.0 Bye\r\n"
+            ]
+            .concat()
+        );
+        assert_eq!(wrong_counts, vec![(2, 1)]);
+
+        let (resp, wrong_counts) = interact_dynamic(inp, ProtocolName::Lmtp, 5);
+        assert_eq!(
+            resp,
+            [
+                prefix,
+                b"250 2.0.0 Okay\r\n\
+                  250 2.0.0 Okay\r\n\
+                  221 2.0.0 Bye\r\n"
+            ]
+            .concat()
+        );
+        assert_eq!(wrong_counts, vec![(2, 5)]);
+    }
+
+    #[test]
+    fn dynamic_protocol() {
+        let transaction: &[u8] = b"MAIL FROM:<>\r\n\
+                                   RCPT TO:<foo@test.example.org>\r\n\
+                                   RCPT TO:<bar@test.example.org>\r\n\
+                                   DATA\r\n\
+                                   Hello\r\n\
+                                   .\r\n\
+                                   QUIT\r\n";
+        let (resp, wrong_counts) = interact_dynamic(
+            &[&b"LHLO test\r\nEHLO test\r\n"[..], transaction].concat(),
+            ProtocolName::Smtp,
+            2,
+        );
+        assert_eq!(
+            resp,
+            &b"220 test.example.org Service ready\r\n\
+               500 5.5.1 Command not recognized\r\n\
+               250-test.example.org\r\n\
+               250-8BITMIME\r\n\
+               250-ENHANCEDSTATUSCODES\r\n\
+               250-PIPELINING\r\n\
+               250-SMTPUTF8\r\n\
+               250 STARTTLS\r\n\
+               250 2.0.0 Okay\r\n\
+               250 2.1.5 Okay\r\n\
+               250 2.1.5 Okay\r\n\
+               354 Start mail input; end with <CRLF>.<CRLF>\r\n\
+               250 2.0.0 Okay\r\n\
+               221 2.0.0 Bye\r\n"[..]
+        );
+        assert!(wrong_counts.is_empty());
+
+        // The same Config also speaks LMTP
+        let (resp, wrong_counts) = interact_dynamic(
+            &[&b"EHLO test\r\nLHLO test\r\n"[..], transaction].concat(),
+            ProtocolName::Lmtp,
+            2,
+        );
+        assert!(resp.starts_with(
+            b"220 test.example.org Service ready\r\n\
+              500 5.5.1 Command not recognized\r\n\
+              250-test.example.org\r\n"
+        ));
+        assert!(resp.ends_with(
+            b"354 Start mail input; end with <CRLF>.<CRLF>\r\n\
+              250 2.0.0 Okay\r\n\
+              250 2.0.0 Okay\r\n\
+              221 2.0.0 Bye\r\n"
+        ));
+        assert!(wrong_counts.is_empty());
+    }
+
+    #[test]
+    fn protocol_mismatch() {
+        // Nothing is read from or written to the connection
+        let dynamic = Arc::new(DynamicTestConfig {
+            n_decisions: 1,
+            wrong_counts: Arc::new(Mutex::new(Vec::new())),
+        });
+        let res = executor::block_on(interact(MinBoundsIo, IsAlreadyTls::No, (), dynamic.clone()));
+        assert!(matches!(
+            res,
+            Err(SessionError::ProtocolMismatch {
+                requested: None,
+                supported: None,
+            })
+        ));
+        let res = executor::block_on(interact_with_protocol(
+            MinBoundsIo,
+            IsAlreadyTls::No,
+            ProtocolName::Lmtp,
+            None,
+            (),
+            ShutdownSignal::never(),
+            Arc::new(TestConfig::default()),
+        ));
+        assert!(matches!(
+            res,
+            Err(SessionError::ProtocolMismatch {
+                requested: Some(ProtocolName::Lmtp),
+                supported: Some(ProtocolName::Smtp),
+            })
+        ));
+
+        // Server checks it once, before accepting any connection
+        let res = smol::block_on(async {
+            Server::new(dynamic, |_| ())
+                .bind("127.0.0.1:0")
+                .await
+                .expect("binding server")
+                .serve()
+                .await
+        });
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    // Fuzzer-found
+    #[test]
+    fn interrupted_data() {
+        let inp: &[u8] = b"MAIL FROM:foo\r\n\
+                           RCPT TO:bar\r\n\
+                           DATA\r\n\
+                           hello";
+        let cfg = Arc::new(TestConfig::default());
+        let (res, _) = interact_steps(&[Step::Send(inp)], None, 1024 * 1024, cfg);
+        let err_kind = res.expect_err("calling interact").kind();
         assert_eq!(err_kind, io::ErrorKind::ConnectionAborted,);
     }
 
+    #[test]
+    fn session_errors() {
+        // Runs a session on `inp`, leaving the input open and the output unread
+        // until it ends, and returns the error it ended with
+        fn session_error(inp: &[u8], out_capacity: usize) -> SessionError {
+            let cfg = Arc::new(TestConfig {
+                timeouts: Some(chrono::Duration::milliseconds(10)),
+                ..TestConfig::default()
+            });
+            let (res, _) = interact_steps(&[Step::Send(inp), Step::Hold], None, out_capacity, cfg);
+            res.expect_err("calling interact")
+        }
+
+        // Input closed right after it was written
+        fn session_error_at_eof(inp: &[u8]) -> SessionError {
+            let cfg = Arc::new(TestConfig::default());
+            let (res, _) = interact_steps(&[Step::Send(inp)], None, 1024 * 1024, cfg);
+            res.expect_err("calling interact")
+        }
+
+        let err = session_error(b"HELO test\r\n", 1024 * 1024);
+        assert!(matches!(err, SessionError::CommandTimeout), "{:?}", err);
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        // The banner does not fit in the output pipe
+        let err = session_error(b"", 16);
+        assert!(matches!(err, SessionError::ReplyTimeout), "{:?}", err);
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        let err = session_error_at_eof(b"HELO te");
+        assert!(
+            matches!(
+                err,
+                SessionError::ClientAborted {
+                    phase: SessionPhase::Command
+                }
+            ),
+            "{:?}",
+            err
+        );
+        let err = session_error_at_eof(
+            b"HELO test\r\n\
+              MAIL FROM:<foo@bar.example.org>\r\n\
+              RCPT TO:<foo2@bar.example.org>\r\n\
+              DATA\r\n\
+              hello",
+        );
+        assert!(
+            matches!(
+                err,
+                SessionError::ClientAborted {
+                    phase: SessionPhase::Data
+                }
+            ),
+            "{:?}",
+            err
+        );
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
+
+        // The client leaves in the middle of the handshake
+        let err = session_error_at_eof(b"EHLO test\r\nSTARTTLS\r\n");
+        match err {
+            SessionError::TlsFailed(e) => assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof),
+            err => panic!("expected a TLS failure, got {:?}", err),
+        }
+    }
+
     // Fuzzer-found
     #[test]
     fn no_stack_overflow() {
@@ -1281,9 +3082,7 @@ mod tests {
               \r\n\r\n\r\n\r\n\r\n\r\n\r\n\r\n\r\n\r\n\r\n\n\r\n\n\r\n\r\n\r\n\r\n\r\n\n\r\n\n\r\
               \r\n\r\n\r\n\r\n\r\n\r\n\r\n\r\n\r\n\r\n\r\n\n\r\n\n\r\n\r\n\r\n\r\n\r\n\n\r\n\n\r\
               \r\n\r\n\r\n\r\n\r\n\r\n\r\n\r\n\r\n\r\n\r\n\n\r\n\n\r\n\r\n\r\n\r\n\r\n\n\r\n\r\n";
-        let cfg = Arc::new(TestConfig {
-            mails: Arc::new(Mutex::new(Vec::new())),
-        });
+        let cfg = Arc::new(TestConfig::default());
         let (inp_pipe_r, mut inp_pipe_w) = piper::pipe(1024 * 1024);
         let (_out_pipe_r, out_pipe_w) = piper::pipe(1024 * 1024);
         let io = Duplex::new(inp_pipe_r, out_pipe_w);
@@ -1299,6 +3098,183 @@ mod tests {
         });
     }
 
+    #[test]
+    fn server_limits_connections() {
+        smol::block_on(async {
+            let cfg = Arc::new(TestConfig::default());
+            let server = Server::new(cfg, |_| ())
+                .bind("127.0.0.1:0")
+                .await
+                .expect("binding test server")
+                .max_connections(1);
+            let addr = server.local_addrs().expect("getting local address")[0];
+            let (shutdown_send, shutdown_recv) = smol::channel::bounded::<()>(1);
+            let server = smol::spawn(server.serve_until(async move {
+                let _ = shutdown_recv.recv().await;
+            }));
+
+            let mut first = smol::net::TcpStream::connect(addr)
+                .await
+                .expect("connecting first client");
+            let mut banner = [0; 36];
+            first.read_exact(&mut banner).await.expect("reading banner");
+            assert_eq!(&banner, b"220 test.example.org Service ready\r\n");
+
+            let mut second = smol::net::TcpStream::connect(addr)
+                .await
+                .expect("connecting second client");
+            let mut resp = Vec::new();
+            second
+                .read_to_end(&mut resp)
+                .await
+                .expect("reading refusal");
+            println!("Got: {:?}", show_bytes(&resp));
+            assert_eq!(resp, b"421 4.7.0 Too many connections\r\n");
+
+            shutdown_send.send(()).await.expect("signalling shutdown");
+            let mut resp = Vec::new();
+            first
+                .read_to_end(&mut resp)
+                .await
+                .expect("reading shutdown reply");
+            assert_eq!(resp, b"421 4.3.2 Service shutting down\r\n");
+            server.await.expect("draining server");
+        });
+    }
+
+    #[test]
+    fn accept_backoff_grows_then_caps() {
+        let mut backoff = std::time::Duration::ZERO;
+        let mut waits = Vec::new();
+        for _ in 0..10 {
+            backoff = server::accept_backoff(backoff);
+            waits.push(backoff.as_millis());
+        }
+        assert_eq!(waits, [10, 20, 40, 80, 160, 320, 640, 1000, 1000, 1000]);
+    }
+
+    #[test]
+    fn server_implicit_tls() {
+        smol::block_on(async {
+            let cfg = Arc::new(TestConfig::default());
+            let server = Server::new(cfg.clone(), |_| ())
+                .bind_tls("127.0.0.1:0")
+                .await
+                .expect("binding test server");
+            let addr = server.local_addrs().expect("getting local address")[0];
+            let (shutdown_send, shutdown_recv) = smol::channel::bounded::<()>(1);
+            let server = smol::spawn(server.serve_until(async move {
+                let _ = shutdown_recv.recv().await;
+            }));
+
+            let mut client = smol::net::TcpStream::connect(addr)
+                .await
+                .expect("connecting client");
+            let mut handshake = [0; 12];
+            client
+                .read_exact(&mut handshake)
+                .await
+                .expect("reading handshake");
+            assert_eq!(&handshake, b"<tls server>");
+            client
+                .write_all(b"<tls client>QUIT\r\n")
+                .await
+                .expect("sending handshake");
+            let mut resp = Vec::new();
+            client
+                .read_to_end(&mut resp)
+                .await
+                .expect("reading replies");
+            assert_eq!(
+                resp,
+                &b"220 test.example.org Service ready\r\n221 2.0.0 Bye\r\n"[..]
+            );
+            shutdown_send.send(()).await.expect("signalling shutdown");
+            server.await.expect("draining server");
+            assert_eq!(
+                cfg.events.lock().unwrap().last().map(|e| &e[..]),
+                Some("disconnect true")
+            );
+            assert!(cfg
+                .events
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|e| e.starts_with("tls Some(\"fake\")")));
+        });
+    }
+
+    #[test]
+    fn shutdown_lets_data_finish() {
+        let resp_mail = Arc::new(Mutex::new(Vec::new()));
+        let cfg = Arc::new(TestConfig {
+            mails: resp_mail.clone(),
+            ..TestConfig::default()
+        });
+        let (res, resp) = interact_steps(
+            &[
+                Step::Send(
+                    b"HELO test\r\n\
+                      MAIL FROM:<foo@bar.example.org>\r\n\
+                      RCPT TO:<foo2@bar.example.org>\r\n\
+                      DATA\r\n\
+                      Hello\r\n",
+                ),
+                Step::Shutdown,
+                Step::Send(b".\r\nNOOP\r\n"),
+            ],
+            None,
+            1024 * 1024,
+            cfg,
+        );
+        res.expect("calling interact");
+        let out: &[u8] = b"220 test.example.org Service ready\r\n\
+                           250 test.example.org\r\n\
+                           250 2.0.0 Okay\r\n\
+                           250 2.1.5 Okay\r\n\
+                           354 Start mail input; end with <CRLF>.<CRLF>\r\n\
+                           250 2.0.0 Okay\r\n\
+                           421 4.3.2 Service shutting down\r\n";
+        println!("Expecting: {:?}", show_bytes(out));
+        println!("Got      : {:?}", show_bytes(&resp));
+        assert_eq!(resp, out);
+        assert_eq!(resp_mail.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn shutdown_interrupts_reads() {
+        // Neither the greeting delay nor a half-sent command keep the session
+        // open once shutdown is requested
+        for (greeting_delay, steps, out) in [
+            (
+                chrono::Duration::minutes(10),
+                &[Step::Shutdown, Step::Hold][..],
+                &b"421 4.3.2 Service shutting down\r\n"[..],
+            ),
+            (
+                chrono::Duration::zero(),
+                &[Step::Send(b"HELO test\r\nNOO"), Step::Shutdown, Step::Hold][..],
+                &b"220 test.example.org Service ready\r\n\
+                   250 test.example.org\r\n\
+                   421 4.3.2 Service shutting down\r\n"[..],
+            ),
+        ] {
+            let cfg = Arc::new(TestConfig {
+                greeting_delay,
+                ..TestConfig::default()
+            });
+            let (res, resp) = interact_steps(steps, None, 1024 * 1024, cfg);
+            res.expect("calling interact");
+            assert_eq!(
+                resp,
+                out,
+                "got {:?}, expected {:?}",
+                show_bytes(&resp),
+                show_bytes(out)
+            );
+        }
+    }
+
     struct MinBoundsIo;
     impl !Sync for MinBoundsIo {}
     impl AsyncRead for MinBoundsIo {
@@ -1334,13 +3310,372 @@ mod tests {
         }
     }
 
-    fn assert_send<T: Send>(_: T) {}
+    #[cfg(feature = "rustls")]
+    fn tls_test_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("smtp-server-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).expect("creating test directory");
+        dir
+    }
+
+    /// Writes a new self-signed certificate for `hostname` in `dir`, and
+    /// returns it along with the paths to its PEM files
+    #[cfg(feature = "rustls")]
+    fn write_self_signed(
+        dir: &std::path::Path,
+        hostname: &str,
+    ) -> (
+        std::path::PathBuf,
+        std::path::PathBuf,
+        rustls_pki_types::CertificateDer<'static>,
+    ) {
+        let generated = rcgen::generate_simple_self_signed(vec![hostname.to_owned()])
+            .expect("generating certificate");
+        let cert = dir.join(format!("{}.crt", hostname));
+        let key = dir.join(format!("{}.key", hostname));
+        std::fs::write(&cert, generated.cert.pem()).expect("writing certificate");
+        std::fs::write(&key, generated.key_pair.serialize_pem()).expect("writing key");
+        (cert, key, generated.cert.der().clone())
+    }
+
+    /// Writes a new CA certificate to `ca_path`, and returns a client
+    /// certificate signed by it along with its key
+    #[cfg(feature = "rustls")]
+    fn write_client_ca(
+        ca_path: &std::path::Path,
+    ) -> (rcgen::Certificate, rustls_pki_types::PrivateKeyDer<'static>) {
+        let ca_key = rcgen::KeyPair::generate().expect("generating CA key");
+        let mut ca_params = rcgen::CertificateParams::new(vec![]).expect("building CA params");
+        ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        let ca = ca_params.self_signed(&ca_key).expect("signing CA");
+        std::fs::write(ca_path, ca.pem()).expect("writing CA certificate");
+        let client_key = rcgen::KeyPair::generate().expect("generating client key");
+        let client = rcgen::CertificateParams::new(vec!["partner.example.org".to_owned()])
+            .expect("building client params")
+            .signed_by(&client_key, &ca, &ca_key)
+            .expect("signing client certificate");
+        let client_key = rustls_pki_types::PrivateKeyDer::try_from(client_key.serialize_der())
+            .expect("parsing client key");
+        (client, client_key)
+    }
+
+    #[cfg(feature = "rustls")]
+    type TlsTestClient = futures_rustls::client::TlsStream<Duplex<piper::Reader, piper::Writer>>;
+
+    #[cfg(feature = "rustls")]
+    type TlsTestServer = tls::TlsStream<Duplex<piper::Reader, piper::Writer>>;
+
+    /// Runs a TLS handshake between `acceptor` and a client asking for
+    /// `hostname`, returning the server and client ends of the connection
+    /// along with the `TlsInfo` seen by the server
+    #[cfg(feature = "rustls")]
+    async fn tls_connect(
+        acceptor: &RustlsAcceptor,
+        hostname: &str,
+        roots: &[rustls_pki_types::CertificateDer<'static>],
+    ) -> (TlsTestServer, TlsInfo, TlsTestClient) {
+        tls_connect_with_cert(acceptor, hostname, roots, None).await
+    }
+
+    /// Same as `tls_connect`, with the client presenting `client_cert` if set
+    #[cfg(feature = "rustls")]
+    async fn tls_connect_with_cert(
+        acceptor: &RustlsAcceptor,
+        hostname: &str,
+        roots: &[rustls_pki_types::CertificateDer<'static>],
+        client_cert: Option<(
+            rustls_pki_types::CertificateDer<'static>,
+            rustls_pki_types::PrivateKeyDer<'static>,
+        )>,
+    ) -> (TlsTestServer, TlsInfo, TlsTestClient) {
+        let (server, client) = tls_handshake(acceptor, hostname, roots, client_cert).await;
+        let (server, info) = server.expect("server-side handshake");
+        (server, info, client.expect("client-side handshake"))
+    }
 
+    /// Same as `tls_connect_with_cert`, returning the results of both sides of
+    /// the handshake instead of expecting it to succeed
+    #[cfg(feature = "rustls")]
+    async fn tls_handshake(
+        acceptor: &RustlsAcceptor,
+        hostname: &str,
+        roots: &[rustls_pki_types::CertificateDer<'static>],
+        client_cert: Option<(
+            rustls_pki_types::CertificateDer<'static>,
+            rustls_pki_types::PrivateKeyDer<'static>,
+        )>,
+    ) -> (
+        io::Result<(TlsTestServer, TlsInfo)>,
+        io::Result<TlsTestClient>,
+    ) {
+        let (c2s_r, c2s_w) = piper::pipe(1024 * 1024);
+        let (s2c_r, s2c_w) = piper::pipe(1024 * 1024);
+        let mut root_store = rustls::RootCertStore::empty();
+        for root in roots {
+            root_store
+                .add(root.clone())
+                .expect("adding root certificate");
+        }
+        let config = rustls::ClientConfig::builder_with_provider(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()
+        .expect("building client config")
+        .with_root_certificates(root_store);
+        let config = match client_cert {
+            Some((cert, key)) => config
+                .with_client_auth_cert(vec![cert], key)
+                .expect("setting client certificate"),
+            None => config.with_no_client_auth(),
+        };
+        let connector = futures_rustls::TlsConnector::from(Arc::new(config));
+        let name = rustls_pki_types::ServerName::try_from(hostname.to_owned())
+            .expect("parsing server name");
+        futures::join!(
+            acceptor.accept(Duplex::new(c2s_r, s2c_w)),
+            connector.connect(name, Duplex::new(s2c_r, c2s_w)),
+        )
+    }
+
+    #[cfg(feature = "rustls")]
     #[test]
-    fn interact_is_send() {
+    fn rustls_sni_and_reload() {
+        let dir = tls_test_dir("rustls-sni");
+        let (default_cert, default_key, default_der) =
+            write_self_signed(&dir, "default.example.org");
+        let (mx_cert, mx_key, mx_der) = write_self_signed(&dir, "mx.example.org");
+        let acceptor = RustlsAcceptor::new(&default_cert, &default_key)
+            .expect("loading default certificate")
+            .sni("MX.example.org", &mx_cert, &mx_key)
+            .expect("loading SNI certificate");
+        let peer_cert = |client: &TlsTestClient| {
+            client
+                .get_ref()
+                .1
+                .peer_certificates()
+                .expect("no peer certificate")[0]
+                .clone()
+        };
+        executor::block_on(async {
+            let roots = vec![default_der.clone(), mx_der.clone()];
+            let (_, info, client) = tls_connect(&acceptor, "mx.example.org", &roots).await;
+            assert_eq!(peer_cert(&client), mx_der);
+            assert_eq!(info.sni.as_deref(), Some("mx.example.org"));
+            assert!(info.protocol_version.is_some());
+            assert!(info.cipher_suite.is_some());
+            assert!(info.peer_certificates.is_empty());
+            let (_, _, client) = tls_connect(&acceptor, "default.example.org", &roots).await;
+            assert_eq!(peer_cert(&client), default_der);
+
+            // Renew the certificate on disk: it is only used after a reload
+            let (_, _, new_mx_der) = write_self_signed(&dir, "mx.example.org");
+            let roots = vec![mx_der.clone(), new_mx_der.clone()];
+            let (_, _, client) = tls_connect(&acceptor, "mx.example.org", &roots).await;
+            assert_eq!(peer_cert(&client), mx_der);
+            acceptor.reload().expect("reloading certificates");
+            let (_, _, client) = tls_connect(&acceptor, "mx.example.org", &roots).await;
+            assert_eq!(peer_cert(&client), new_mx_der);
+
+            // A key that does not match its certificate is not installed
+            let (_, other_key, _) = write_self_signed(&dir, "other.example.org");
+            std::fs::copy(&other_key, &mx_key).expect("overwriting key");
+            let err = acceptor.reload().expect_err("reloading mismatched key");
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            let (_, _, client) = tls_connect(&acceptor, "mx.example.org", &roots).await;
+            assert_eq!(peer_cert(&client), new_mx_der);
+        });
+        let err = RustlsAcceptor::new(&default_cert, &mx_key)
+            .err()
+            .expect("loading mismatched key");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        std::fs::remove_dir_all(&dir).expect("removing test directory");
+    }
+
+    #[cfg(feature = "rustls")]
+    #[test]
+    fn rustls_client_certificate() {
+        let dir = tls_test_dir("rustls-client-cert");
+        let (cert, key, der) = write_self_signed(&dir, "test.example.org");
+        let ca_path = dir.join("ca.crt");
+        let (client, client_key) = write_client_ca(&ca_path);
+
+        let acceptor = RustlsAcceptor::new(&cert, &key)
+            .expect("loading certificate")
+            .client_ca(&ca_path)
+            .expect("loading CA certificate");
+        executor::block_on(async {
+            let roots = vec![der.clone()];
+            let (_, info, _) = tls_connect_with_cert(
+                &acceptor,
+                "test.example.org",
+                &roots,
+                Some((client.der().clone(), client_key)),
+            )
+            .await;
+            assert_eq!(info.peer_certificates, vec![client.der().to_vec()]);
+            assert!(info.verified);
+            // Clients without a certificate are still accepted
+            let (_, info, _) = tls_connect(&acceptor, "test.example.org", &roots).await;
+            assert!(info.peer_certificates.is_empty());
+            assert!(!info.verified);
+            // But not clients with a certificate from another CA
+            let (untrusted, untrusted_key) = write_client_ca(&dir.join("other-ca.crt"));
+            let (server, _) = tls_handshake(
+                &acceptor,
+                "test.example.org",
+                &roots,
+                Some((untrusted.der().clone(), untrusted_key)),
+            )
+            .await;
+            assert!(server.is_err());
+
+            // The CA file is read again on reload
+            let (client, client_key) = write_client_ca(&ca_path);
+            acceptor.reload().expect("reloading certificates");
+            let (_, info, _) = tls_connect_with_cert(
+                &acceptor,
+                "test.example.org",
+                &roots,
+                Some((client.der().clone(), client_key)),
+            )
+            .await;
+            assert_eq!(info.peer_certificates, vec![client.der().to_vec()]);
+        });
+        std::fs::remove_dir_all(&dir).expect("removing test directory");
+    }
+
+    #[cfg(feature = "rustls")]
+    #[test]
+    fn rustls_client_certificate_identity() {
+        let dir = tls_test_dir("rustls-client-identity");
+        let (cert, key, der) = write_self_signed(&dir, "test.example.org");
+        let ca_path = dir.join("ca.crt");
+        let (client, client_key) = write_client_ca(&ca_path);
+        let verifying = RustlsAcceptor::new(&cert, &key)
+            .expect("loading certificate")
+            .client_ca(&ca_path)
+            .expect("loading CA certificate");
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let cfg = Arc::new(TestConfig {
+            events: events.clone(),
+            ..TestConfig::default()
+        });
+        executor::block_on(async {
+            let (server, info, mut client) = tls_connect_with_cert(
+                &verifying,
+                "test.example.org",
+                std::slice::from_ref(&der),
+                Some((client.der().clone(), client_key)),
+            )
+            .await;
+            client
+                .write_all(b"QUIT\r\n")
+                .await
+                .expect("writing to client");
+            client.flush().await.expect("flushing client");
+            let mut resp = Vec::new();
+            let (res, _) = futures::join!(
+                interact(server, IsAlreadyTls::Yes(info.clone()), (), cfg.clone()),
+                client.read_to_end(&mut resp),
+            );
+            res.expect("calling interact");
+
+            // The same certificate, but not verified, for instance because
+            // the acceptor does not check client certificates
+            let (server, _, mut client) = tls_connect(&verifying, "test.example.org", &[der]).await;
+            client
+                .write_all(b"QUIT\r\n")
+                .await
+                .expect("writing to client");
+            client.flush().await.expect("flushing client");
+            let mut resp = Vec::new();
+            let unverified = TlsInfo {
+                verified: false,
+                ..info
+            };
+            let (res, _) = futures::join!(
+                interact(server, IsAlreadyTls::Yes(unverified), (), cfg),
+                client.read_to_end(&mut resp),
+            );
+            res.expect("calling interact");
+        });
+        std::fs::remove_dir_all(&dir).expect("removing test directory");
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                "connect".to_string(),
+                "tls Some(\"TLSv1_3\") as Some(\"partner\")".to_string(),
+                "disconnect true".to_string(),
+                "connect".to_string(),
+                "tls Some(\"TLSv1_3\") as None".to_string(),
+                "disconnect true".to_string(),
+            ]
+        );
+    }
+
+    #[cfg(feature = "rustls")]
+    #[test]
+    fn rustls_implicit_tls() {
+        let dir = tls_test_dir("rustls-implicit");
+        let (cert, key, der) = write_self_signed(&dir, "test.example.org");
+        let acceptor = RustlsAcceptor::new(&cert, &key).expect("loading certificate");
+        let events = Arc::new(Mutex::new(Vec::new()));
         let cfg = Arc::new(TestConfig {
-            mails: Arc::new(Mutex::new(Vec::new())),
+            events: events.clone(),
+            ..TestConfig::default()
         });
+        let resp = executor::block_on(async {
+            let (server, info, mut client) =
+                tls_connect(&acceptor, "test.example.org", &[der]).await;
+            client
+                .write_all(b"EHLO test\r\nSTARTTLS\r\nQUIT\r\n")
+                .await
+                .expect("writing to client");
+            client.flush().await.expect("flushing client");
+            let mut resp = Vec::new();
+            let (res, _) = futures::join!(
+                interact(server, IsAlreadyTls::Yes(info), (), cfg),
+                // interact does not send a close_notify, so this ends with an
+                // unexpected EOF error once all the replies are read
+                client.read_to_end(&mut resp),
+            );
+            res.expect("calling interact");
+            resp
+        });
+        std::fs::remove_dir_all(&dir).expect("removing test directory");
+        println!("Got: {:?}", show_bytes(&resp));
+        assert_eq!(
+            resp,
+            &b"220 test.example.org Service ready\r\n\
+               250-test.example.org\r\n\
+               250-8BITMIME\r\n\
+               250-ENHANCEDSTATUSCODES\r\n\
+               250-PIPELINING\r\n\
+               250 SMTPUTF8\r\n\
+               502 5.5.1 Command not supported\r\n\
+               221 2.0.0 Bye\r\n"[..]
+        );
+        let events = events.lock().unwrap();
+        assert!(events.contains(&"tls Some(\"TLSv1_3\") as None".to_string()));
+    }
+
+    fn assert_send<T: Send>(_: T) {}
+
+    #[test]
+    fn interact_is_send() {
+        let cfg = Arc::new(TestConfig::default());
         assert_send(interact(MinBoundsIo, IsAlreadyTls::No, (), cfg));
+        let cfg = Arc::new(DynamicTestConfig {
+            n_decisions: 1,
+            wrong_counts: Arc::new(Mutex::new(Vec::new())),
+        });
+        assert_send(interact_with_protocol(
+            MinBoundsIo,
+            IsAlreadyTls::No,
+            ProtocolName::Lmtp,
+            None,
+            (),
+            ShutdownSignal::never(),
+            cfg,
+        ));
     }
 }
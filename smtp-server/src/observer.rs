@@ -0,0 +1,452 @@
+//! Hooks for collecting metrics about sessions
+//!
+//! A session reports to the [`SessionObserver`] returned by
+//! [`Config::session_observer`]. [`Metrics`] is an observer keeping counters
+//! and histograms in memory, that renders them in the Prometheus text format.
+//!
+//! [`Config::session_observer`]: crate::Config::session_observer
+
+use std::{
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use smtp_message::{Email, ReplyCode};
+use smtp_server_types::{HelloInfo, TlsInfo};
+
+use crate::{trace::Trace, SessionError, TransactionAbortReason};
+
+/// How a mail transaction ended
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TransactionOutcome {
+    /// `handle_mail` accepted the mail, for at least one recipient in LMTP
+    Accepted,
+
+    /// `handle_mail` rejected the mail, or did not read it
+    Rejected,
+
+    /// The transaction ended before reaching `handle_mail`
+    Aborted(TransactionAbortReason),
+}
+
+/// Receiver of the events of sessions, for instance to update metrics
+///
+/// All the functions do nothing by default.
+#[allow(unused_variables)]
+pub trait SessionObserver: Send + Sync {
+    fn session_started(&self) {}
+
+    /// A command line was received, `verb` being `None` if it could not be
+    /// parsed
+    fn command(&self, verb: Option<&'static str>) {}
+
+    fn reply(&self, code: ReplyCode) {}
+
+    fn bytes_received(&self, len: usize) {}
+
+    /// STARTTLS succeeded
+    fn tls_upgraded(&self) {}
+
+    /// A transaction ended, `duration` after its MAIL FROM
+    fn transaction_finished(&self, duration: Duration, outcome: TransactionOutcome) {}
+
+    /// The decisions on a mail were all sent, `duration` after the reply to
+    /// DATA
+    fn data_finished(&self, duration: Duration) {}
+
+    fn session_finished(&self, duration: Duration, res: &Result<(), SessionError>) {}
+}
+
+/// Forwards the events of a session to its observer, if any, and to its
+/// `tracing` spans
+#[derive(Clone)]
+pub(crate) struct Observer {
+    observer: Option<Arc<dyn SessionObserver>>,
+    trace: Trace,
+}
+
+impl Observer {
+    pub(crate) fn new(observer: Option<Arc<dyn SessionObserver>>, trace: Trace) -> Observer {
+        Observer { observer, trace }
+    }
+
+    pub(crate) fn session_started(&self) {
+        if let Some(o) = &self.observer {
+            o.session_started();
+        }
+    }
+
+    pub(crate) fn command(&self, verb: Option<&'static str>) {
+        self.trace.command(verb);
+        if let Some(o) = &self.observer {
+            o.command(verb);
+        }
+    }
+
+    pub(crate) fn reply(&self, code: ReplyCode, bytes: &[u8]) {
+        self.trace.reply(code, bytes);
+        if let Some(o) = &self.observer {
+            o.reply(code);
+        }
+    }
+
+    pub(crate) fn bytes_received(&self, len: usize) {
+        if let Some(o) = &self.observer {
+            o.bytes_received(len);
+        }
+    }
+
+    pub(crate) fn hello(&self, hello: &HelloInfo) {
+        self.trace.hello(hello);
+    }
+
+    pub(crate) fn tls_upgraded(&self, info: &TlsInfo) {
+        self.trace.tls(info);
+        if let Some(o) = &self.observer {
+            o.tls_upgraded();
+        }
+    }
+
+    /// Records in `start` that a transaction starts now
+    pub(crate) fn transaction_started(&self, start: &mut Option<Instant>) {
+        *start = Some(Instant::now());
+        self.trace.transaction_started();
+    }
+
+    pub(crate) fn mail_from(&self, from: Option<&Email>) {
+        self.trace.mail_from(from);
+    }
+
+    pub(crate) fn recipient_accepted(&self, recipients: usize) {
+        self.trace.recipient_accepted(recipients);
+    }
+
+    /// Reports the transaction started at `start`, if there is one, and clears
+    /// it
+    pub(crate) fn transaction_finished(
+        &self,
+        start: &mut Option<Instant>,
+        outcome: TransactionOutcome,
+    ) {
+        if let Some(start) = start.take() {
+            self.trace.transaction_finished(outcome);
+            if let Some(o) = &self.observer {
+                o.transaction_finished(start.elapsed(), outcome);
+            }
+        }
+    }
+
+    /// Reports the end of the mail that started being received at `start`,
+    /// `size` being the number of bytes it took, escaped
+    pub(crate) fn data_finished(&self, start: Instant, size: u64) {
+        self.trace.data_finished(size);
+        if let Some(o) = &self.observer {
+            o.data_finished(start.elapsed());
+        }
+    }
+
+    pub(crate) fn session_finished(&self, start: Instant, res: &Result<(), SessionError>) {
+        if let Some(o) = &self.observer {
+            o.session_finished(start.elapsed(), res);
+        }
+    }
+}
+
+/// Upper bounds, in seconds, of the buckets of the duration histograms
+pub const DURATION_BUCKETS: &[f64] = &[0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0];
+
+/// Atomic counters of the observations in each bucket, so that sessions
+/// do not contend on a lock
+struct Histogram {
+    /// Number of observations in each bucket, not cumulated
+    counts: [AtomicU64; DURATION_BUCKETS.len() + 1],
+    sum_nanos: AtomicU64,
+}
+
+impl Histogram {
+    fn new() -> Histogram {
+        Histogram {
+            counts: Default::default(),
+            sum_nanos: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, d: Duration) {
+        let secs = d.as_secs_f64();
+        let bucket = DURATION_BUCKETS
+            .iter()
+            .position(|&b| secs <= b)
+            .unwrap_or(DURATION_BUCKETS.len());
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos.fetch_add(
+            u64::try_from(d.as_nanos()).unwrap_or(u64::MAX),
+            Ordering::Relaxed,
+        );
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        let mut cumulated = 0;
+        for (i, count) in self.counts.iter().enumerate() {
+            cumulated += count.load(Ordering::Relaxed);
+            match DURATION_BUCKETS.get(i) {
+                Some(b) => {
+                    let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, b, cumulated);
+                }
+                None => {
+                    let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, cumulated);
+                }
+            }
+        }
+        let sum = self.sum_nanos.load(Ordering::Relaxed) as f64 / 1e9;
+        let _ = writeln!(out, "{}_sum {}", name, sum);
+        let _ = writeln!(out, "{}_count {}", name, cumulated);
+    }
+}
+
+/// Verbs counted by [`Metrics`], as returned by `Command::verb`, followed by
+/// the labels of unparsable commands and of any other verb
+const VERBS: &[&str] = &[
+    "DATA", "EHLO", "EXPN", "HELO", "HELP", "LHLO", "MAIL", "NOOP", "QUIT", "RCPT", "RSET",
+    "STARTTLS", "VRFY", "invalid", "other",
+];
+
+const OUTCOMES: &[&str] = &["accepted", "rejected", "aborted"];
+
+/// In-memory metrics of all the sessions observed
+///
+/// Share it between sessions by returning clones of an `Arc<Metrics>` from
+/// [`Config::session_observer`](crate::Config::session_observer), and expose
+/// [`render`](Metrics::render) to the Prometheus scraper.
+pub struct Metrics {
+    sessions: AtomicU64,
+    /// Commands by verb, in the order of `VERBS`
+    commands: [AtomicU64; VERBS.len()],
+    /// Replies by class, from 1xx to 5xx
+    replies: [AtomicU64; 5],
+    bytes_received: AtomicU64,
+    tls_upgrades: AtomicU64,
+    command_timeouts: AtomicU64,
+    reply_timeouts: AtomicU64,
+    /// Transactions by outcome, in the order of `OUTCOMES`
+    transactions: [AtomicU64; OUTCOMES.len()],
+    transaction_duration: Histogram,
+    data_duration: Histogram,
+}
+
+impl Default for Metrics {
+    fn default() -> Metrics {
+        Metrics {
+            sessions: AtomicU64::new(0),
+            commands: Default::default(),
+            replies: Default::default(),
+            bytes_received: AtomicU64::new(0),
+            tls_upgrades: AtomicU64::new(0),
+            command_timeouts: AtomicU64::new(0),
+            reply_timeouts: AtomicU64::new(0),
+            transactions: Default::default(),
+            transaction_duration: Histogram::new(),
+            data_duration: Histogram::new(),
+        }
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    /// The metrics, in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let counter = |out: &mut String, name: &str, help: &str, value: &AtomicU64| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{} {}", name, value.load(Ordering::Relaxed));
+        };
+        counter(
+            &mut out,
+            "smtp_sessions_total",
+            "Sessions started",
+            &self.sessions,
+        );
+        counter(
+            &mut out,
+            "smtp_received_bytes_total",
+            "Bytes received from clients",
+            &self.bytes_received,
+        );
+        counter(
+            &mut out,
+            "smtp_tls_upgrades_total",
+            "Successful STARTTLS",
+            &self.tls_upgrades,
+        );
+
+        let _ = writeln!(out, "# HELP smtp_commands_total Commands received, by verb");
+        let _ = writeln!(out, "# TYPE smtp_commands_total counter");
+        for (verb, count) in VERBS.iter().zip(&self.commands) {
+            let _ = writeln!(
+                out,
+                "smtp_commands_total{{verb=\"{}\"}} {}",
+                verb,
+                count.load(Ordering::Relaxed)
+            );
+        }
+
+        let _ = writeln!(out, "# HELP smtp_replies_total Replies sent, by class");
+        let _ = writeln!(out, "# TYPE smtp_replies_total counter");
+        for (i, count) in self.replies.iter().enumerate() {
+            let _ = writeln!(
+                out,
+                "smtp_replies_total{{class=\"{}xx\"}} {}",
+                i + 1,
+                count.load(Ordering::Relaxed)
+            );
+        }
+
+        let _ = writeln!(out, "# HELP smtp_timeouts_total Sessions that timed out");
+        let _ = writeln!(out, "# TYPE smtp_timeouts_total counter");
+        for (kind, count) in [
+            ("command", &self.command_timeouts),
+            ("reply", &self.reply_timeouts),
+        ] {
+            let _ = writeln!(
+                out,
+                "smtp_timeouts_total{{kind=\"{}\"}} {}",
+                kind,
+                count.load(Ordering::Relaxed)
+            );
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP smtp_transactions_total Mail transactions, by outcome"
+        );
+        let _ = writeln!(out, "# TYPE smtp_transactions_total counter");
+        for (outcome, count) in OUTCOMES.iter().zip(&self.transactions) {
+            let _ = writeln!(
+                out,
+                "smtp_transactions_total{{outcome=\"{}\"}} {}",
+                outcome,
+                count.load(Ordering::Relaxed)
+            );
+        }
+
+        self.transaction_duration.render(
+            &mut out,
+            "smtp_transaction_duration_seconds",
+            "Time from MAIL FROM to the end of the transaction",
+        );
+        self.data_duration.render(
+            &mut out,
+            "smtp_data_duration_seconds",
+            "Time from the reply to DATA to the last reply to the mail",
+        );
+        out
+    }
+}
+
+impl SessionObserver for Metrics {
+    fn session_started(&self) {
+        self.sessions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn command(&self, verb: Option<&'static str>) {
+        let label = verb.unwrap_or("invalid");
+        let i = VERBS
+            .iter()
+            .position(|&v| v == label)
+            .unwrap_or(VERBS.len() - 1);
+        self.commands[i].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn reply(&self, code: ReplyCode) {
+        if let Some(count) = self
+            .replies
+            .get((code.0[0] as usize).wrapping_sub(b'1' as usize))
+        {
+            count.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn bytes_received(&self, len: usize) {
+        self.bytes_received.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    fn tls_upgraded(&self) {
+        self.tls_upgrades.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn transaction_finished(&self, duration: Duration, outcome: TransactionOutcome) {
+        let i = match outcome {
+            TransactionOutcome::Accepted => 0,
+            TransactionOutcome::Rejected => 1,
+            TransactionOutcome::Aborted(_) => 2,
+        };
+        self.transactions[i].fetch_add(1, Ordering::Relaxed);
+        self.transaction_duration.observe(duration);
+    }
+
+    fn data_finished(&self, duration: Duration) {
+        self.data_duration.observe(duration);
+    }
+
+    fn session_finished(&self, _duration: Duration, res: &Result<(), SessionError>) {
+        match res {
+            Err(SessionError::CommandTimeout) => {
+                self.command_timeouts.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(SessionError::ReplyTimeout) => {
+                self.reply_timeouts.fetch_add(1, Ordering::Relaxed);
+            }
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_histograms() {
+        let metrics = Metrics::new();
+        metrics.data_finished(Duration::from_millis(20));
+        metrics.data_finished(Duration::from_millis(70));
+        metrics.data_finished(Duration::from_secs(1000));
+        metrics.reply(ReplyCode::OKAY);
+        let rendered = metrics.render();
+        let data = rendered
+            .lines()
+            .filter(|l| l.starts_with("smtp_data_duration_seconds"))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            data,
+            vec![
+                "smtp_data_duration_seconds_bucket{le=\"0.01\"} 0",
+                "smtp_data_duration_seconds_bucket{le=\"0.05\"} 1",
+                "smtp_data_duration_seconds_bucket{le=\"0.1\"} 2",
+                "smtp_data_duration_seconds_bucket{le=\"0.5\"} 2",
+                "smtp_data_duration_seconds_bucket{le=\"1\"} 2",
+                "smtp_data_duration_seconds_bucket{le=\"5\"} 2",
+                "smtp_data_duration_seconds_bucket{le=\"10\"} 2",
+                "smtp_data_duration_seconds_bucket{le=\"30\"} 2",
+                "smtp_data_duration_seconds_bucket{le=\"60\"} 2",
+                "smtp_data_duration_seconds_bucket{le=\"300\"} 2",
+                "smtp_data_duration_seconds_bucket{le=\"+Inf\"} 3",
+                "smtp_data_duration_seconds_sum 1000.09",
+                "smtp_data_duration_seconds_count 3",
+            ]
+        );
+        assert!(rendered
+            .lines()
+            .any(|l| l == "smtp_replies_total{class=\"2xx\"} 1"));
+        assert!(rendered.contains("# TYPE smtp_transaction_duration_seconds histogram\n"));
+    }
+}
@@ -1,58 +1,142 @@
-use std::pin::Pin;
+use std::{marker::PhantomData, pin::Pin};
 
-use futures::StreamExt;
+use futures::future::{self, Either};
 
 use smtp_server_types::Decision;
 
-#[derive(PartialEq, Eq, Clone, Copy, Debug)]
-pub enum ProtocolName {
-    Smtp,
-    Lmtp,
-}
+use crate::SessionError;
+
+pub use smtp_server_types::ProtocolName;
+
+/// Stream of decisions returned by `handle_mail` for LMTP, one for each
+/// accepted recipient
+pub type DecisionStream<'resp> = Pin<Box<dyn futures::Stream<Item = Decision<()>> + Send + 'resp>>;
 
-pub trait Protocol<'resp>: private::Sealed {
-    const PROTOCOL: ProtocolName;
+pub trait Protocol: private::Sealed {
+    /// Protocol spoken by all the sessions, or `None` if it is chosen for
+    /// each session when calling
+    /// [`interact_with_protocol`](crate::interact_with_protocol)
+    const PROTOCOL: Option<ProtocolName>;
 
-    // TODO: when we have GATs, 'resp should be a parameter of HandleMailReturnType
-    // and not of the whole Protocol trait.
-    type HandleMailReturnType;
+    /// Type returned by `handle_mail`
+    type HandleMailReturnType<'resp>: Send;
 
-    // TODO: we might be able to remove the Box type here Rust gains GATs (generic
-    // associated types) and TAIT (type Alias = impl Trait) is implemented
-    fn handle_mail_return_type_as_stream(
-        _resp: Self::HandleMailReturnType,
-    ) -> Pin<Box<dyn futures::Stream<Item = Decision<()>> + Send + 'resp>>;
+    /// Decisions taken from a `HandleMailReturnType`, in the order of the
+    /// recipients
+    type Decisions<'resp>: Send + Unpin + futures::Stream<Item = Decision<()>>;
+
+    fn handle_mail_return_type_as_stream<'resp>(
+        resp: Self::HandleMailReturnType<'resp>,
+    ) -> Self::Decisions<'resp>;
 }
 
 pub struct Smtp;
-impl<'resp> Protocol<'resp> for Smtp {
-    type HandleMailReturnType = Decision<()>;
+impl Protocol for Smtp {
+    type HandleMailReturnType<'resp> = Decision<()>;
+    type Decisions<'resp> = futures::stream::Once<future::Ready<Decision<()>>>;
 
-    const PROTOCOL: ProtocolName = ProtocolName::Smtp;
+    const PROTOCOL: Option<ProtocolName> = Some(ProtocolName::Smtp);
 
-    fn handle_mail_return_type_as_stream(
-        resp: Self::HandleMailReturnType,
-    ) -> Pin<Box<dyn futures::Stream<Item = Decision<()>> + Send + 'resp>> {
-        futures::stream::once(async move { resp }).boxed()
+    fn handle_mail_return_type_as_stream<'resp>(
+        resp: Self::HandleMailReturnType<'resp>,
+    ) -> Self::Decisions<'resp> {
+        futures::stream::once(future::ready(resp))
     }
 }
 
-pub struct Lmtp;
-impl<'resp> Protocol<'resp> for Lmtp {
-    // TODO: same as above, GAT+TAIT might allow us to remove Box here
-    type HandleMailReturnType = Pin<Box<dyn futures::Stream<Item = Decision<()>> + Send + 'resp>>;
+/// LMTP, with `handle_mail` returning the stream of decisions chosen through
+/// `S`
+///
+/// By default this is a [`DecisionStream`], which costs an allocation for
+/// each mail. `Config`s that can name their stream type can avoid it by
+/// implementing [`LmtpDecisions`] and using `Lmtp<TheirDecisions>`.
+pub struct Lmtp<S = BoxedDecisions>(PhantomData<S>);
+impl<S> Protocol for Lmtp<S>
+where
+    S: LmtpDecisions,
+{
+    type HandleMailReturnType<'resp> = S::Stream<'resp>;
+    type Decisions<'resp> = S::Stream<'resp>;
 
-    const PROTOCOL: ProtocolName = ProtocolName::Lmtp;
+    const PROTOCOL: Option<ProtocolName> = Some(ProtocolName::Lmtp);
 
-    fn handle_mail_return_type_as_stream(
-        resp: Self::HandleMailReturnType,
-    ) -> Pin<Box<dyn futures::Stream<Item = Decision<()>> + Send + 'resp>> {
+    fn handle_mail_return_type_as_stream<'resp>(
+        resp: Self::HandleMailReturnType<'resp>,
+    ) -> Self::Decisions<'resp> {
         resp
     }
 }
 
+/// Type of the stream of decisions returned by `handle_mail` for [`Lmtp`]
+pub trait LmtpDecisions {
+    type Stream<'resp>: Send + Unpin + futures::Stream<Item = Decision<()>>;
+}
+
+/// [`DecisionStream`], the default [`LmtpDecisions`]
+pub struct BoxedDecisions;
+impl LmtpDecisions for BoxedDecisions {
+    type Stream<'resp> = DecisionStream<'resp>;
+}
+
+/// Protocol chosen at runtime, so that a single `Config` can serve both SMTP
+/// and LMTP sessions
+///
+/// `handle_mail` can look at `conn_meta.protocol` to know which kind of
+/// [`MailDecisions`] is expected. LMTP decisions are always a
+/// [`DecisionStream`] here.
+pub struct Dynamic;
+impl Protocol for Dynamic {
+    type HandleMailReturnType<'resp> = MailDecisions<'resp>;
+    type Decisions<'resp> = Either<<Smtp as Protocol>::Decisions<'resp>, DecisionStream<'resp>>;
+
+    const PROTOCOL: Option<ProtocolName> = None;
+
+    fn handle_mail_return_type_as_stream<'resp>(
+        resp: Self::HandleMailReturnType<'resp>,
+    ) -> Self::Decisions<'resp> {
+        match resp {
+            MailDecisions::Single(decision) => {
+                Either::Left(Smtp::handle_mail_return_type_as_stream(decision))
+            }
+            MailDecisions::PerRecipient(stream) => Either::Right(stream),
+        }
+    }
+}
+
+/// Return type of `handle_mail` for the [`Dynamic`] protocol
+pub enum MailDecisions<'resp> {
+    /// One decision for the whole mail, as in SMTP
+    Single(Decision<()>),
+
+    /// One decision for each accepted recipient, as in LMTP
+    PerRecipient(DecisionStream<'resp>),
+}
+
+/// Protocol to run a session with, given the one asked for when calling
+/// `interact`
+///
+/// Fails if `requested` does not match the static protocol of `P`, or if
+/// nothing was requested for [`Dynamic`].
+pub(crate) fn session_protocol<P>(
+    requested: Option<ProtocolName>,
+) -> Result<ProtocolName, SessionError>
+where
+    P: Protocol,
+{
+    match (P::PROTOCOL, requested) {
+        (Some(fixed), None) => Ok(fixed),
+        (Some(fixed), Some(requested)) if fixed == requested => Ok(fixed),
+        (None, Some(requested)) => Ok(requested),
+        (supported, requested) => Err(SessionError::ProtocolMismatch {
+            requested,
+            supported,
+        }),
+    }
+}
+
 mod private {
     pub trait Sealed {}
     impl Sealed for super::Smtp {}
-    impl Sealed for super::Lmtp {}
+    impl<S> Sealed for super::Lmtp<S> {}
+    impl Sealed for super::Dynamic {}
 }
@@ -0,0 +1,387 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures::{io::AsyncWriteExt, StreamExt};
+use log::{debug, warn};
+use smol::{
+    future::FutureExt,
+    net::{AsyncToSocketAddrs, TcpListener, TcpStream},
+};
+
+use crate::{interact_session, protocol, Config, IsAlreadyTls, ProtocolName, SessionTls, Shutdown};
+
+/// Bounds of the wait after a failed `accept`, that doubles on each failure in
+/// a row
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Wait before the next `accept` after a failed one, `previous` being the wait
+/// before that failure, or zero if the `accept` before it succeeded
+pub(crate) fn accept_backoff(previous: Duration) -> Duration {
+    (previous * 2).clamp(MIN_ACCEPT_BACKOFF, MAX_ACCEPT_BACKOFF)
+}
+
+type MakeConnMeta<M> = Box<dyn Send + Sync + Fn(SocketAddr) -> M>;
+
+/// TCP server that accepts connections on one or more listeners and runs
+/// [`interact`](crate::interact) for each of them on the smol executor.
+///
+/// Connections above the configured limits are answered with
+/// [`Config::too_many_connections`](crate::Config::too_many_connections) and
+/// closed right away.
+pub struct Server<Cfg: Config> {
+    cfg: Arc<Cfg>,
+    make_conn_meta: MakeConnMeta<Cfg::ConnectionUserMeta>,
+    /// Listeners along with whether they use implicit TLS
+    listeners: Vec<(TcpListener, bool)>,
+    max_connections: Option<usize>,
+    max_connections_per_ip: Option<usize>,
+    protocol: Option<ProtocolName>,
+    drain_timeout: Option<chrono::Duration>,
+}
+
+impl<Cfg> Server<Cfg>
+where
+    Cfg: 'static + Config,
+{
+    /// `make_conn_meta` is called with the peer address of each accepted
+    /// connection, to build the `ConnectionUserMeta` passed to `interact`.
+    pub fn new<F>(cfg: Arc<Cfg>, make_conn_meta: F) -> Server<Cfg>
+    where
+        F: 'static + Send + Sync + Fn(SocketAddr) -> Cfg::ConnectionUserMeta,
+    {
+        Server {
+            cfg,
+            make_conn_meta: Box::new(make_conn_meta),
+            listeners: Vec::new(),
+            max_connections: None,
+            max_connections_per_ip: None,
+            protocol: None,
+            drain_timeout: None,
+        }
+    }
+
+    pub async fn bind<A>(self, addr: A) -> io::Result<Server<Cfg>>
+    where
+        A: AsyncToSocketAddrs,
+    {
+        Ok(self.listen(TcpListener::bind(addr).await?))
+    }
+
+    pub fn listen(mut self, listener: TcpListener) -> Server<Cfg> {
+        self.listeners.push((listener, false));
+        self
+    }
+
+    /// Same as [`bind`](Server::bind), but for implicit TLS: the TLS handshake
+    /// is done with [`Config::tls_accept`](crate::Config::tls_accept) before
+    /// the banner is sent
+    pub async fn bind_tls<A>(self, addr: A) -> io::Result<Server<Cfg>>
+    where
+        A: AsyncToSocketAddrs,
+    {
+        Ok(self.listen_tls(TcpListener::bind(addr).await?))
+    }
+
+    /// Same as [`listen`](Server::listen), but for implicit TLS: the TLS
+    /// handshake is done with
+    /// [`Config::tls_accept`](crate::Config::tls_accept) before the banner is
+    /// sent
+    pub fn listen_tls(mut self, listener: TcpListener) -> Server<Cfg> {
+        self.listeners.push((listener, true));
+        self
+    }
+
+    /// Maximum number of sessions running at the same time, across all the
+    /// listeners
+    pub fn max_connections(mut self, max: usize) -> Server<Cfg> {
+        self.max_connections = Some(max);
+        self
+    }
+
+    /// Maximum number of sessions running at the same time for a single
+    /// peer IP address
+    pub fn max_connections_per_ip(mut self, max: usize) -> Server<Cfg> {
+        self.max_connections_per_ip = Some(max);
+        self
+    }
+
+    /// Protocol spoken on all the listeners, required if the `Config` uses the
+    /// [`Dynamic`](crate::protocol::Dynamic) protocol. To serve both SMTP and
+    /// LMTP, run one `Server` for each with the same `Config`.
+    pub fn protocol(mut self, protocol: ProtocolName) -> Server<Cfg> {
+        self.protocol = Some(protocol);
+        self
+    }
+
+    /// Maximum time [`serve_until`](Server::serve_until) waits for the
+    /// running sessions to terminate once shutdown is requested
+    ///
+    /// Defaults to
+    /// [`Config::shutdown_grace_period`](crate::Config::shutdown_grace_period)
+    /// plus [`Config::reply_write_timeout`](crate::Config::reply_write_timeout),
+    /// which is enough for sessions whose `Config` hooks do not hang.
+    pub fn drain_timeout(mut self, timeout: chrono::Duration) -> Server<Cfg> {
+        self.drain_timeout = Some(timeout);
+        self
+    }
+
+    pub fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        self.listeners.iter().map(|(l, _)| l.local_addr()).collect()
+    }
+
+    /// Accept connections forever
+    pub async fn serve(self) -> io::Result<()> {
+        self.serve_until(futures::future::pending()).await
+    }
+
+    /// Accept connections until `shutdown` resolves, then stop listening, ask
+    /// the running sessions to shut down and wait for all of them to terminate
+    /// before returning
+    ///
+    /// Returns an error of kind [`InvalidInput`](io::ErrorKind::InvalidInput)
+    /// right away if the [`protocol`](Server::protocol) does not suit the
+    /// `Config`, and one of kind [`TimedOut`](io::ErrorKind::TimedOut) if some
+    /// sessions are still running after the
+    /// [`drain_timeout`](Server::drain_timeout). They are then left running
+    /// in the background.
+    pub async fn serve_until<F>(self, shutdown: F) -> io::Result<()>
+    where
+        F: Future<Output = ()>,
+    {
+        let Server {
+            cfg,
+            make_conn_meta,
+            listeners,
+            max_connections,
+            max_connections_per_ip,
+            protocol,
+            drain_timeout,
+        } = self;
+        // Better fail now than in each session
+        protocol::session_protocol::<Cfg::Protocol>(protocol)?;
+        let counts = Arc::new(Mutex::new(ConnectionCounts {
+            total: 0,
+            per_ip: HashMap::new(),
+        }));
+        // Each session holds a clone of `drain_send`, so that `drain_recv` errors
+        // out once all of them have completed
+        let (drain_send, drain_recv) = smol::channel::bounded::<()>(1);
+        let (session_shutdown, session_shutdown_signal) = Shutdown::new();
+
+        let mut incoming =
+            futures::stream::select_all(listeners.into_iter().map(|(listener, implicit_tls)| {
+                futures::stream::unfold(listener, move |l| async move {
+                    let res = l.accept().await;
+                    Some(((res, implicit_tls), l))
+                })
+                .boxed()
+            }));
+        futures::pin_mut!(shutdown);
+        let mut backoff = Duration::ZERO;
+
+        loop {
+            let (res, implicit_tls) = match incoming
+                .next()
+                .or(async {
+                    shutdown.as_mut().await;
+                    None
+                })
+                .await
+            {
+                Some(accepted) => accepted,
+                None => break,
+            };
+            let (stream, peer_addr) = match res {
+                Ok(accepted) => {
+                    backoff = Duration::ZERO;
+                    accepted
+                }
+                Err(e) => {
+                    // Errors like running out of file descriptors last a while,
+                    // so wait before trying again rather than spin on them
+                    backoff = accept_backoff(backoff);
+                    warn!(
+                        "Failed accepting a connection, retrying in {:?}: {}",
+                        backoff, e
+                    );
+                    let shutdown_requested = async {
+                        smol::Timer::after(backoff).await;
+                        false
+                    }
+                    .or(async {
+                        shutdown.as_mut().await;
+                        true
+                    })
+                    .await;
+                    if shutdown_requested {
+                        break;
+                    }
+                    continue;
+                }
+            };
+
+            let guard = ConnectionGuard::acquire(
+                &counts,
+                peer_addr.ip(),
+                max_connections,
+                max_connections_per_ip,
+            );
+            let cfg = cfg.clone();
+            let drain_send = drain_send.clone();
+            match guard {
+                None => {
+                    debug!(
+                        "Refusing connection from {}: too many connections",
+                        peer_addr
+                    );
+                    smol::spawn(async move {
+                        refuse_connection(stream, peer_addr, &*cfg).await;
+                        drop(drain_send);
+                    })
+                    .detach();
+                }
+                Some(guard) => {
+                    let conn_meta = make_conn_meta(peer_addr);
+                    let shutdown = session_shutdown_signal.clone();
+                    smol::spawn(async move {
+                        let tls = match implicit_tls {
+                            true => SessionTls::Handshake,
+                            false => SessionTls::Given(IsAlreadyTls::No),
+                        };
+                        let res = interact_session(
+                            stream,
+                            tls,
+                            protocol,
+                            Some(peer_addr),
+                            conn_meta,
+                            shutdown,
+                            cfg,
+                        )
+                        .await;
+                        if let Err(e) = res {
+                            debug!("Session with {} terminated with an error: {}", peer_addr, e);
+                        }
+                        drop(guard);
+                        drop(drain_send);
+                    })
+                    .detach();
+                }
+            }
+        }
+
+        // Stop listening before waiting for the in-flight sessions
+        drop(incoming);
+        session_shutdown.trigger();
+        drop(drain_send);
+        let drain_timeout = drain_timeout
+            .unwrap_or_else(|| cfg.shutdown_grace_period() + cfg.reply_write_timeout())
+            .to_std()
+            .unwrap_or(std::time::Duration::from_secs(0));
+        let drained = async {
+            let _ = drain_recv.recv().await;
+            true
+        }
+        .or(async {
+            smol::Timer::after(drain_timeout).await;
+            false
+        })
+        .await;
+        if !drained {
+            let running = counts
+                .lock()
+                .expect("connection counts mutex poisoned")
+                .total;
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("{} sessions still running after the drain timeout", running),
+            ));
+        }
+        Ok(())
+    }
+}
+
+async fn refuse_connection<Cfg>(mut stream: TcpStream, peer_addr: SocketAddr, cfg: &Cfg)
+where
+    Cfg: Config,
+{
+    let reply = cfg.too_many_connections(&peer_addr);
+    let mut buf = Vec::new();
+    reply.write_into(&mut buf);
+    let res = async {
+        stream.write_all(&buf).await?;
+        stream.close().await
+    }
+    .or(async {
+        smol::Timer::after(
+            cfg.reply_write_timeout()
+                .to_std()
+                .unwrap_or(std::time::Duration::from_secs(0)),
+        )
+        .await;
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "timed out sending a reply",
+        ))
+    })
+    .await;
+    if let Err(e) = res {
+        debug!("Failed refusing connection from {}: {}", peer_addr, e);
+    }
+}
+
+struct ConnectionCounts {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+/// Counts as one running session until dropped
+struct ConnectionGuard {
+    counts: Arc<Mutex<ConnectionCounts>>,
+    ip: IpAddr,
+}
+
+impl ConnectionGuard {
+    fn acquire(
+        counts: &Arc<Mutex<ConnectionCounts>>,
+        ip: IpAddr,
+        max_connections: Option<usize>,
+        max_connections_per_ip: Option<usize>,
+    ) -> Option<ConnectionGuard> {
+        let mut c = counts.lock().expect("connection counts mutex poisoned");
+        let for_ip = c.per_ip.get(&ip).copied().unwrap_or(0);
+        if max_connections.map(|m| c.total >= m).unwrap_or(false)
+            || max_connections_per_ip.map(|m| for_ip >= m).unwrap_or(false)
+        {
+            return None;
+        }
+        c.total += 1;
+        c.per_ip.insert(ip, for_ip + 1);
+        Some(ConnectionGuard {
+            counts: counts.clone(),
+            ip,
+        })
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut c = self
+            .counts
+            .lock()
+            .expect("connection counts mutex poisoned");
+        c.total -= 1;
+        if let Some(n) = c.per_ip.get_mut(&self.ip) {
+            *n -= 1;
+            if *n == 0 {
+                c.per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
@@ -0,0 +1,63 @@
+use smol::channel::{self, Receiver, Sender};
+
+/// Handle used to ask sessions started with
+/// [`interact_with_shutdown`](crate::interact_with_shutdown) to terminate.
+///
+/// Note: dropping the `Shutdown` also triggers it.
+pub struct Shutdown {
+    send: Sender<()>,
+}
+
+/// Receiving end of a [`Shutdown`](Shutdown), which can be cloned to be
+/// given to any number of sessions
+#[derive(Clone)]
+pub struct ShutdownSignal {
+    recv: Option<Receiver<()>>,
+}
+
+/// Reason given to [`Config::service_shutting_down`] when a session is
+/// closed because of a [`Shutdown`](Shutdown)
+///
+/// [`Config::service_shutting_down`]: crate::Config::service_shutting_down
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ShutdownReason {
+    /// The session was waiting for, reading, or about to handle a command,
+    /// or was in its greeting delay
+    Idle,
+
+    /// The session was inside DATA, and the message did not finish being
+    /// handled before the end of the grace period
+    GracePeriodExpired,
+}
+
+impl Shutdown {
+    pub fn new() -> (Shutdown, ShutdownSignal) {
+        let (send, recv) = channel::bounded(1);
+        (Shutdown { send }, ShutdownSignal { recv: Some(recv) })
+    }
+
+    pub fn trigger(&self) {
+        self.send.close();
+    }
+}
+
+impl ShutdownSignal {
+    /// A signal that never triggers
+    pub fn never() -> ShutdownSignal {
+        ShutdownSignal { recv: None }
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.recv.as_ref().map(|r| r.is_closed()).unwrap_or(false)
+    }
+
+    /// Resolves once the associated `Shutdown` has been triggered
+    pub async fn wait(&self) {
+        match self.recv {
+            // Nothing is ever sent on the channel, so this only returns once it
+            // is closed
+            Some(ref recv) => while recv.recv().await.is_ok() {},
+            None => futures::future::pending().await,
+        }
+    }
+}
@@ -0,0 +1,283 @@
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use futures::io::{AsyncRead, AsyncWrite};
+use futures_rustls::TlsAcceptor;
+use rustls::{
+    crypto::ring,
+    server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier},
+    sign::CertifiedKey,
+    RootCertStore, ServerConfig, ServerConnection,
+};
+use rustls_pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+
+use crate::TlsInfo;
+
+pub use futures_rustls::server::TlsStream;
+
+/// Ready-made implementation of [`Config::tls_accept`](crate::Config::tls_accept)
+/// based on rustls, with certificates loaded from PEM files.
+///
+/// For STARTTLS, `tls_accept` can just forward to [`accept`](Self::accept).
+/// For implicit TLS, either use [`Server::bind_tls`](crate::Server::bind_tls),
+/// or call `accept` on the freshly accepted connection and give the stream to
+/// [`interact`](crate::interact) with
+/// [`IsAlreadyTls::Yes`](crate::IsAlreadyTls::Yes) and the returned
+/// [`TlsInfo`].
+///
+/// The certificates are only read from disk when building the acceptor and
+/// on [`reload`](Self::reload), so that they can be renewed without
+/// restarting the server.
+///
+/// Client certificates are only requested once [`client_ca`](Self::client_ca)
+/// is set.
+pub struct RustlsAcceptor {
+    default: PemFiles,
+    by_name: Vec<(String, PemFiles)>,
+    client_ca: Option<PathBuf>,
+    resolver: Arc<CertResolver>,
+    /// Rebuilt on reload when `client_ca` is set, so that the CA file is read
+    /// again
+    acceptor: RwLock<TlsAcceptor>,
+}
+
+impl RustlsAcceptor {
+    /// `cert` is the certificate chain to serve to clients that do not send
+    /// SNI, or send a name that was not configured with
+    /// [`sni`](Self::sni), and `key` its private key
+    pub fn new<C, K>(cert: C, key: K) -> io::Result<RustlsAcceptor>
+    where
+        C: Into<PathBuf>,
+        K: Into<PathBuf>,
+    {
+        let default = PemFiles {
+            cert: cert.into(),
+            key: key.into(),
+        };
+        let resolver = Arc::new(CertResolver {
+            certs: RwLock::new(Certs {
+                default: default.load()?,
+                by_name: HashMap::new(),
+            }),
+        });
+        let acceptor = server_config(&resolver, None)?;
+        Ok(RustlsAcceptor {
+            default,
+            by_name: Vec::new(),
+            client_ca: None,
+            resolver,
+            acceptor: RwLock::new(acceptor),
+        })
+    }
+
+    /// Ask clients for a certificate, and verify the ones they present
+    /// against the CA certificates in the `ca` PEM file. Clients that do not
+    /// present any certificate are still accepted, as most MTAs never do.
+    ///
+    /// The verified chain ends up in
+    /// [`TlsInfo::peer_certificates`](crate::TlsInfo::peer_certificates), to be
+    /// mapped to an identity by
+    /// [`Config::authenticate_client_certificate`](crate::Config::authenticate_client_certificate).
+    pub fn client_ca<P>(mut self, ca: P) -> io::Result<RustlsAcceptor>
+    where
+        P: Into<PathBuf>,
+    {
+        let ca = ca.into();
+        self.acceptor = RwLock::new(server_config(&self.resolver, Some(&ca))?);
+        self.client_ca = Some(ca);
+        Ok(self)
+    }
+
+    /// Serve `cert` to clients that ask for `hostname` through SNI
+    pub fn sni<C, K>(mut self, hostname: &str, cert: C, key: K) -> io::Result<RustlsAcceptor>
+    where
+        C: Into<PathBuf>,
+        K: Into<PathBuf>,
+    {
+        let hostname = hostname.to_ascii_lowercase();
+        let files = PemFiles {
+            cert: cert.into(),
+            key: key.into(),
+        };
+        let loaded = files.load()?;
+        self.resolver
+            .certs
+            .write()
+            .expect("certificates lock poisoned")
+            .by_name
+            .insert(hostname.clone(), loaded);
+        self.by_name.retain(|(h, _)| *h != hostname);
+        self.by_name.push((hostname, files));
+        Ok(self)
+    }
+
+    /// Read all the certificates and keys again from disk, along with the
+    /// [`client_ca`](Self::client_ca) file. Handshakes started after this
+    /// returns use the new certificates.
+    ///
+    /// If any of the files fails to load, the previous certificates are all
+    /// kept.
+    pub fn reload(&self) -> io::Result<()> {
+        let acceptor = match &self.client_ca {
+            Some(ca) => Some(server_config(&self.resolver, Some(ca))?),
+            None => None,
+        };
+        let certs = Certs {
+            default: self.default.load()?,
+            by_name: self
+                .by_name
+                .iter()
+                .map(|(h, files)| Ok((h.clone(), files.load()?)))
+                .collect::<io::Result<_>>()?,
+        };
+        *self
+            .resolver
+            .certs
+            .write()
+            .expect("certificates lock poisoned") = certs;
+        if let Some(acceptor) = acceptor {
+            *self.acceptor.write().expect("acceptor lock poisoned") = acceptor;
+        }
+        Ok(())
+    }
+
+    /// Run the TLS handshake on `io`. For STARTTLS, set
+    /// [`Config::TlsStream<IO>`](crate::Config::TlsStream) to
+    /// [`TlsStream<IO>`](TlsStream).
+    pub async fn accept<IO>(&self, io: IO) -> io::Result<(TlsStream<IO>, TlsInfo)>
+    where
+        IO: Unpin + AsyncRead + AsyncWrite,
+    {
+        let acceptor = self
+            .acceptor
+            .read()
+            .expect("acceptor lock poisoned")
+            .clone();
+        let stream = acceptor.accept(io).await?;
+        let info = tls_info(stream.get_ref().1, self.client_ca.is_some());
+        Ok((stream, info))
+    }
+}
+
+/// Builds the acceptor for the certificates of `resolver`, verifying client
+/// certificates against the CA certificates in the `client_ca` PEM file if
+/// there is one
+fn server_config(
+    resolver: &Arc<CertResolver>,
+    client_ca: Option<&Path>,
+) -> io::Result<TlsAcceptor> {
+    let provider = Arc::new(ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(io::Error::other)?;
+    let config = match client_ca {
+        None => builder
+            .with_no_client_auth()
+            .with_cert_resolver(resolver.clone()),
+        Some(ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in CertificateDer::pem_file_iter(ca).map_err(|e| invalid_pem(ca, e))? {
+                roots
+                    .add(cert.map_err(|e| invalid_pem(ca, e))?)
+                    .map_err(|e| invalid_pem(ca, e))?;
+            }
+            if roots.is_empty() {
+                return Err(invalid_pem(ca, "no certificate found"));
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .allow_unauthenticated()
+                .build()
+                .map_err(io::Error::other)?;
+            builder
+                .with_client_cert_verifier(verifier)
+                .with_cert_resolver(resolver.clone())
+        }
+    };
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Only client certificates checked against a client CA are marked as verified
+fn tls_info(conn: &ServerConnection, verifies_clients: bool) -> TlsInfo {
+    let peer_certificates: Vec<Vec<u8>> = conn
+        .peer_certificates()
+        .unwrap_or(&[])
+        .iter()
+        .map(|c| c.to_vec())
+        .collect();
+    TlsInfo {
+        protocol_version: conn.protocol_version().map(|v| {
+            v.as_str()
+                .map(String::from)
+                .unwrap_or_else(|| format!("{:?}", v))
+        }),
+        cipher_suite: conn.negotiated_cipher_suite().map(|s| {
+            let s = s.suite();
+            s.as_str()
+                .map(String::from)
+                .unwrap_or_else(|| format!("{:?}", s))
+        }),
+        sni: conn.server_name().map(String::from),
+        verified: verifies_clients && !peer_certificates.is_empty(),
+        peer_certificates,
+    }
+}
+
+#[derive(Clone, Debug)]
+struct PemFiles {
+    cert: PathBuf,
+    key: PathBuf,
+}
+
+impl PemFiles {
+    fn load(&self) -> io::Result<Arc<CertifiedKey>> {
+        let chain = CertificateDer::pem_file_iter(&self.cert)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| invalid_pem(&self.cert, e))?;
+        if chain.is_empty() {
+            return Err(invalid_pem(&self.cert, "no certificate found"));
+        }
+        let key = PrivateKeyDer::from_pem_file(&self.key).map_err(|e| invalid_pem(&self.key, e))?;
+        let key = ring::sign::any_supported_type(&key).map_err(|e| invalid_pem(&self.key, e))?;
+        let certified = CertifiedKey::new(chain, key);
+        // Otherwise every handshake would fail once the pair is installed
+        certified.keys_match().map_err(|e| {
+            invalid_pem(
+                &self.key,
+                format_args!("does not match {}: {}", self.cert.display(), e),
+            )
+        })?;
+        Ok(Arc::new(certified))
+    }
+}
+
+fn invalid_pem(path: &Path, e: impl std::fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("failed loading {}: {}", path.display(), e),
+    )
+}
+
+#[derive(Debug)]
+struct Certs {
+    default: Arc<CertifiedKey>,
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+}
+
+#[derive(Debug)]
+struct CertResolver {
+    certs: RwLock<Certs>,
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let certs = self.certs.read().expect("certificates lock poisoned");
+        let by_name = client_hello
+            .server_name()
+            .and_then(|name| certs.by_name.get(&name.to_ascii_lowercase()));
+        Some(by_name.unwrap_or(&certs.default).clone())
+    }
+}
@@ -0,0 +1,229 @@
+//! `tracing` instrumentation of sessions, doing nothing without the `tracing`
+//! feature
+//!
+//! Each session gets an `smtp_session` span, with the peer address, the TLS
+//! protocol version and the HELO hostname, and each mail transaction a child
+//! `smtp_transaction` span, with the sender, the number of accepted
+//! recipients, the size of the mail and how the transaction ended. Commands
+//! and replies are events of the innermost of these spans, which is also
+//! entered while the `Config` hooks run, so that their own events are
+//! correlated with the session.
+
+#[cfg(feature = "tracing")]
+pub(crate) use enabled::Trace;
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) use disabled::Trace;
+
+#[cfg(feature = "tracing")]
+mod enabled {
+    use std::{
+        future::Future,
+        net::SocketAddr,
+        pin::Pin,
+        sync::{Arc, Mutex},
+        task::{Context, Poll},
+    };
+
+    use smtp_message::{Email, ReplyCode};
+    use smtp_server_types::{HelloInfo, TlsInfo};
+    use tracing::{field, Span};
+
+    use crate::{observer::TransactionOutcome, ProtocolName};
+
+    #[derive(Clone)]
+    pub(crate) struct Trace {
+        session: Span,
+        /// Span of the open transaction, if any
+        transaction: Arc<Mutex<Option<Span>>>,
+    }
+
+    impl Trace {
+        pub(crate) fn new(peer: Option<SocketAddr>, protocol: ProtocolName) -> Trace {
+            let session = tracing::info_span!(
+                "smtp_session",
+                peer = field::Empty,
+                protocol = ?protocol,
+                tls = field::Empty,
+                helo = field::Empty,
+            );
+            if let Some(peer) = peer {
+                session.record("peer", field::display(peer));
+            }
+            Trace {
+                session,
+                transaction: Arc::new(Mutex::new(None)),
+            }
+        }
+
+        fn transaction(&self) -> std::sync::MutexGuard<'_, Option<Span>> {
+            self.transaction.lock().expect("failed to lock mutex")
+        }
+
+        /// Runs `fut` in the spans of the session
+        ///
+        /// All the other functions must be called from within `fut`.
+        pub(crate) fn instrument<F: Future>(&self, fut: F) -> impl Future<Output = F::Output> {
+            Traced {
+                fut: Box::pin(fut),
+                trace: self.clone(),
+            }
+        }
+
+        pub(crate) fn tls(&self, info: &TlsInfo) {
+            let version = info.protocol_version.as_deref().unwrap_or("unknown");
+            self.session.record("tls", version);
+        }
+
+        pub(crate) fn hello(&self, hello: &HelloInfo) {
+            self.session.record("helo", field::display(&hello.hostname));
+        }
+
+        pub(crate) fn command(&self, verb: Option<&'static str>) {
+            match verb {
+                Some(verb) => tracing::debug!(command = verb, "received command"),
+                None => tracing::debug!("received invalid command"),
+            }
+        }
+
+        pub(crate) fn reply(&self, code: ReplyCode, bytes: &[u8]) {
+            tracing::debug!(
+                code = code.code(),
+                reply = %bytes.trim_ascii_end().escape_ascii(),
+                "sent reply"
+            );
+        }
+
+        pub(crate) fn transaction_started(&self) {
+            let span = tracing::info_span!(
+                parent: &self.session,
+                "smtp_transaction",
+                from = field::Empty,
+                recipients = 0,
+                size = field::Empty,
+                outcome = field::Empty,
+            );
+            // Entered right away, as the commands pipelined after MAIL FROM
+            // are handled in the same poll
+            enter(&span);
+            *self.transaction() = Some(span);
+        }
+
+        fn record<V: field::Value>(&self, field: &str, value: V) {
+            if let Some(span) = &*self.transaction() {
+                span.record(field, value);
+            }
+        }
+
+        pub(crate) fn mail_from(&self, from: Option<&Email>) {
+            match from {
+                Some(from) => self.record("from", field::display(from)),
+                None => self.record("from", "<>"),
+            }
+        }
+
+        pub(crate) fn recipient_accepted(&self, recipients: usize) {
+            self.record("recipients", recipients);
+        }
+
+        pub(crate) fn data_finished(&self, size: u64) {
+            self.record("size", size);
+        }
+
+        pub(crate) fn transaction_finished(&self, outcome: TransactionOutcome) {
+            if let Some(span) = self.transaction().take() {
+                span.record("outcome", field::debug(outcome));
+                tracing::info!(outcome = ?outcome, "transaction finished");
+                exit(&span);
+            }
+        }
+    }
+
+    // Spans are entered and exited by hand rather than with guards, so that
+    // the transaction span can be entered and exited in the middle of a poll
+    fn enter(span: &Span) {
+        span.with_subscriber(|(id, dispatch)| dispatch.enter(id));
+    }
+
+    fn exit(span: &Span) {
+        span.with_subscriber(|(id, dispatch)| dispatch.exit(id));
+    }
+
+    struct Traced<F> {
+        fut: Pin<Box<F>>,
+        trace: Trace,
+    }
+
+    /// Exits the spans of the session when dropped, even on panic
+    struct Entered<'a>(&'a Trace);
+
+    impl<'a> Entered<'a> {
+        fn new(trace: &'a Trace) -> Entered<'a> {
+            enter(&trace.session);
+            if let Some(span) = &*trace.transaction() {
+                enter(span);
+            }
+            Entered(trace)
+        }
+    }
+
+    impl Drop for Entered<'_> {
+        fn drop(&mut self) {
+            if let Some(span) = &*self.0.transaction() {
+                exit(span);
+            }
+            exit(&self.0.session);
+        }
+    }
+
+    impl<F: Future> Future for Traced<F> {
+        type Output = F::Output;
+
+        fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+            let this = self.get_mut();
+            let _entered = Entered::new(&this.trace);
+            this.fut.as_mut().poll(cx)
+        }
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+mod disabled {
+    use std::{future::Future, net::SocketAddr};
+
+    use smtp_message::{Email, ReplyCode};
+    use smtp_server_types::{HelloInfo, TlsInfo};
+
+    use crate::{observer::TransactionOutcome, ProtocolName};
+
+    #[derive(Clone)]
+    pub(crate) struct Trace;
+
+    impl Trace {
+        pub(crate) fn new(_peer: Option<SocketAddr>, _protocol: ProtocolName) -> Trace {
+            Trace
+        }
+
+        pub(crate) fn instrument<F: Future>(&self, fut: F) -> F {
+            fut
+        }
+
+        pub(crate) fn tls(&self, _info: &TlsInfo) {}
+
+        pub(crate) fn hello(&self, _hello: &HelloInfo) {}
+
+        pub(crate) fn command(&self, _verb: Option<&'static str>) {}
+
+        pub(crate) fn reply(&self, _code: ReplyCode, _bytes: &[u8]) {}
+
+        pub(crate) fn transaction_started(&self) {}
+
+        pub(crate) fn mail_from(&self, _from: Option<&Email>) {}
+
+        pub(crate) fn recipient_accepted(&self, _recipients: usize) {}
+
+        pub(crate) fn data_finished(&self, _size: u64) {}
+
+        pub(crate) fn transaction_finished(&self, _outcome: TransactionOutcome) {}
+    }
+}
@@ -0,0 +1,729 @@
+//! Byte-level transcripts of sessions, for debugging delivery disputes
+//!
+//! A session is recorded when [`Config::transcript_sink`] returns a
+//! [`TranscriptSink`]. The sink is given every chunk read from the client and
+//! every reply sent, after the credentials sent in AUTH exchanges have been
+//! redacted and DATA bodies have been truncated to
+//! [`TranscriptSink::max_data_len`] bytes.
+//!
+//! DATA bodies are recognized as the input following a 354 reply, which
+//! clients must wait for before sending the body. A client that sends it
+//! earlier will have it recorded as commands, without truncation.
+//!
+//! [`Config::transcript_sink`]: crate::Config::transcript_sink
+
+use std::{
+    borrow::Cow,
+    cmp::min,
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::Path,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use futures::{AsyncRead, AsyncWrite};
+use log::error;
+use smtp_message::ReplyCode;
+
+use crate::observer::Observer;
+
+/// Default for [`TranscriptSink::max_data_len`]
+pub const DEFAULT_MAX_DATA_LEN: usize = 1024;
+
+const DATA_END: &[u8] = b"\r\n.\r\n";
+const AUTH: &[u8] = b"AUTH ";
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Direction {
+    /// From the client to the server
+    Received,
+
+    /// From the server to the client
+    Sent,
+}
+
+/// Part of a transcript
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Event<'a> {
+    /// Bytes read from the client
+    Received(Cow<'a, [u8]>),
+
+    /// Bytes read from the client were left out as they are AUTH credentials
+    Redacted,
+
+    /// This many bytes of a DATA body were left out
+    Truncated(usize),
+
+    /// A reply, as sent to the client
+    Sent(Cow<'a, [u8]>),
+}
+
+impl Event<'_> {
+    pub fn direction(&self) -> Direction {
+        match self {
+            Event::Received(_) | Event::Redacted | Event::Truncated(_) => Direction::Received,
+            Event::Sent(_) => Direction::Sent,
+        }
+    }
+
+    pub fn into_owned(self) -> Event<'static> {
+        match self {
+            Event::Received(b) => Event::Received(Cow::Owned(b.into_owned())),
+            Event::Redacted => Event::Redacted,
+            Event::Truncated(n) => Event::Truncated(n),
+            Event::Sent(b) => Event::Sent(Cow::Owned(b.into_owned())),
+        }
+    }
+}
+
+/// Receiver of the transcript of a session
+pub trait TranscriptSink: Send {
+    fn record(&mut self, time: DateTime<Utc>, event: Event<'_>);
+
+    /// Called once the session is over
+    fn finish(&mut self) {}
+
+    /// Number of bytes of each DATA body that are recorded, the end marker
+    /// being always recorded
+    fn max_data_len(&self) -> usize {
+        DEFAULT_MAX_DATA_LEN
+    }
+}
+
+/// Event of a transcript, as recorded by a [`MemorySink`] or read back by
+/// [`read_file`]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Entry {
+    pub time: DateTime<Utc>,
+    pub event: Event<'static>,
+}
+
+/// Sink keeping the transcript in memory
+///
+/// Clones share the same transcript, so that one can be given to the session
+/// while another is kept to read it.
+#[derive(Clone, Debug)]
+pub struct MemorySink {
+    entries: Arc<Mutex<Vec<Entry>>>,
+    max_data_len: usize,
+}
+
+impl Default for MemorySink {
+    fn default() -> MemorySink {
+        MemorySink {
+            entries: Arc::default(),
+            max_data_len: DEFAULT_MAX_DATA_LEN,
+        }
+    }
+}
+
+impl MemorySink {
+    pub fn new() -> MemorySink {
+        MemorySink::default()
+    }
+
+    pub fn with_max_data_len(mut self, max_data_len: usize) -> MemorySink {
+        self.max_data_len = max_data_len;
+        self
+    }
+
+    pub fn entries(&self) -> Vec<Entry> {
+        self.entries.lock().expect("failed to lock mutex").clone()
+    }
+}
+
+impl TranscriptSink for MemorySink {
+    fn record(&mut self, time: DateTime<Utc>, event: Event<'_>) {
+        self.entries
+            .lock()
+            .expect("failed to lock mutex")
+            .push(Entry {
+                time,
+                event: event.into_owned(),
+            });
+    }
+
+    fn max_data_len(&self) -> usize {
+        self.max_data_len
+    }
+}
+
+/// Sink writing the transcript to a file, one event per line
+///
+/// Each line is the RFC 3339 time of the event, then a tag and a payload
+/// separated by spaces:
+/// - `C <bytes>` for bytes received from the client,
+/// - `S <bytes>` for bytes sent by the server,
+/// - `R` for redacted bytes,
+/// - `T <count>` for truncated bytes.
+///
+/// Bytes are written as ASCII, with `\r`, `\n`, `\\` and `\xNN` escapes for
+/// anything else.
+pub struct FileSink {
+    file: BufWriter<File>,
+    failed: bool,
+    max_data_len: usize,
+}
+
+impl FileSink {
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<FileSink> {
+        Ok(FileSink {
+            file: BufWriter::new(File::create(path)?),
+            failed: false,
+            max_data_len: DEFAULT_MAX_DATA_LEN,
+        })
+    }
+
+    pub fn with_max_data_len(mut self, max_data_len: usize) -> FileSink {
+        self.max_data_len = max_data_len;
+        self
+    }
+
+    fn write(&mut self, time: DateTime<Utc>, event: &Event<'_>) -> io::Result<()> {
+        let time = time.to_rfc3339_opts(SecondsFormat::Micros, true);
+        match event {
+            Event::Received(b) => {
+                write!(self.file, "{} C ", time)?;
+                write_escaped(&mut self.file, b)?;
+            }
+            Event::Sent(b) => {
+                write!(self.file, "{} S ", time)?;
+                write_escaped(&mut self.file, b)?;
+            }
+            Event::Redacted => write!(self.file, "{} R", time)?,
+            Event::Truncated(n) => write!(self.file, "{} T {}", time, n)?,
+        }
+        self.file.write_all(b"\n")
+    }
+
+    fn fail(&mut self, e: io::Error) {
+        // Only logged once, the rest of the transcript is dropped
+        error!("Failed writing transcript: {}", e);
+        self.failed = true;
+    }
+}
+
+impl TranscriptSink for FileSink {
+    fn record(&mut self, time: DateTime<Utc>, event: Event<'_>) {
+        if !self.failed {
+            if let Err(e) = self.write(time, &event) {
+                self.fail(e);
+            }
+        }
+    }
+
+    fn finish(&mut self) {
+        if !self.failed {
+            if let Err(e) = self.file.flush() {
+                self.fail(e);
+            }
+        }
+    }
+
+    fn max_data_len(&self) -> usize {
+        self.max_data_len
+    }
+}
+
+fn write_escaped(w: &mut impl Write, bytes: &[u8]) -> io::Result<()> {
+    for &b in bytes {
+        match b {
+            b'\r' => w.write_all(b"\\r")?,
+            b'\n' => w.write_all(b"\\n")?,
+            b'\\' => w.write_all(b"\\\\")?,
+            0x20..=0x7e => w.write_all(&[b])?,
+            _ => write!(w, "\\x{:02x}", b)?,
+        }
+    }
+    Ok(())
+}
+
+/// Read back a transcript written by a [`FileSink`]
+pub fn read_file<P: AsRef<Path>>(path: P) -> io::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    for (i, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let entry = parse_line(&line?).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid transcript line {}", i + 1),
+            )
+        })?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+fn parse_line(line: &str) -> Option<Entry> {
+    let mut parts = line.splitn(3, ' ');
+    let time = DateTime::parse_from_rfc3339(parts.next()?)
+        .ok()?
+        .with_timezone(&Utc);
+    let event = match (parts.next()?, parts.next()) {
+        ("C", Some(b)) => Event::Received(Cow::Owned(unescape(b)?)),
+        ("S", Some(b)) => Event::Sent(Cow::Owned(unescape(b)?)),
+        ("R", None) => Event::Redacted,
+        ("T", Some(n)) => Event::Truncated(n.parse().ok()?),
+        _ => return None,
+    };
+    Some(Entry { time, event })
+}
+
+fn unescape(s: &str) -> Option<Vec<u8>> {
+    let mut res = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'\\' {
+            res.push(b);
+            continue;
+        }
+        match bytes.next()? {
+            b'r' => res.push(b'\r'),
+            b'n' => res.push(b'\n'),
+            b'\\' => res.push(b'\\'),
+            b'x' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                res.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+            }
+            _ => return None,
+        }
+    }
+    Some(res)
+}
+
+enum State {
+    /// Reading commands, `line_start` being whether no byte of the current
+    /// line was recorded yet, and `held` the bytes that might start an AUTH
+    /// command
+    Command { line_start: bool, held: Vec<u8> },
+
+    /// Reading the mechanism of an AUTH command
+    AuthMechanism,
+
+    /// Dropping bytes until the end of the line, `emitted` being whether the
+    /// `Redacted` event was already given
+    Redacting { emitted: bool },
+
+    /// Reading a DATA body, with `matched` bytes of the end marker matched
+    Data {
+        kept: usize,
+        dropped: usize,
+        matched: usize,
+    },
+}
+
+/// Redacts and truncates the session before giving it to the sink
+pub(crate) struct Transcript {
+    sink: Box<dyn TranscriptSink>,
+    max_data_len: usize,
+    state: State,
+}
+
+impl Transcript {
+    pub(crate) fn new(sink: Box<dyn TranscriptSink>) -> Transcript {
+        Transcript {
+            max_data_len: sink.max_data_len(),
+            sink,
+            state: State::Command {
+                line_start: true,
+                held: Vec::new(),
+            },
+        }
+    }
+
+    pub(crate) fn sent(&mut self, code: ReplyCode, bytes: &[u8]) {
+        let time = Utc::now();
+        self.flush_held(time);
+        self.sink.record(time, Event::Sent(Cow::Borrowed(bytes)));
+        match code.code() {
+            354 => {
+                self.state = State::Data {
+                    kept: 0,
+                    dropped: 0,
+                    // The CRLF ending the DATA command starts the end marker
+                    matched: 2,
+                }
+            }
+            // The next line is the client's answer to the AUTH challenge
+            334 => self.state = State::Redacting { emitted: false },
+            _ => (),
+        }
+    }
+
+    pub(crate) fn received(&mut self, mut bytes: &[u8]) {
+        let time = Utc::now();
+        while !bytes.is_empty() {
+            let used = self.consume(time, bytes);
+            bytes = &bytes[used..];
+        }
+    }
+
+    /// Records the start of `bytes` according to the current state, and
+    /// returns the number of bytes handled
+    fn consume(&mut self, time: DateTime<Utc>, bytes: &[u8]) -> usize {
+        match self.state {
+            State::Command {
+                line_start: true,
+                ref mut held,
+            } => {
+                let len = min(held.len() + bytes.len(), AUTH.len());
+                let is_auth = held
+                    .iter()
+                    .chain(bytes)
+                    .zip(&AUTH[..len])
+                    .all(|(a, b)| a.eq_ignore_ascii_case(b));
+                if !is_auth {
+                    // The held bytes are the start of an ordinary line, which
+                    // is handled from the start of `bytes` in the new state
+                    self.flush_held(time);
+                    self.state = State::Command {
+                        line_start: false,
+                        held: Vec::new(),
+                    };
+                    return 0;
+                }
+                if len < AUTH.len() {
+                    held.extend_from_slice(bytes);
+                    return bytes.len();
+                }
+                let used = AUTH.len() - held.len();
+                held.extend_from_slice(&bytes[..used]);
+                self.flush_held(time);
+                self.state = State::AuthMechanism;
+                used
+            }
+            State::Command {
+                line_start: false, ..
+            } => {
+                let len = match bytes.iter().position(|&b| b == b'\n') {
+                    Some(i) => {
+                        self.state = State::Command {
+                            line_start: true,
+                            held: Vec::new(),
+                        };
+                        i + 1
+                    }
+                    None => bytes.len(),
+                };
+                self.sink
+                    .record(time, Event::Received(Cow::Borrowed(&bytes[..len])));
+                len
+            }
+            State::AuthMechanism => {
+                let len = match bytes.iter().position(|&b| b == b' ' || b == b'\r') {
+                    Some(i) if bytes[i] == b' ' => {
+                        self.state = State::Redacting { emitted: false };
+                        i + 1
+                    }
+                    Some(i) => {
+                        self.state = State::Command {
+                            line_start: false,
+                            held: Vec::new(),
+                        };
+                        i
+                    }
+                    None => bytes.len(),
+                };
+                if len > 0 {
+                    self.sink
+                        .record(time, Event::Received(Cow::Borrowed(&bytes[..len])));
+                }
+                len
+            }
+            State::Redacting { ref mut emitted } => {
+                if !*emitted && bytes[0] != b'\r' {
+                    *emitted = true;
+                    self.sink.record(time, Event::Redacted);
+                }
+                match bytes.iter().position(|&b| b == b'\r') {
+                    Some(i) => {
+                        self.state = State::Command {
+                            line_start: false,
+                            held: Vec::new(),
+                        };
+                        i
+                    }
+                    None => bytes.len(),
+                }
+            }
+            State::Data {
+                ref mut kept,
+                ref mut dropped,
+                ref mut matched,
+            } => {
+                let mut len = bytes.len();
+                for (i, &b) in bytes.iter().enumerate() {
+                    *matched = match (DATA_END[*matched] == b, b) {
+                        (true, _) => *matched + 1,
+                        (false, b'\r') => 1,
+                        (false, _) => 0,
+                    };
+                    if *matched == DATA_END.len() {
+                        len = i + 1;
+                        break;
+                    }
+                }
+                let keep = min(self.max_data_len - *kept, len);
+                *kept += keep;
+                *dropped += len - keep;
+                if keep > 0 {
+                    self.sink
+                        .record(time, Event::Received(Cow::Borrowed(&bytes[..keep])));
+                }
+                if *matched == DATA_END.len() {
+                    // Re-add the part of the end marker that was dropped, so
+                    // that the transcript stays a valid session
+                    let end = min(*dropped, DATA_END.len());
+                    if *dropped > end {
+                        self.sink.record(time, Event::Truncated(*dropped - end));
+                    }
+                    if end > 0 {
+                        self.sink.record(
+                            time,
+                            Event::Received(Cow::Borrowed(&DATA_END[DATA_END.len() - end..])),
+                        );
+                    }
+                    self.state = State::Command {
+                        line_start: true,
+                        held: Vec::new(),
+                    };
+                }
+                len
+            }
+        }
+    }
+
+    /// Records the bytes held while waiting to know whether they start an
+    /// AUTH command
+    fn flush_held(&mut self, time: DateTime<Utc>) {
+        if let State::Command { ref mut held, .. } = self.state {
+            if !held.is_empty() {
+                let held = std::mem::take(held);
+                self.sink.record(time, Event::Received(Cow::Owned(held)));
+            }
+        }
+    }
+}
+
+impl Drop for Transcript {
+    fn drop(&mut self) {
+        let time = Utc::now();
+        self.flush_held(time);
+        if let State::Data { dropped, .. } = self.state {
+            if dropped > 0 {
+                self.sink.record(time, Event::Truncated(dropped));
+            }
+        }
+        self.sink.finish();
+    }
+}
+
+/// Stream recording what is read from it and the replies sent through it,
+/// and reporting them to the session's observer
+///
+/// The transcript is finished when it is dropped.
+pub(crate) struct Tapped<S> {
+    pub(crate) inner: S,
+    transcript: Option<Transcript>,
+    observer: Observer,
+    /// Number of bytes read so far
+    received: u64,
+}
+
+impl<S> Tapped<S> {
+    pub(crate) fn new(
+        inner: S,
+        sink: Option<Box<dyn TranscriptSink>>,
+        observer: Observer,
+    ) -> Tapped<S> {
+        Tapped {
+            inner,
+            transcript: sink.map(Transcript::new),
+            observer,
+            received: 0,
+        }
+    }
+
+    pub(crate) fn received(&self) -> u64 {
+        self.received
+    }
+
+    pub(crate) fn sent(&mut self, code: ReplyCode, bytes: &[u8]) {
+        self.observer.reply(code, bytes);
+        if let Some(t) = &mut self.transcript {
+            t.sent(code, bytes);
+        }
+    }
+}
+
+impl<S> AsyncRead for Tapped<S>
+where
+    S: Unpin + AsyncRead,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let res = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(read)) = res {
+            this.observer.bytes_received(read);
+            this.received += read as u64;
+            if let Some(t) = &mut this.transcript {
+                t.received(&buf[..read]);
+            }
+        }
+        res
+    }
+}
+
+impl<S> AsyncWrite for Tapped<S>
+where
+    S: Unpin + AsyncWrite,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Plays `chunks` through a transcript, replies being given by their code
+    /// after a `>`, and renders what the sink got
+    fn record(max_data_len: usize, chunks: &[&[u8]]) -> String {
+        let sink = MemorySink::new().with_max_data_len(max_data_len);
+        let mut transcript = Transcript::new(Box::new(sink.clone()));
+        for c in chunks {
+            match c.strip_prefix(b">") {
+                Some(code) => {
+                    let code = ReplyCode([code[0], code[1], code[2]]);
+                    transcript.sent(code, c);
+                }
+                None => transcript.received(c),
+            }
+        }
+        drop(transcript);
+        let mut res = String::new();
+        for e in sink.entries() {
+            match e.event {
+                Event::Received(b) => res.push_str(std::str::from_utf8(&b).unwrap()),
+                Event::Redacted => res.push_str("<redacted>"),
+                Event::Truncated(n) => res.push_str(&format!("<{} truncated>", n)),
+                Event::Sent(b) => {
+                    res.push_str(&format!("[{}]", std::str::from_utf8(&b[1..]).unwrap()))
+                }
+            }
+        }
+        res
+    }
+
+    #[test]
+    fn redacts_auth() {
+        let tests: &[(&[&[u8]], &str)] = &[
+            (
+                &[b"EHLO test\r\nAUTH PLAIN dGVzdA==\r\nNOOP\r\n"],
+                "EHLO test\r\nAUTH PLAIN <redacted>\r\nNOOP\r\n",
+            ),
+            (
+                &[b"AU", b"th plain dGVz", b"dA==\r", b"\nNOOP\r\n"],
+                "AUth plain <redacted>\r\nNOOP\r\n",
+            ),
+            (
+                &[
+                    b"AUTH LOGIN\r\n",
+                    b">334",
+                    b"dGVzdA==\r\n",
+                    b">334",
+                    b"*\r\n",
+                ],
+                "AUTH LOGIN\r\n[334]<redacted>\r\n[334]<redacted>\r\n",
+            ),
+            (&[b"AUTHX 1\r\nAUT"], "AUTHX 1\r\nAUT"),
+            (&[b"NOOP AUTH PLAIN x\r\n"], "NOOP AUTH PLAIN x\r\n"),
+        ];
+        for &(chunks, expected) in tests {
+            assert_eq!(record(1024, chunks), expected);
+        }
+    }
+
+    #[test]
+    fn truncates_data() {
+        let tests: &[(&[&[u8]], &str)] = &[
+            (
+                &[
+                    b"DATA\r\n",
+                    b">354",
+                    b"Hello world\r\n..more\r",
+                    b"\n.",
+                    b"\r\nQUIT\r\n",
+                ],
+                "DATA\r\n[354]Hello <13 truncated>\r\n.\r\nQUIT\r\n",
+            ),
+            (
+                &[b"DATA\r\n", b">354", b"Hello\r\n.\r\nAUTH PLAIN x\r\n"],
+                "DATA\r\n[354]Hello\r\n.\r\nAUTH PLAIN <redacted>\r\n",
+            ),
+            (&[b">354", b".\r\nQUIT\r\n"], "[354].\r\nQUIT\r\n"),
+            (&[b">354", b"Hello\r\n.\r"], "[354]Hello\r<3 truncated>"),
+            (
+                &[b">354", b"Hello there\r\n.\r\n"],
+                "[354]Hello <5 truncated>\r\n.\r\n",
+            ),
+        ];
+        for &(chunks, expected) in tests {
+            assert_eq!(record(6, chunks), expected);
+        }
+    }
+
+    #[test]
+    fn file_sink_roundtrips() {
+        let path = std::env::temp_dir().join(format!("transcript-{}.txt", std::process::id()));
+        let mut sink = FileSink::create(&path).unwrap();
+        let time = DateTime::parse_from_rfc3339("2020-01-02T03:04:05.5Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        sink.record(time, Event::Received(b"EHLO \\\xff\r\n"[..].into()));
+        sink.record(time, Event::Redacted);
+        sink.record(time, Event::Truncated(42));
+        sink.record(time, Event::Sent(b"250 Okay\r\n"[..].into()));
+        sink.finish();
+        let written = std::fs::read_to_string(&path).unwrap();
+        let read = read_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            written,
+            "2020-01-02T03:04:05.500000Z C EHLO \\\\\\xff\\r\\n\n\
+             2020-01-02T03:04:05.500000Z R\n\
+             2020-01-02T03:04:05.500000Z T 42\n\
+             2020-01-02T03:04:05.500000Z S 250 Okay\\r\\n\n"
+        );
+        assert_eq!(
+            read.into_iter().map(|e| e.event).collect::<Vec<_>>(),
+            vec![
+                Event::Received(b"EHLO \\\xff\r\n"[..].into()),
+                Event::Redacted,
+                Event::Truncated(42),
+                Event::Sent(b"250 Okay\r\n"[..].into()),
+            ]
+        );
+        assert_eq!(parse_line("2020-01-02T03:04:05Z C \\q"), None);
+        assert_eq!(parse_line("2020-01-02T03:04:05Z R x"), None);
+    }
+}